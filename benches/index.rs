@@ -0,0 +1,77 @@
+//! Indexing an in-memory BAM: lazily, with one BGZF worker and with one per
+//! core, against decoding every record in full as noodles' reader does.
+
+use std::{fmt::Write as _, num::NonZeroUsize};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use noodles::{bam, sam};
+use stream_index::{build_bam_index, IndexOptions};
+
+const RECORDS: usize = 50_000;
+
+/// A coordinate-sorted BAM of 100 bp reads with qualities and a tag each.
+fn bam() -> Vec<u8> {
+    let mut text = String::from("@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:10000000\n");
+    for i in 0..RECORDS {
+        writeln!(
+            text,
+            "r{}\t0\tchr1\t{}\t60\t50M2I48M\t*\t0\t0\t{}\t{}\tNM:i:2",
+            i,
+            1 + 100 * i,
+            "ACGT".repeat(25),
+            "I".repeat(100)
+        )
+        .unwrap();
+    }
+    let mut reader = sam::Reader::new(text.as_bytes());
+    let header = reader.read_header().unwrap();
+    let mut writer = bam::Writer::new(Vec::new());
+    writer.write_header(&header).unwrap();
+    for result in reader.records(&header) {
+        writer.write_record(&header, &result.unwrap()).unwrap();
+    }
+    writer.into_inner().finish().unwrap()
+}
+
+/// The fields the index needs, decoded from full records.
+async fn read_records(bam: &[u8]) -> usize {
+    let mut reader = bam::AsyncReader::new(bam);
+    let header: sam::Header = reader.read_header().await.unwrap().parse().unwrap();
+    reader.read_reference_sequences().await.unwrap();
+    let mut record = sam::alignment::Record::default();
+    let mut ends = 0;
+    while reader.read_record(&header, &mut record).await.unwrap() != 0 {
+        ends += record.alignment_end().map_or(0, usize::from);
+    }
+    ends
+}
+
+fn bench_index(c: &mut Criterion) {
+    let bam = bam();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("index");
+    group.throughput(Throughput::Bytes(bam.len() as u64));
+    group.sample_size(10);
+
+    let cores = std::thread::available_parallelism().unwrap();
+    for (name, threads) in [
+        ("lazy, 1 worker", NonZeroUsize::MIN),
+        ("lazy, 1 worker per core", cores),
+    ] {
+        let options = IndexOptions {
+            threads: Some(threads),
+            ..Default::default()
+        };
+        group.bench_function(name, |b| {
+            b.to_async(&runtime)
+                .iter(|| async { build_bam_index(&mut &bam[..], &options).await.unwrap() })
+        });
+    }
+    group.bench_function("read_record", |b| {
+        b.to_async(&runtime).iter(|| read_records(&bam))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_index);
+criterion_main!(benches);
@@ -0,0 +1,244 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use futures::TryStreamExt;
+use tokio::{io::AsyncReadExt, sync::Semaphore, task::JoinSet};
+
+use stream_index::{auth, get_async_stream_reader, get_object_store, Source, StoreOptions};
+
+use crate::{cli, index_source, Destination, Outcome};
+
+/// A BAM to index and, optionally, where its index goes.
+struct Job {
+    input: Source,
+    output: Option<Destination>,
+}
+
+/// Parses a manifest: one input per line, with an optional tab-separated
+/// output. Blank lines and lines starting with `#` are ignored.
+fn parse_manifest(text: &str) -> Result<Vec<Job>> {
+    let mut jobs = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let job = parse_manifest_line(line)
+            .with_context(|| format!("Invalid manifest line {}", i + 1))?;
+        jobs.push(job);
+    }
+    Ok(jobs)
+}
+
+fn parse_manifest_line(line: &str) -> Result<Job> {
+    let mut fields = line.split('\t').map(str::trim);
+    let input = fields.next().unwrap_or_default().parse()?;
+    let output = match fields.next() {
+        Some("") | None => None,
+        Some(s) => Some(s.parse()?),
+    };
+    Ok(Job { input, output })
+}
+
+async fn read_manifest(source: &Source, store_options: &StoreOptions) -> Result<Vec<Job>> {
+    let mut reader = get_async_stream_reader(source, store_options).await?;
+    let mut text = String::new();
+    reader
+        .read_to_string(&mut text)
+        .await
+        .with_context(|| format!("Failed to read manifest {}", source))?;
+    parse_manifest(&text)
+}
+
+/// Lists every `.bam` and `.cram` object under `prefix`.
+async fn list_prefix(prefix: &url::Url, store_options: &StoreOptions) -> Result<Vec<Job>> {
+    let (store, path) = get_object_store(prefix, store_options)?;
+    let objects: Vec<_> = store
+        .list(Some(&path))
+        .await?
+        .try_collect()
+        .await
+        .with_context(|| format!("Failed to list {}", auth::redact_url(prefix)))?;
+    let mut jobs = Vec::new();
+    for meta in objects {
+        if !matches!(meta.location.extension(), Some("bam" | "cram")) {
+            continue;
+        }
+        let mut url = prefix.clone();
+        url.set_path(&format!("/{}", meta.location));
+        jobs.push(Job {
+            input: Source::Url(url),
+            output: None,
+        });
+    }
+    jobs.sort_by_key(|job| job.input.to_string());
+    Ok(jobs)
+}
+
+pub async fn run_batch(args: cli::BatchArgs) -> Result<()> {
+    if matches!(
+        args.output,
+        Some(Destination::File(_) | Destination::Stdout)
+    ) {
+        anyhow::bail!("--output must be a directory or prefix ending in `/` in batch mode");
+    }
+    let store_options = args.store.store_options()?;
+
+    let mut jobs: Vec<Job> = args
+        .inputs
+        .into_iter()
+        .map(|input| Job {
+            input,
+            output: None,
+        })
+        .collect();
+    if let Some(manifest) = &args.manifest {
+        jobs.extend(read_manifest(manifest, &store_options).await?);
+    }
+    if let Some(prefix) = &args.prefix {
+        let listed = list_prefix(prefix, &store_options).await?;
+        log::info!(
+            "Found {} BAMs and CRAMs under {}",
+            listed.len(),
+            auth::redact_url(prefix)
+        );
+        jobs.extend(listed);
+    }
+    if jobs.iter().any(|job| matches!(job.input, Source::Stdin)) {
+        anyhow::bail!("Batch inputs can't be read from stdin");
+    }
+
+    let options = Arc::new(args.indexing.run_options());
+    let if_exists = args.indexing.if_exists;
+    let store_options = Arc::new(store_options);
+    let output = Arc::new(args.output);
+    let semaphore = Arc::new(Semaphore::new(args.jobs.get()));
+
+    let mut tasks = JoinSet::new();
+    for (i, job) in jobs.iter().enumerate() {
+        let input = job.input.clone();
+        let job_output = job.output.clone();
+        let output = Arc::clone(&output);
+        let options = Arc::clone(&options);
+        let store_options = Arc::clone(&store_options);
+        let semaphore = Arc::clone(&semaphore);
+        tasks.spawn(async move {
+            let result = async {
+                let _permit = semaphore.acquire_owned().await?;
+                log::debug!("Indexing {}", input);
+                let output = job_output.as_ref().or(output.as_ref().as_ref());
+                index_source(&input, output, &options, if_exists, &store_options).await
+            }
+            .await;
+            if let Err(e) = &result {
+                log::error!("Failed to index {}: {:#}", input, e);
+            }
+            (i, result)
+        });
+    }
+
+    // A task that panicked can't say which job it was; its job is left
+    // without a result and reported as failed below.
+    let mut results: Vec<Option<Result<Outcome>>> = jobs.iter().map(|_| None).collect();
+    while let Some(joined) = tasks.join_next().await {
+        match joined {
+            Ok((i, result)) => results[i] = Some(result),
+            Err(e) => log::error!("An indexing task failed: {}", e),
+        }
+    }
+
+    // One tab-separated line per input: status, input, then index locations
+    // or the error.
+    let (mut indexed, mut skipped, mut failed) = (0, 0, 0);
+    for (job, result) in jobs.iter().zip(results) {
+        let result = result.unwrap_or_else(|| Err(anyhow::anyhow!("The indexing task panicked")));
+        match result {
+            Ok(Outcome::Indexed(locations)) => {
+                indexed += 1;
+                let locations: Vec<_> = locations
+                    .iter()
+                    .map(|location| location.as_ref().map_or("-".into(), auth::redact_url))
+                    .collect();
+                println!("ok\t{}\t{}", job.input, locations.join(","));
+            }
+            Ok(Outcome::Skipped(url)) => {
+                skipped += 1;
+                println!("skipped\t{}\t{}", job.input, auth::redact_url(&url));
+            }
+            Err(e) => {
+                failed += 1;
+                let e = auth::redact_urls(&format!("{:#}", e));
+                println!("failed\t{}\t{}", job.input, e);
+            }
+        }
+    }
+    log::info!(
+        "{} indexed, {} skipped, {} failed",
+        indexed,
+        skipped,
+        failed
+    );
+    if failed > 0 {
+        anyhow::bail!("{} of {} inputs failed to index", failed, jobs.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inputs(jobs: &[Job]) -> Vec<String> {
+        jobs.iter().map(|job| job.input.to_string()).collect()
+    }
+
+    #[test]
+    fn manifests_list_inputs_and_optional_outputs() {
+        let cwd = std::env::current_dir().unwrap();
+        let text = "# input\toutput\n\
+                    s3://bucket/a.bam\n\
+                    \n   \n\
+                    data/b.bam\ts3://bucket/indexes/\n\
+                    /data/c.cram\t\n";
+        let jobs = parse_manifest(text).unwrap();
+
+        let b = url::Url::from_file_path(cwd.join("data/b.bam")).unwrap();
+        assert_eq!(
+            inputs(&jobs),
+            ["s3://bucket/a.bam", b.as_str(), "file:///data/c.cram"]
+        );
+        assert!(jobs[0].output.is_none());
+        assert!(matches!(&jobs[1].output, Some(Destination::Directory(url))
+            if url.as_str() == "s3://bucket/indexes/"));
+        assert!(jobs[2].output.is_none());
+    }
+
+    #[test]
+    fn invalid_manifest_lines_are_reported_by_number() {
+        for (text, line) in [
+            ("a.bam\nhttps://[::1/b.bam\n", 2),
+            ("# comment\n\ta.bam.bai\n", 2),
+            ("a.bam\tgs://bucket name/\n", 1),
+        ] {
+            let e = parse_manifest(text).err().expect(text);
+            assert_eq!(e.to_string(), format!("Invalid manifest line {}", line));
+        }
+    }
+
+    #[tokio::test]
+    async fn prefixes_list_the_bams_and_crams_under_them() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        for name in ["b.bam", "a.cram", "a.bam.bai", "notes.txt", "sub/c.bam"] {
+            std::fs::write(dir.path().join(name), b"").unwrap();
+        }
+        let prefix = url::Url::from_directory_path(dir.path()).unwrap();
+
+        let jobs = list_prefix(&prefix, &Default::default()).await.unwrap();
+        let expected: Vec<_> = ["a.cram", "b.bam", "sub/c.bam"]
+            .iter()
+            .map(|name| prefix.join(name).unwrap().to_string())
+            .collect();
+        assert_eq!(inputs(&jobs), expected);
+        assert!(jobs.iter().all(|job| job.output.is_none()));
+    }
+}
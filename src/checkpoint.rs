@@ -0,0 +1,557 @@
+//! Saving indexing progress to an object store and resuming from it.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use noodles::{
+    bgzf::VirtualPosition,
+    core::Position,
+    csi::{
+        self,
+        index::{
+            reference_sequence::{bin::Chunk, Bin, Metadata},
+            ReferenceSequence,
+        },
+    },
+    sam,
+};
+use object_store::ObjectStore;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bam_stream_reader, bam_stream_reader_at,
+    coverage::{Coverage, CoverageOptions},
+    drs, finish_index,
+    flagstat::FlagStat,
+    format_position, get_object_store, index_records, read_bam_header,
+    resume::open_resumable,
+    split::merge_indexes,
+    BamIndex, Error, IndexOptions, RecordSpan, Result, StoreOptions,
+};
+
+const CHECKPOINT_VERSION: u32 = 3;
+
+/// Progress through a BAM, saved as JSON.
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    version: u32,
+    source: String,
+    /// The source's ETag when indexing started; resuming a changed object
+    /// would mix two files' records.
+    e_tag: Option<String>,
+    /// Where the next record starts, and the compressed offset of its block.
+    virtual_position: u64,
+    byte_offset: u64,
+    min_shift: u8,
+    depth: u8,
+    /// (reference sequence, 1-based start) of the last record indexed, for
+    /// the sort order check.
+    last_record: Option<(usize, Option<usize>)>,
+    /// The index of every record before `virtual_position`.
+    index: IndexState,
+    /// And their flag counts.
+    flagstat: FlagStat,
+    /// And their coverage, if it was counted.
+    #[serde(default)]
+    coverage: Option<Coverage>,
+}
+
+/// What `csi::index::Indexer` has built so far.
+#[derive(Serialize, Deserialize)]
+struct IndexState {
+    reference_sequences: Vec<ReferenceSequenceState>,
+    unplaced_unmapped_record_count: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ReferenceSequenceState {
+    /// In ID order.
+    bins: Vec<BinState>,
+    linear_index: Vec<u64>,
+    /// Start and end positions, then mapped and unmapped record counts.
+    metadata: Option<(u64, u64, u64, u64)>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BinState {
+    id: usize,
+    loffset: u64,
+    /// (start, end) virtual positions.
+    chunks: Vec<(u64, u64)>,
+}
+
+impl IndexState {
+    fn new(index: &csi::Index) -> Self {
+        let reference_sequences = index
+            .reference_sequences()
+            .iter()
+            .map(|reference_sequence| {
+                let mut bins: Vec<_> = reference_sequence
+                    .bins()
+                    .iter()
+                    .map(|(&id, bin)| {
+                        let chunks = bin
+                            .chunks()
+                            .iter()
+                            .map(|chunk| (u64::from(chunk.start()), u64::from(chunk.end())))
+                            .collect();
+                        BinState {
+                            id,
+                            loffset: u64::from(bin.loffset()),
+                            chunks,
+                        }
+                    })
+                    .collect();
+                bins.sort_by_key(|bin| bin.id);
+                ReferenceSequenceState {
+                    bins,
+                    linear_index: reference_sequence
+                        .linear_index()
+                        .iter()
+                        .map(|&position| u64::from(position))
+                        .collect(),
+                    metadata: reference_sequence.metadata().map(|metadata| {
+                        (
+                            u64::from(metadata.start_position()),
+                            u64::from(metadata.end_position()),
+                            metadata.mapped_record_count(),
+                            metadata.unmapped_record_count(),
+                        )
+                    }),
+                }
+            })
+            .collect();
+        Self {
+            reference_sequences,
+            unplaced_unmapped_record_count: index.unplaced_unmapped_record_count().unwrap_or(0),
+        }
+    }
+
+    fn to_index(&self, min_shift: u8, depth: u8) -> csi::Index {
+        let reference_sequences = self
+            .reference_sequences
+            .iter()
+            .map(|reference_sequence| {
+                let bins = reference_sequence
+                    .bins
+                    .iter()
+                    .map(|bin| {
+                        let chunks = bin
+                            .chunks
+                            .iter()
+                            .map(|&(start, end)| {
+                                Chunk::new(VirtualPosition::from(start), VirtualPosition::from(end))
+                            })
+                            .collect();
+                        (bin.id, Bin::new(VirtualPosition::from(bin.loffset), chunks))
+                    })
+                    .collect();
+                let linear_index = reference_sequence
+                    .linear_index
+                    .iter()
+                    .map(|&position| VirtualPosition::from(position))
+                    .collect();
+                let metadata = reference_sequence
+                    .metadata
+                    .map(|(start, end, mapped, unmapped)| {
+                        Metadata::new(
+                            VirtualPosition::from(start),
+                            VirtualPosition::from(end),
+                            mapped,
+                            unmapped,
+                        )
+                    });
+                ReferenceSequence::new(bins, linear_index, metadata)
+            })
+            .collect();
+        csi::Index::builder()
+            .set_min_shift(min_shift)
+            .set_depth(depth)
+            .set_reference_sequences(reference_sequences)
+            .set_unplaced_unmapped_record_count(self.unplaced_unmapped_record_count)
+            .build()
+    }
+}
+
+/// Saves indexing progress every `interval`.
+pub(crate) struct Checkpointer {
+    location: url::Url,
+    store_options: StoreOptions,
+    interval: Duration,
+    last_saved: Instant,
+    source: url::Url,
+    e_tag: Option<String>,
+    min_shift: u8,
+    depth: u8,
+    /// The index of the records before the last checkpoint.
+    index: Option<csi::Index>,
+    /// Flag counts and coverage of the records before the checkpoint resumed
+    /// from.
+    flagstat: FlagStat,
+    coverage: Option<Coverage>,
+}
+
+impl Checkpointer {
+    pub fn is_due(&self) -> bool {
+        self.last_saved.elapsed() >= self.interval
+    }
+
+    /// Folds the records in `indexer` into the saved index, leaving it empty,
+    /// and saves a checkpoint for resuming at `position`. `span` covers the
+    /// records read since this run started.
+    ///
+    /// A checkpoint that fails to save is logged and retried next interval;
+    /// the indexing itself carries on.
+    pub async fn save(
+        &mut self,
+        indexer: &mut csi::index::Indexer,
+        header: &sam::Header,
+        position: VirtualPosition,
+        span: &RecordSpan,
+    ) {
+        let indexer = std::mem::replace(
+            indexer,
+            csi::index::Indexer::new(self.min_shift, self.depth),
+        );
+        let index = finish_index(indexer, header, self.min_shift, self.depth);
+        self.index = Some(self.merge(index));
+        self.last_saved = Instant::now();
+        let (flagstat, coverage) = self.totals(span);
+
+        let checkpoint = Checkpoint {
+            version: CHECKPOINT_VERSION,
+            source: self.source.to_string(),
+            e_tag: self.e_tag.clone(),
+            virtual_position: u64::from(position),
+            byte_offset: position.compressed(),
+            min_shift: self.min_shift,
+            depth: self.depth,
+            last_record: span.last.map(|(id, start)| (id, start.map(usize::from))),
+            index: IndexState::new(self.index.as_ref().unwrap()),
+            flagstat,
+            coverage,
+        };
+        match self.put(&checkpoint).await {
+            Ok(()) => log::info!(
+                "Saved checkpoint at byte {} to {}",
+                checkpoint.byte_offset,
+                self.location
+            ),
+            Err(e) => log::warn!("Failed to save checkpoint to {}: {:#}", self.location, e),
+        }
+    }
+
+    async fn put(&self, checkpoint: &Checkpoint) -> Result<()> {
+        let (store, path) = get_object_store(&self.location, &self.store_options)?;
+        // A single put, so a run killed mid-write leaves the last checkpoint.
+        store
+            .put(&path, serde_json::to_vec(checkpoint)?.into())
+            .await?;
+        Ok(())
+    }
+
+    /// The counts in `span` added to those from before the checkpoint.
+    fn totals(&self, span: &RecordSpan) -> (FlagStat, Option<Coverage>) {
+        let mut flagstat = self.flagstat;
+        flagstat.add(&span.flagstat);
+        let mut coverage = self.coverage.clone();
+        match (&mut coverage, &span.coverage) {
+            (Some(coverage), Some(part)) => coverage.add(part),
+            (None, part) => coverage = part.clone(),
+            _ => {}
+        }
+        (flagstat, coverage)
+    }
+
+    /// `index` merged after the records already saved.
+    fn merge(&mut self, index: csi::Index) -> csi::Index {
+        match self.index.take() {
+            Some(saved) => merge_indexes(&[saved, index], self.min_shift, self.depth),
+            None => index,
+        }
+    }
+}
+
+/// Loads the checkpoint at `location`, if there is one that applies to this
+/// run.
+async fn load_checkpoint(
+    location: &url::Url,
+    source: &url::Url,
+    e_tag: Option<&str>,
+    (min_shift, depth): (u8, u8),
+    coverage: Option<CoverageOptions>,
+    store_options: &StoreOptions,
+) -> Result<Option<Checkpoint>> {
+    let (store, path) = get_object_store(location, store_options)?;
+    let buf = match store.get(&path).await {
+        Ok(result) => result.bytes().await?,
+        Err(object_store::Error::NotFound { .. }) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let checkpoint: Checkpoint = match serde_json::from_slice(&buf) {
+        Ok(checkpoint) => checkpoint,
+        Err(e) => {
+            log::warn!("Ignoring unreadable checkpoint {}: {}", location, e);
+            return Ok(None);
+        }
+    };
+    let mismatch = if checkpoint.version != CHECKPOINT_VERSION {
+        Some(format!("version {}", checkpoint.version))
+    } else if checkpoint.source != source.as_str() {
+        Some(format!("it's for {}", checkpoint.source))
+    } else if checkpoint.e_tag.as_deref() != e_tag {
+        Some("the input has changed since".to_string())
+    } else if (checkpoint.min_shift, checkpoint.depth) != (min_shift, depth) {
+        Some(format!(
+            "it used min_shift {} and depth {}",
+            checkpoint.min_shift, checkpoint.depth
+        ))
+    } else if checkpoint.coverage.as_ref().map(|c| *c.options()) != coverage {
+        Some("it counted coverage differently".to_string())
+    } else {
+        None
+    };
+    if let Some(reason) = mismatch {
+        log::warn!(
+            "Ignoring checkpoint {} ({}); starting over",
+            location,
+            reason
+        );
+        return Ok(None);
+    }
+    Ok(Some(checkpoint))
+}
+
+/// Removes the checkpoint once the index is written.
+pub async fn remove_checkpoint(location: &url::Url, store_options: &StoreOptions) {
+    let result = async {
+        let (store, path) = get_object_store(location, store_options)?;
+        store.delete(&path).await?;
+        Ok::<_, Error>(())
+    }
+    .await;
+    if let Err(e) = result {
+        log::warn!("Failed to remove checkpoint {}: {:#}", location, e);
+    }
+}
+
+/// Indexes the BAM at `url`, saving progress to `location` as it goes and
+/// resuming from the checkpoint there, if any.
+pub async fn build_bam_index_checkpointed(
+    url: &url::Url,
+    location: &url::Url,
+    options: &IndexOptions,
+    store_options: &StoreOptions,
+) -> Result<BamIndex> {
+    // Checkpoints are kept for the DRS URI, not the access URL it resolves
+    // to, which may be signed afresh every time.
+    let (read_url, read_options) = drs::resolve(url, store_options).await?;
+    let (store, path) = get_object_store(&read_url, &read_options)?;
+    let store: Arc<dyn ObjectStore> = Arc::from(store);
+    let meta = store.head(&path).await?;
+
+    let reader =
+        open_resumable(url, store.clone(), path.clone(), None, &store_options.retry).await?;
+    let mut bam_reader = bam_stream_reader(reader, options.threads);
+    let (header, format, min_shift, depth) = read_bam_header(&mut bam_reader, options).await?;
+    let checkpoint = load_checkpoint(
+        location,
+        url,
+        meta.e_tag.as_deref(),
+        (min_shift, depth),
+        options.coverage,
+        store_options,
+    )
+    .await
+    .map_err(|e| Error::Checkpoint {
+        location: location.clone(),
+        source: Box::new(e),
+    })?;
+
+    let mut checkpointer = Checkpointer {
+        location: location.clone(),
+        store_options: store_options.clone(),
+        interval: options.checkpoint_interval,
+        last_saved: Instant::now(),
+        source: url.clone(),
+        e_tag: meta.e_tag.clone(),
+        min_shift,
+        depth,
+        index: None,
+        flagstat: FlagStat::default(),
+        coverage: None,
+    };
+    let mut indexer = csi::index::Indexer::new(min_shift, depth);
+    let span = match &checkpoint {
+        None => {
+            index_records(
+                &mut bam_reader,
+                &header,
+                &mut indexer,
+                0,
+                None,
+                options.coverage,
+                Some(&mut checkpointer),
+            )
+            .await?
+        }
+        Some(checkpoint) => {
+            drop(bam_reader);
+            let start = VirtualPosition::from(checkpoint.virtual_position);
+            log::info!(
+                "Resuming {} from byte {} using checkpoint {}",
+                url,
+                checkpoint.byte_offset,
+                location
+            );
+            checkpointer.index = Some(checkpoint.index.to_index(min_shift, depth));
+            checkpointer.flagstat = checkpoint.flagstat;
+            checkpointer.coverage = checkpoint.coverage.clone();
+            let range = start.compressed() as usize..meta.size;
+            let reader =
+                open_resumable(url, store, path, Some(range), &store_options.retry).await?;
+            let mut bam_reader = bam_stream_reader_at(reader, options.threads, start).await?;
+            index_records(
+                &mut bam_reader,
+                &header,
+                &mut indexer,
+                start.compressed(),
+                None,
+                options.coverage,
+                Some(&mut checkpointer),
+            )
+            .await?
+        }
+    };
+
+    // The sort order check doesn't see across the checkpoint.
+    let last = checkpoint.and_then(|checkpoint| checkpoint.last_record);
+    if let (Some((last_id, last_start)), Some((name, first))) = (last, &span.first) {
+        let last = (last_id, last_start.and_then(Position::new));
+        if *first < last {
+            return Err(Error::Unsorted {
+                name: name.clone(),
+                position: format_position(&header, first.0, first.1),
+                previous: format_position(&header, last.0, last.1),
+            });
+        }
+    }
+
+    let index = finish_index(indexer, &header, min_shift, depth);
+    let (flagstat, coverage) = checkpointer.totals(&span);
+    Ok(BamIndex {
+        format,
+        index: checkpointer.merge(index),
+        header,
+        flagstat,
+        coverage,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::{
+        bam_stream_reader, build_bam_index, encode_bai,
+        testing::{bam_from_sam, many_records},
+        BAI_DEPTH, BAI_MIN_SHIFT,
+    };
+
+    /// Indexes about the first half of `bam` at `url` and saves a checkpoint
+    /// where it stopped, as a run killed after a save would leave.
+    async fn save_halfway(
+        bam: &[u8],
+        url: &url::Url,
+        location: &url::Url,
+        options: &IndexOptions,
+    ) -> VirtualPosition {
+        let (store, path) = get_object_store(url, &Default::default()).unwrap();
+        let meta = store.head(&path).await.unwrap();
+        let mut bam_reader = bam_stream_reader(bam, options.threads);
+        let (header, _, min_shift, depth) =
+            read_bam_header(&mut bam_reader, options).await.unwrap();
+        let mut checkpointer = Checkpointer {
+            location: location.clone(),
+            store_options: Default::default(),
+            interval: Duration::MAX,
+            last_saved: Instant::now(),
+            source: url.clone(),
+            e_tag: meta.e_tag,
+            min_shift,
+            depth,
+            index: None,
+            flagstat: FlagStat::default(),
+            coverage: None,
+        };
+        let mut indexer = csi::index::Indexer::new(min_shift, depth);
+        let half = VirtualPosition::try_from((bam.len() as u64 / 2, 0)).unwrap();
+        let span = index_records(
+            &mut bam_reader,
+            &header,
+            &mut indexer,
+            0,
+            Some(half),
+            options.coverage,
+            Some(&mut checkpointer),
+        )
+        .await
+        .unwrap();
+        checkpointer
+            .save(&mut indexer, &header, span.end, &span)
+            .await;
+        span.end
+    }
+
+    #[tokio::test]
+    async fn resuming_from_a_checkpoint_matches_one_pass() {
+        let bam = bam_from_sam(&many_records(5000));
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&bam).unwrap();
+        let url = url::Url::from_file_path(file.path()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let location = url::Url::from_file_path(dir.path().join("index.checkpoint")).unwrap();
+        let options = IndexOptions {
+            coverage: Some(Default::default()),
+            ..Default::default()
+        };
+
+        let resume_at = save_halfway(&bam, &url, &location, &options).await;
+        assert!(resume_at.compressed() > 0 && resume_at.compressed() < bam.len() as u64);
+        let (store, path) = get_object_store(&url, &Default::default()).unwrap();
+        let e_tag = store.head(&path).await.unwrap().e_tag;
+        let store_options = StoreOptions::default();
+        let load = |binning| {
+            load_checkpoint(
+                &location,
+                &url,
+                e_tag.as_deref(),
+                binning,
+                options.coverage,
+                &store_options,
+            )
+        };
+        assert!(load((BAI_MIN_SHIFT, BAI_DEPTH)).await.unwrap().is_some());
+        assert!(load((BAI_MIN_SHIFT + 1, BAI_DEPTH))
+            .await
+            .unwrap()
+            .is_none());
+
+        let resumed = build_bam_index_checkpointed(&url, &location, &options, &Default::default())
+            .await
+            .unwrap();
+        let one_pass = build_bam_index(&mut &bam[..], &options).await.unwrap();
+        assert_eq!(encode_bai(&resumed.index), encode_bai(&one_pass.index));
+        assert_eq!(
+            resumed.flagstat.to_json().unwrap(),
+            one_pass.flagstat.to_json().unwrap()
+        );
+        assert_eq!(
+            serde_json::to_value(&resumed.coverage).unwrap(),
+            serde_json::to_value(&one_pass.coverage).unwrap()
+        );
+    }
+}
@@ -0,0 +1,790 @@
+use std::{net::SocketAddr, num::NonZeroUsize, path::PathBuf, time::Duration};
+
+use anyhow::Context;
+use clap::{ArgAction, ArgGroup, Args, Parser, Subcommand, ValueEnum};
+
+use stream_index::{
+    auth::{HttpAuth, Netrc},
+    coverage::{CoverageFormat, CoverageOptions},
+    parse_url_or_path,
+    resume::RetryOptions,
+    stats::StatsFormat,
+    IndexFormat, IndexOptions, Source, StoreOptions, BAI_MIN_SHIFT, CSI_MAX_DEPTH, CSI_MAX_SHIFT,
+};
+
+use crate::{
+    tabix::{Preset, TabixOptions},
+    view::ViewFormat,
+    Destination, IfExists, RunOptions,
+};
+
+/// Build BAM indexes by streaming from object storage, without downloading.
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Print more progress information (repeat for debug output).
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Only print errors.
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+impl Cli {
+    pub fn log_level(&self) -> log::LevelFilter {
+        if self.quiet {
+            return log::LevelFilter::Error;
+        }
+        match self.verbose {
+            0 => log::LevelFilter::Info,
+            1 => log::LevelFilter::Debug,
+            _ => log::LevelFilter::Trace,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Stream a BAM or CRAM and write its index.
+    Index(IndexArgs),
+    /// Index many BAMs and CRAMs concurrently, from arguments, a manifest or a prefix listing.
+    Batch(BatchArgs),
+    /// Stream a bgzipped VCF, BED, GFF or other tab-delimited file and write a tabix index.
+    Tabix(TabixArgs),
+    /// Stream a plain or bgzipped FASTA and write its `.fai` (and `.gzi`).
+    Faidx(FaidxArgs),
+    /// Check an existing .bai or .csi against an index streamed from the BAM.
+    /// Exits 0 if it's valid, 2 if it's stale and 3 if it's corrupt.
+    Verify(VerifyArgs),
+    /// Serve BAMs over the GA4GH htsget reads API, indexing each on its first
+    /// request unless it has an index already.
+    Serve(ServeArgs),
+    /// Print the records overlapping regions of a BAM, fetching only the
+    /// blocks its index points to.
+    #[command(visible_alias = "query")]
+    View(ViewArgs),
+}
+
+#[derive(Args)]
+pub struct IndexArgs {
+    /// BAM or CRAM to index: an http(s)://, s3://, gs://, az://, drs:// or
+    /// file:// URL, a local path, or `-` for stdin (read as BAM). Inputs ending
+    /// in `.cram` get a `.crai`.
+    #[arg(value_parser = parse_source)]
+    pub input: Source,
+
+    /// Where to write the index: a file, a directory or prefix ending in `/`,
+    /// a URL, or `-` for stdout. Defaults to next to the input when it is
+    /// writable, else the working directory.
+    #[arg(short, long, value_parser = parse_destination)]
+    pub output: Option<Destination>,
+
+    #[command(flatten)]
+    pub indexing: IndexingArgs,
+
+    /// Save progress to this file or URL every `--checkpoint-interval`, and
+    /// resume from it if it already exists. Removed once the index is written.
+    #[arg(long, value_parser = parse_url)]
+    pub checkpoint: Option<url::Url>,
+
+    /// Copy the input to this file or URL while indexing it, e.g. to move a
+    /// BAM into a bucket and index it in one read. A directory or prefix
+    /// ending in `/` keeps the input's name. The index goes next to the copy
+    /// unless `--output` is given.
+    #[arg(long, value_parser = parse_url)]
+    pub tee: Option<url::Url>,
+
+    /// Seconds between checkpoints.
+    #[arg(long, value_name = "SECONDS", default_value = "300", value_parser = parse_seconds)]
+    pub checkpoint_interval: Duration,
+
+    #[command(flatten)]
+    pub store: StoreArgs,
+}
+
+#[derive(Args)]
+#[command(group(
+    ArgGroup::new("sources")
+        .args(["inputs", "manifest", "prefix"])
+        .required(true)
+        .multiple(true)
+))]
+pub struct BatchArgs {
+    /// BAMs and CRAMs to index: http(s)://, s3://, gs://, az://, drs:// or
+    /// file:// URLs, or local paths.
+    #[arg(value_parser = parse_source)]
+    pub inputs: Vec<Source>,
+
+    /// TSV with one input per line and an optional second column overriding
+    /// `--output` for that input. `-` reads it from stdin.
+    #[arg(long, value_parser = parse_source)]
+    pub manifest: Option<Source>,
+
+    /// Index every `.bam` and `.cram` object under this s3://, gs://, az:// or
+    /// local prefix.
+    #[arg(long, value_parser = parse_url)]
+    pub prefix: Option<url::Url>,
+
+    /// Directory or prefix (ending in `/`) to write the indexes to. Defaults
+    /// to next to each input when it is writable, else the working directory.
+    #[arg(short, long, value_parser = parse_destination)]
+    pub output: Option<Destination>,
+
+    /// How many inputs to index at once.
+    #[arg(short, long, default_value = "4")]
+    pub jobs: NonZeroUsize,
+
+    #[command(flatten)]
+    pub indexing: IndexingArgs,
+
+    #[command(flatten)]
+    pub store: StoreArgs,
+}
+
+#[derive(Args)]
+pub struct TabixArgs {
+    /// Bgzipped file to index: an http(s)://, s3://, gs://, az://, drs:// or
+    /// file:// URL, a local path, or `-` for stdin.
+    #[arg(value_parser = parse_source)]
+    pub input: Source,
+
+    /// Where to write the `.tbi`; see `index --help`.
+    #[arg(short, long, value_parser = parse_destination)]
+    pub output: Option<Destination>,
+
+    /// Column layout. Guessed from the file name when neither this nor the
+    /// columns are given.
+    #[arg(short, long, value_enum)]
+    pub preset: Option<Preset>,
+
+    /// Column of the reference sequence name (1-based).
+    #[arg(short, long)]
+    pub sequence_column: Option<usize>,
+
+    /// Column of the start position (1-based).
+    #[arg(short, long)]
+    pub begin_column: Option<usize>,
+
+    /// Column of the end position (1-based). Defaults to the start position.
+    #[arg(short, long)]
+    pub end_column: Option<usize>,
+
+    /// Start positions are 0-based and intervals half-open, as in BED.
+    #[arg(short = '0', long)]
+    pub zero_based: bool,
+
+    /// Skip lines starting with this character.
+    #[arg(short, long)]
+    pub comment: Option<char>,
+
+    /// Skip this many lines at the start of the file.
+    #[arg(short = 'S', long)]
+    pub skip_lines: Option<u32>,
+
+    /// What to do when the index already exists.
+    #[arg(long, value_enum, default_value_t = IfExists::Overwrite)]
+    pub if_exists: IfExists,
+
+    #[command(flatten)]
+    pub store: StoreArgs,
+}
+
+impl TabixArgs {
+    pub fn tabix_options(&self) -> TabixOptions {
+        TabixOptions {
+            preset: self.preset,
+            sequence_column: self.sequence_column,
+            begin_column: self.begin_column,
+            end_column: self.end_column,
+            zero_based: self.zero_based,
+            comment: self.comment.map(|c| c as u8),
+            skip_lines: self.skip_lines,
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct FaidxArgs {
+    /// FASTA to index, plain or bgzipped: an http(s)://, s3://, gs://, az://,
+    /// drs:// or file:// URL, a local path, or `-` for stdin.
+    #[arg(value_parser = parse_source)]
+    pub input: Source,
+
+    /// Where to write the `.fai`; see `index --help`. Must be a directory or
+    /// prefix for bgzipped input, which also gets a `.gzi`.
+    #[arg(short, long, value_parser = parse_destination)]
+    pub output: Option<Destination>,
+
+    /// What to do when the index already exists.
+    #[arg(long, value_enum, default_value_t = IfExists::Overwrite)]
+    pub if_exists: IfExists,
+
+    #[command(flatten)]
+    pub store: StoreArgs,
+}
+
+#[derive(Args)]
+pub struct VerifyArgs {
+    /// BAM whose index to check: an http(s)://, s3://, gs://, az://, drs:// or
+    /// file:// URL, a local path, or `-` for stdin.
+    #[arg(value_parser = parse_source)]
+    pub input: Source,
+
+    /// The index to check. Defaults to the input's `.bai`, or its `.csi` if
+    /// there's no `.bai`.
+    #[arg(short, long, value_parser = parse_url)]
+    pub index: Option<url::Url>,
+
+    #[command(flatten)]
+    pub reindex: ReindexArgs,
+
+    #[command(flatten)]
+    pub store: StoreArgs,
+}
+
+#[derive(Args)]
+pub struct ServeArgs {
+    /// Where the BAMs are: an http(s)://, s3://, gs://, az:// or file:// URL
+    /// prefix, or a local directory. `/reads/<id>` serves `<root>/<id>.bam`.
+    #[arg(value_parser = parse_url)]
+    pub root: url::Url,
+
+    /// Address to listen on.
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    pub listen: SocketAddr,
+
+    /// URL clients reach this server at, for tickets to BAMs they can't fetch
+    /// directly, which are served from `/data/`. Defaults to
+    /// `http://<listen address>/`.
+    #[arg(long, value_parser = url::Url::parse)]
+    pub public_url: Option<url::Url>,
+
+    #[command(flatten)]
+    pub reindex: ReindexArgs,
+
+    #[command(flatten)]
+    pub store: StoreArgs,
+}
+
+#[derive(Args)]
+pub struct ViewArgs {
+    /// BAM to query: an http(s)://, s3://, gs://, az://, drs:// or file://
+    /// URL, or a local path.
+    #[arg(value_parser = parse_url)]
+    pub input: url::Url,
+
+    /// Regions to print, as `chr1`, `chr1:100000` or `chr1:100000-200000`
+    /// (1-based and inclusive), or `*` for the unplaced unmapped records. A
+    /// record overlapping several regions is printed for each.
+    #[arg(required = true)]
+    pub regions: Vec<String>,
+
+    /// The index to use. Defaults to the input's `.bai`, or its `.csi`; if it
+    /// has neither, the BAM is streamed and indexed in memory first.
+    #[arg(short, long, value_parser = parse_url)]
+    pub index: Option<url::Url>,
+
+    /// Where to write the records: a file or URL. Defaults to stdout.
+    #[arg(short, long, value_parser = parse_url)]
+    pub output: Option<url::Url>,
+
+    /// Output format.
+    #[arg(short = 'O', long, value_enum, default_value_t = ViewFormat::Sam)]
+    pub output_format: ViewFormat,
+
+    /// Include the header in SAM output. BAM output always has it.
+    #[arg(long)]
+    pub with_header: bool,
+
+    #[command(flatten)]
+    pub reindex: ReindexArgs,
+
+    #[command(flatten)]
+    pub store: StoreArgs,
+}
+
+/// How `verify`, `serve` and `view` index a BAM in memory.
+#[derive(Args)]
+pub struct ReindexArgs {
+    /// Index BAMs whose header has no `SO:coordinate`, as long as the records
+    /// turn out to be sorted.
+    #[arg(long)]
+    pub allow_unsorted_header: bool,
+
+    /// How many BGZF blocks to decompress in parallel when indexing. Defaults
+    /// to the number of cores.
+    #[arg(short = '@', long)]
+    pub threads: Option<NonZeroUsize>,
+}
+
+impl ReindexArgs {
+    /// The default options, with these settings.
+    pub fn index_options(&self) -> IndexOptions {
+        IndexOptions {
+            allow_unsorted_header: self.allow_unsorted_header,
+            threads: self.threads,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct IndexingArgs {
+    /// Index format to write.
+    #[arg(short, long, value_enum, default_value_t = IndexFormatArg::Bai)]
+    pub format: IndexFormatArg,
+
+    /// CSI minimum interval size, as a power of two.
+    #[arg(
+        short = 'm',
+        long,
+        default_value_t = BAI_MIN_SHIFT,
+        value_parser = clap::value_parser!(u8).range(1..=i64::from(CSI_MAX_SHIFT))
+    )]
+    pub min_shift: u8,
+
+    /// CSI binning depth. Derived from the longest reference sequence if unset.
+    #[arg(
+        short,
+        long,
+        value_parser = clap::value_parser!(u8).range(1..=i64::from(CSI_MAX_DEPTH))
+    )]
+    pub depth: Option<u8>,
+
+    /// Fail instead of writing CSI when BAI can't address a reference sequence.
+    #[arg(long)]
+    pub no_csi_fallback: bool,
+
+    /// Index BAMs whose header has no `SO:coordinate` (missing, unknown or
+    /// unsorted), as long as the records turn out to be sorted.
+    #[arg(long)]
+    pub allow_unsorted_header: bool,
+
+    /// How many BGZF blocks to decompress in parallel. Defaults to the
+    /// number of cores.
+    #[arg(short = '@', long)]
+    pub threads: Option<NonZeroUsize>,
+
+    /// Split a BAM into this many byte ranges and index them concurrently,
+    /// then merge the results. URL and file inputs only.
+    #[arg(long)]
+    pub split: Option<NonZeroUsize>,
+
+    /// Also write `samtools idxstats`-style per-reference counts next to the
+    /// index, as `.idxstats.tsv`, `.idxstats.json` or both.
+    #[arg(long, value_enum)]
+    pub idxstats: Option<StatsFormatArg>,
+
+    /// Also write `samtools flagstat`-style counts next to the index, as
+    /// `.flagstat.json`, counted in the same pass.
+    #[arg(long)]
+    pub flagstat: bool,
+
+    /// Also write a mean depth per bin track next to the index, as
+    /// `.coverage.bedgraph`, `.coverage.bin` or both.
+    #[arg(long, value_enum)]
+    pub coverage: Option<CoverageFormatArg>,
+
+    /// Coverage bin size in bases.
+    #[arg(long, value_name = "BASES", default_value = "10000")]
+    pub coverage_bin_size: NonZeroUsize,
+
+    /// Leave records with a lower mapping quality out of the coverage.
+    #[arg(long, value_name = "MAPQ", default_value_t = 0)]
+    pub coverage_min_mapq: u8,
+
+    /// Only count records with all of these flags set towards coverage.
+    /// Decimal or 0x-prefixed hex.
+    #[arg(long, value_name = "FLAGS", default_value = "0", value_parser = parse_flags)]
+    pub coverage_include_flags: u16,
+
+    /// Leave records with any of these flags set out of the coverage. Defaults
+    /// to unmapped, secondary, QC-failed and duplicate records.
+    #[arg(long, value_name = "FLAGS", default_value = "0x704", value_parser = parse_flags)]
+    pub coverage_exclude_flags: u16,
+
+    /// What to do when the index already exists.
+    #[arg(long, value_enum, default_value_t = IfExists::Overwrite)]
+    pub if_exists: IfExists,
+}
+
+impl IndexingArgs {
+    pub fn run_options(&self) -> RunOptions {
+        let coverage_options = CoverageOptions {
+            bin_size: self.coverage_bin_size,
+            min_mapping_quality: self.coverage_min_mapq,
+            include_flags: self.coverage_include_flags,
+            exclude_flags: self.coverage_exclude_flags,
+        };
+        RunOptions {
+            indexing: IndexOptions {
+                format: self.format.into(),
+                min_shift: self.min_shift,
+                depth: self.depth,
+                csi_fallback: !self.no_csi_fallback,
+                allow_unsorted_header: self.allow_unsorted_header,
+                threads: self.threads,
+                coverage: self.coverage.map(|_| coverage_options),
+                ..Default::default()
+            },
+            split: self.split,
+            idxstats: self.idxstats.map(Into::into),
+            flagstat: self.flagstat,
+            coverage: self.coverage.map(Into::into),
+            // Set per run by `index`.
+            ..Default::default()
+        }
+    }
+}
+
+/// `--format`, for [`IndexFormat`].
+#[derive(Clone, Copy, ValueEnum)]
+pub enum IndexFormatArg {
+    /// BAM index; reference sequences up to 2^29 - 1 bp.
+    Bai,
+    /// Coordinate-sorted index with configurable binning.
+    Csi,
+    /// Write both a BAI and a CSI.
+    Both,
+}
+
+impl From<IndexFormatArg> for IndexFormat {
+    fn from(arg: IndexFormatArg) -> Self {
+        match arg {
+            IndexFormatArg::Bai => IndexFormat::Bai,
+            IndexFormatArg::Csi => IndexFormat::Csi,
+            IndexFormatArg::Both => IndexFormat::Both,
+        }
+    }
+}
+
+/// `--idxstats`, for [`StatsFormat`].
+#[derive(Clone, Copy, ValueEnum)]
+pub enum StatsFormatArg {
+    /// `samtools idxstats` columns: name, length, mapped, unmapped.
+    Tsv,
+    /// The same counts as a JSON object.
+    Json,
+    /// Write both.
+    Both,
+}
+
+impl From<StatsFormatArg> for StatsFormat {
+    fn from(arg: StatsFormatArg) -> Self {
+        match arg {
+            StatsFormatArg::Tsv => StatsFormat::Tsv,
+            StatsFormatArg::Json => StatsFormat::Json,
+            StatsFormatArg::Both => StatsFormat::Both,
+        }
+    }
+}
+
+/// `--coverage`, for [`CoverageFormat`].
+#[derive(Clone, Copy, ValueEnum)]
+pub enum CoverageFormatArg {
+    /// Mean depth per bin as bedGraph.
+    Bedgraph,
+    /// The same means as little-endian `f32`s; see the README for the layout.
+    Binary,
+    /// Write both.
+    Both,
+}
+
+impl From<CoverageFormatArg> for CoverageFormat {
+    fn from(arg: CoverageFormatArg) -> Self {
+        match arg {
+            CoverageFormatArg::Bedgraph => CoverageFormat::Bedgraph,
+            CoverageFormatArg::Binary => CoverageFormat::Binary,
+            CoverageFormatArg::Both => CoverageFormat::Both,
+        }
+    }
+}
+
+/// Object store settings. Unset values fall back to the usual `AWS_*`,
+/// `GOOGLE_*` and `AZURE_*` environment variables.
+#[derive(Args)]
+#[command(next_help_heading = "Object store")]
+pub struct StoreArgs {
+    /// S3 region.
+    #[arg(long)]
+    pub region: Option<String>,
+
+    /// S3 endpoint, e.g. `http://localhost:9000` for a local MinIO.
+    #[arg(long)]
+    pub endpoint: Option<String>,
+
+    /// S3 access key ID.
+    #[arg(long, requires = "secret_access_key")]
+    pub access_key_id: Option<String>,
+
+    /// S3 secret access key.
+    #[arg(long, requires = "access_key_id")]
+    pub secret_access_key: Option<String>,
+
+    /// S3 session token for temporary credentials.
+    #[arg(long)]
+    pub session_token: Option<String>,
+
+    /// GCS emulator endpoint, e.g. `http://localhost:4443` for
+    /// fake-gcs-server. Defaults to `STORAGE_EMULATOR_HOST`; no credentials
+    /// are sent to it.
+    #[arg(long)]
+    pub gcs_endpoint: Option<String>,
+
+    /// Azure Blob endpoint, e.g. `http://127.0.0.1:10000/devstoreaccount1` for
+    /// Azurite.
+    #[arg(long)]
+    pub azure_endpoint: Option<String>,
+
+    /// Base URL of the DRS server to resolve `drs://` inputs with, instead of
+    /// `https://<host>` (e.g. `http://localhost:8080` for a local mock).
+    #[arg(long)]
+    pub drs_endpoint: Option<String>,
+
+    /// Extra header for HTTP(S) requests to the input's host, as
+    /// `Name: value`; repeat for more. The environment variable takes one per
+    /// line. Like the bearer token and basic auth, it isn't sent to other
+    /// hosts, such as DRS access URLs or those the input redirects to.
+    #[arg(
+        short = 'H',
+        long = "header",
+        value_name = "HEADER",
+        env = "STREAM_INDEX_HTTP_HEADERS",
+        value_delimiter = '\n',
+        hide_env_values = true
+    )]
+    pub headers: Vec<String>,
+
+    /// Bearer token for HTTP(S) requests to the input's host. Prefer the
+    /// environment variable, which other users can't see in the process list.
+    #[arg(long, env = "STREAM_INDEX_BEARER_TOKEN", hide_env_values = true)]
+    pub bearer_token: Option<String>,
+
+    /// Basic auth for HTTP(S) requests to the input's host.
+    #[arg(
+        long,
+        value_name = "USER:PASSWORD",
+        env = "STREAM_INDEX_BASIC_AUTH",
+        hide_env_values = true,
+        conflicts_with = "bearer_token"
+    )]
+    pub basic_auth: Option<String>,
+
+    /// netrc-format file of per-host logins for HTTP(S) requests the flags
+    /// above don't authorize. Defaults to `~/.netrc` if it exists.
+    #[arg(long, value_name = "FILE", env = "NETRC")]
+    pub netrc: Option<PathBuf>,
+
+    /// How many times in a row to retry a failed request, or reopen a stream
+    /// that dropped, before giving up. Streams resume from the last byte read.
+    #[arg(long, default_value_t = 5)]
+    pub retries: usize,
+
+    /// Seconds to wait before the first retry; doubles with each one after.
+    #[arg(long, value_name = "SECONDS", default_value = "1", value_parser = parse_seconds)]
+    pub retry_backoff: Duration,
+
+    /// Longest wait between retries, in seconds.
+    #[arg(long, value_name = "SECONDS", default_value = "30", value_parser = parse_seconds)]
+    pub max_retry_backoff: Duration,
+}
+
+impl StoreArgs {
+    /// Errors here never quote the secrets, unlike clap's, which is why they
+    /// aren't parsed as the arguments are.
+    pub fn store_options(&self) -> anyhow::Result<StoreOptions> {
+        let headers = self
+            .headers
+            .iter()
+            .filter(|header| !header.trim().is_empty())
+            .map(|header| {
+                let (name, value) = header
+                    .split_once(':')
+                    .context("HTTP headers must be given as `Name: value`")?;
+                Ok((name.trim().to_string(), value.trim().to_string()))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let basic = match &self.basic_auth {
+            Some(basic_auth) => {
+                let (user, password) = basic_auth
+                    .split_once(':')
+                    .context("--basic-auth must be given as `user:password`")?;
+                Some((user.to_string(), password.to_string()))
+            }
+            None => None,
+        };
+        let netrc_path = self.netrc.clone().or_else(|| {
+            let path = PathBuf::from(std::env::var_os("HOME")?).join(".netrc");
+            path.is_file().then_some(path)
+        });
+        let netrc = match netrc_path {
+            Some(path) => Some(
+                Netrc::read(&path)
+                    .with_context(|| format!("Failed to read netrc {}", path.display()))?,
+            ),
+            None => None,
+        };
+        Ok(StoreOptions {
+            region: self.region.clone(),
+            endpoint: self.endpoint.clone(),
+            access_key_id: self.access_key_id.clone(),
+            secret_access_key: self.secret_access_key.clone(),
+            session_token: self.session_token.clone(),
+            gcs_endpoint: self.gcs_endpoint.clone(),
+            azure_endpoint: self.azure_endpoint.clone(),
+            drs_endpoint: self.drs_endpoint.clone(),
+            http_auth: HttpAuth {
+                headers,
+                bearer_token: self.bearer_token.clone(),
+                basic,
+                netrc,
+                host: None,
+            },
+            retry: RetryOptions {
+                max_retries: self.retries,
+                initial_backoff: self.retry_backoff,
+                max_backoff: self.max_retry_backoff,
+            },
+        })
+    }
+}
+
+fn parse_source(s: &str) -> Result<Source, String> {
+    s.parse().map_err(|e: stream_index::Error| e.to_string())
+}
+
+fn parse_destination(s: &str) -> Result<Destination, String> {
+    s.parse().map_err(|e: anyhow::Error| format!("{:#}", e))
+}
+
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    let seconds: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    Duration::try_from_secs_f64(seconds).map_err(|e| format!("{}", e))
+}
+
+fn parse_flags(s: &str) -> Result<u16, String> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| format!("{}", e))
+}
+
+fn parse_url(s: &str) -> Result<url::Url, String> {
+    parse_url_or_path(s).map_err(|e| format!("{:#}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::error::ErrorKind;
+
+    use super::*;
+
+    fn parse(args: &str) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(std::iter::once("stream-index").chain(args.split_whitespace()))
+    }
+
+    #[test]
+    fn subcommands_parse_their_required_arguments() {
+        for args in [
+            "index s3://bucket/a.bam",
+            "index -v -v s3://bucket/a.bam -o - -f both --idxstats tsv",
+            "batch s3://bucket/a.bam s3://bucket/b.bam",
+            "batch --manifest inputs.tsv",
+            "batch --prefix s3://bucket/bams/ -j 8",
+            "tabix s3://bucket/a.vcf.gz -p vcf",
+            "faidx s3://bucket/ref.fa.gz -o out/",
+            "verify s3://bucket/a.bam -i s3://bucket/a.bam.bai",
+            "serve s3://bucket/bams/ --listen 0.0.0.0:8080",
+            "view s3://bucket/a.bam chr1:1-100 chr2",
+            "query s3://bucket/a.bam chr1 -O bam",
+            "index s3://bucket/a.bam --access-key-id id --secret-access-key secret",
+        ] {
+            assert!(parse(args).is_ok(), "{}", args);
+        }
+
+        let cli = parse("-q index a.bam --if-exists skip --split 2").unwrap();
+        assert_eq!(cli.log_level(), log::LevelFilter::Error);
+        let Command::Index(args) = cli.command else {
+            panic!("expected index")
+        };
+        assert!(matches!(args.indexing.if_exists, IfExists::Skip));
+        assert_eq!(args.indexing.split.map(NonZeroUsize::get), Some(2));
+    }
+
+    #[test]
+    fn missing_conflicting_and_invalid_arguments_are_rejected() {
+        for (args, kind) in [
+            ("", ErrorKind::DisplayHelpOnMissingArgumentOrSubcommand),
+            ("index", ErrorKind::MissingRequiredArgument),
+            ("batch", ErrorKind::MissingRequiredArgument),
+            ("tabix", ErrorKind::MissingRequiredArgument),
+            ("faidx", ErrorKind::MissingRequiredArgument),
+            ("verify", ErrorKind::MissingRequiredArgument),
+            ("serve", ErrorKind::MissingRequiredArgument),
+            ("view s3://bucket/a.bam", ErrorKind::MissingRequiredArgument),
+            ("index a.bam --verbose --quiet", ErrorKind::ArgumentConflict),
+            (
+                "index a.bam --access-key-id id",
+                ErrorKind::MissingRequiredArgument,
+            ),
+            (
+                "index a.bam --secret-access-key secret",
+                ErrorKind::MissingRequiredArgument,
+            ),
+            (
+                "index a.bam --bearer-token t --basic-auth u:p",
+                ErrorKind::ArgumentConflict,
+            ),
+            ("index a.bam --format tbi", ErrorKind::InvalidValue),
+            ("index a.bam --min-shift 0", ErrorKind::ValueValidation),
+            (
+                "index a.bam --coverage-exclude-flags 0xz",
+                ErrorKind::ValueValidation,
+            ),
+            ("index a.bam --split 0", ErrorKind::ValueValidation),
+            ("batch a.bam --jobs 0", ErrorKind::ValueValidation),
+            ("tabix a.vcf.gz -p sam", ErrorKind::InvalidValue),
+            (
+                "serve s3://bucket/ --listen localhost",
+                ErrorKind::ValueValidation,
+            ),
+            ("view a.bam chr1 -O cram", ErrorKind::InvalidValue),
+            ("index a.bam --no-such-flag", ErrorKind::UnknownArgument),
+            ("reindex a.bam", ErrorKind::InvalidSubcommand),
+        ] {
+            let kind_found = parse(args).err().map(|e| e.kind());
+            assert_eq!(kind_found, Some(kind), "{}", args);
+        }
+    }
+
+    #[test]
+    fn credentials_are_split_without_quoting_them_in_errors() {
+        let store_options = |args| {
+            let Command::Index(args) = parse(args).unwrap().command else {
+                panic!("expected index")
+            };
+            args.store.store_options()
+        };
+
+        let options = store_options("index a.bam -H X-Api-Key:key --basic-auth u:p:w").unwrap();
+        let auth = options.http_auth;
+        assert_eq!(auth.headers, [("X-Api-Key".into(), "key".into())]);
+        assert_eq!(auth.basic, Some(("u".into(), "p:w".into())));
+        assert!(auth.host.is_none());
+
+        for args in [
+            "index a.bam -H X-Api-Key=secret",
+            "index a.bam --basic-auth usersecret",
+        ] {
+            let e = store_options(args).err().expect(args);
+            let message = format!("{:#}", e);
+            assert!(message.contains("must be given as"), "{}", message);
+            assert!(!message.contains("secret"), "{}", message);
+        }
+    }
+}
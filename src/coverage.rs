@@ -0,0 +1,307 @@
+//! Mean depth per bin, counted while indexing.
+
+use std::num::NonZeroUsize;
+
+use noodles::{
+    core::Position,
+    sam::{
+        self,
+        record::{
+            cigar::{op::Kind, Op},
+            Flags, MappingQuality,
+        },
+    },
+};
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+/// Which coverage tracks to write next to the index.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoverageFormat {
+    /// Mean depth per bin as bedGraph.
+    Bedgraph,
+    /// The same means as little-endian `f32`s; see the README for the layout.
+    Binary,
+    /// Write both.
+    Both,
+}
+
+impl CoverageFormat {
+    /// The tracks to write, one file each.
+    pub fn parts(self) -> &'static [CoverageKind] {
+        match self {
+            CoverageFormat::Bedgraph => &[CoverageKind::Bedgraph],
+            CoverageFormat::Binary => &[CoverageKind::Binary],
+            CoverageFormat::Both => &[CoverageKind::Bedgraph, CoverageKind::Binary],
+        }
+    }
+}
+
+/// The format of a single coverage track.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoverageKind {
+    /// `.coverage.bedgraph`
+    Bedgraph,
+    /// `.coverage.bin`
+    Binary,
+}
+
+impl CoverageKind {
+    /// The file extension, without the dot.
+    pub fn extension(self) -> &'static str {
+        match self {
+            CoverageKind::Bedgraph => "coverage.bedgraph",
+            CoverageKind::Binary => "coverage.bin",
+        }
+    }
+}
+
+/// Which records count towards coverage, and the bins it's summed over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CoverageOptions {
+    /// Bases per bin; the last bin of a reference sequence may be shorter.
+    pub bin_size: NonZeroUsize,
+    /// Records with a lower MAPQ are skipped.
+    pub min_mapping_quality: u8,
+    /// Records must have all of these flags set.
+    pub include_flags: u16,
+    /// Records with any of these flags set are skipped.
+    pub exclude_flags: u16,
+}
+
+impl Default for CoverageOptions {
+    fn default() -> Self {
+        Self {
+            bin_size: NonZeroUsize::new(10_000).unwrap(),
+            min_mapping_quality: 0,
+            include_flags: 0,
+            // As `samtools depth`: unmapped, secondary, QC-failed and
+            // duplicate records.
+            exclude_flags: 0x704,
+        }
+    }
+}
+
+/// Aligned bases per bin, from which the mean depths are worked out.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Coverage {
+    options: CoverageOptions,
+    /// The reference sequences' lengths, in header order.
+    lengths: Vec<usize>,
+    /// Per reference sequence, in header order.
+    bins: Vec<Vec<u64>>,
+}
+
+impl Coverage {
+    /// Empty bins for the reference sequences in `header`.
+    pub fn new(header: &sam::Header, options: CoverageOptions) -> Self {
+        let lengths: Vec<_> = header
+            .reference_sequences()
+            .values()
+            .map(|reference_sequence| usize::from(reference_sequence.length()))
+            .collect();
+        let bins = lengths
+            .iter()
+            .map(|length| vec![0; length.div_ceil(options.bin_size.get())])
+            .collect();
+        Self {
+            options,
+            lengths,
+            bins,
+        }
+    }
+
+    /// What's counted.
+    pub fn options(&self) -> &CoverageOptions {
+        &self.options
+    }
+
+    /// Counts the bases `ops` aligns from `start`, if the record passes the
+    /// filters. Only M, = and X operations count; deletions and skips don't.
+    pub fn add_record(
+        &mut self,
+        flags: Flags,
+        mapping_quality: Option<MappingQuality>,
+        (reference_sequence_id, start): (usize, Position),
+        ops: &[Op],
+    ) {
+        let flags = u16::from(flags);
+        if flags & self.options.include_flags != self.options.include_flags
+            || flags & self.options.exclude_flags != 0
+            // A missing MAPQ is stored as 255.
+            || mapping_quality.is_some_and(|q| u8::from(q) < self.options.min_mapping_quality)
+        {
+            return;
+        }
+        let (Some(bins), Some(&length)) = (
+            self.bins.get_mut(reference_sequence_id),
+            self.lengths.get(reference_sequence_id),
+        ) else {
+            return;
+        };
+        let bin_size = self.options.bin_size.get();
+        let mut position = usize::from(start) - 1;
+        for op in ops {
+            match op.kind() {
+                Kind::Match | Kind::SequenceMatch | Kind::SequenceMismatch => {
+                    // Bases past the end of the reference sequence are dropped.
+                    let end = (position + op.len()).min(length);
+                    let mut from = position;
+                    while from < end {
+                        let i = from / bin_size;
+                        let to = end.min((i + 1) * bin_size);
+                        bins[i] += (to - from) as u64;
+                        from = to;
+                    }
+                    position += op.len();
+                }
+                Kind::Deletion | Kind::Skip => position += op.len(),
+                _ => {}
+            }
+        }
+    }
+
+    /// Adds the counts from another part of the same file.
+    pub fn add(&mut self, other: &Coverage) {
+        for (bins, other_bins) in self.bins.iter_mut().zip(&other.bins) {
+            for (n, m) in bins.iter_mut().zip(other_bins) {
+                *n += m;
+            }
+        }
+    }
+
+    /// Mean depth of each bin; the last bin of a reference sequence is only
+    /// as wide as what's left of it.
+    fn mean_depths<'a>(
+        &'a self,
+        header: &'a sam::Header,
+    ) -> impl Iterator<Item = (&'a str, usize, Vec<f64>)> + 'a {
+        let bin_size = self.options.bin_size.get();
+        header.reference_sequences().iter().zip(&self.bins).map(
+            move |((name, reference_sequence), bins)| {
+                let length = usize::from(reference_sequence.length());
+                let depths = bins
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &n)| {
+                        let width = bin_size.min(length - i * bin_size);
+                        n as f64 / width as f64
+                    })
+                    .collect();
+                (name.as_str(), length, depths)
+            },
+        )
+    }
+
+    /// Encodes the mean depths as `kind`.
+    pub fn encode(&self, header: &sam::Header, kind: CoverageKind) -> Result<Vec<u8>> {
+        match kind {
+            CoverageKind::Bedgraph => Ok(self.to_bedgraph(header).into_bytes()),
+            CoverageKind::Binary => self.to_binary(header),
+        }
+    }
+
+    /// One line per bin: name, 0-based start, end, and the mean depth to two
+    /// decimal places.
+    fn to_bedgraph(&self, header: &sam::Header) -> String {
+        let bin_size = self.options.bin_size.get();
+        let mut bedgraph = String::from("track type=bedGraph\n");
+        for (name, length, depths) in self.mean_depths(header) {
+            for (i, depth) in depths.into_iter().enumerate() {
+                let start = i * bin_size;
+                let end = length.min(start + bin_size);
+                let depth = (depth * 100.0).round() / 100.0;
+                bedgraph.push_str(&format!("{}\t{}\t{}\t{}\n", name, start, end, depth));
+            }
+        }
+        bedgraph
+    }
+
+    /// `COV\1`, the bin size and the number of reference sequences as `u32`s,
+    /// then for each reference sequence its name's length as a `u32`, the name,
+    /// its length as a `u32`, and an `f32` mean depth per bin.
+    fn to_binary(&self, header: &sam::Header) -> Result<Vec<u8>> {
+        let mut buf = b"COV\x01".to_vec();
+        buf.extend(u32::try_from(self.options.bin_size.get())?.to_le_bytes());
+        buf.extend(u32::try_from(self.bins.len())?.to_le_bytes());
+        for (name, length, depths) in self.mean_depths(header) {
+            buf.extend(u32::try_from(name.len())?.to_le_bytes());
+            buf.extend(name.as_bytes());
+            buf.extend(u32::try_from(length)?.to_le_bytes());
+            for depth in depths {
+                buf.extend((depth as f32).to_le_bytes());
+            }
+        }
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        build_bam_index,
+        testing::{bam_from_sam, sam_header, sam_record},
+        IndexOptions,
+    };
+
+    #[tokio::test]
+    async fn bases_are_binned_up_to_the_reference_end() {
+        let mut sam = sam_header(&[("chr1", 25), ("chr2", 10)]);
+        for record in [
+            // Five bases, a deletion, then five more across the first bin end.
+            sam_record("a", 0, "chr1", 1, "5M2D5M"),
+            // Duplicates are skipped.
+            sam_record("b", 1024, "chr1", 1, "10M"),
+            // Clips and insertions take up no reference bases.
+            sam_record("c", 0, "chr1", 15, "2S3M1I2M"),
+            // Runs five bases past the end of chr1.
+            sam_record("d", 0, "chr1", 21, "10M"),
+            sam_record("e", 0, "chr2", 1, "4M"),
+        ] {
+            sam.push_str(&record);
+        }
+        let options = IndexOptions {
+            coverage: Some(CoverageOptions {
+                bin_size: NonZeroUsize::new(10).unwrap(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let bam = bam_from_sam(&sam);
+        let bam_index = build_bam_index(&mut &bam[..], &options).await.unwrap();
+        let coverage = bam_index.coverage.unwrap();
+
+        let bedgraph = coverage
+            .encode(&bam_index.header, CoverageKind::Bedgraph)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(bedgraph).unwrap(),
+            "track type=bedGraph\n\
+             chr1\t0\t10\t0.8\n\
+             chr1\t10\t20\t0.7\n\
+             chr1\t20\t25\t1\n\
+             chr2\t0\t10\t0.4\n"
+        );
+
+        let binary = coverage
+            .encode(&bam_index.header, CoverageKind::Binary)
+            .unwrap();
+        let mut expected = b"COV\x01".to_vec();
+        expected.extend(10u32.to_le_bytes());
+        expected.extend(2u32.to_le_bytes());
+        for (name, length, depths) in [
+            ("chr1", 25u32, &[0.8f32, 0.7, 1.0][..]),
+            ("chr2", 10, &[0.4]),
+        ] {
+            expected.extend((name.len() as u32).to_le_bytes());
+            expected.extend(name.as_bytes());
+            expected.extend(length.to_le_bytes());
+            for depth in depths {
+                expected.extend(depth.to_le_bytes());
+            }
+        }
+        assert_eq!(binary, expected);
+    }
+}
@@ -0,0 +1,601 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use noodles::{
+    core::Position,
+    cram::{self, crai, data_container::Slice, DataContainer},
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use stream_index::{get_async_stream_reader, put_bytes, Source, StoreOptions};
+
+use crate::{check_existing, index_location, Destination, IfExists, Outcome};
+
+/// Magic, version and file ID.
+const FILE_DEFINITION_LENGTH: u64 = 26;
+
+/// The block content type of a slice header.
+const SLICE_HEADER_CONTENT_TYPE: u8 = 2;
+const RAW_COMPRESSION_METHOD: u8 = 0;
+
+const UNMAPPED: i32 = -1;
+const MULTIREF: i32 = -2;
+
+/// The parts of a container header indexing needs.
+struct ContainerHeader {
+    /// Length of the container's blocks, which follow the header.
+    length: usize,
+    reference_sequence_id: i32,
+    alignment_start: i32,
+    block_count: i32,
+    landmarks: Vec<usize>,
+    /// The header as read, so the container can be handed to noodles whole.
+    raw: Vec<u8>,
+}
+
+impl ContainerHeader {
+    /// The EOF container CRAM 3 files end with.
+    fn is_eof(&self) -> bool {
+        self.length == 15
+            && self.reference_sequence_id == UNMAPPED
+            && self.alignment_start == 4542278
+            && self.block_count == 1
+    }
+}
+
+fn get_u8(buf: &mut &[u8]) -> Result<u8> {
+    let (&b, rest) = buf.split_first().context("Unexpected end of block")?;
+    *buf = rest;
+    Ok(b)
+}
+
+/// Number of bytes following the first of an ITF8 integer.
+fn itf8_extra_len(b0: u8) -> usize {
+    (b0 & 0xf0).leading_ones() as usize
+}
+
+fn get_itf8(buf: &mut &[u8]) -> Result<i32> {
+    let b0 = get_u8(buf)?;
+    let len = itf8_extra_len(b0);
+    anyhow::ensure!(buf.len() >= len, "Unexpected end of block");
+    let (bytes, rest) = buf.split_at(len);
+    *buf = rest;
+    let b = |i: usize| i32::from(bytes[i]);
+    let b0 = i32::from(b0);
+    Ok(match len {
+        0 => b0,
+        1 => (b0 & 0x7f) << 8 | b(0),
+        2 => (b0 & 0x3f) << 16 | b(0) << 8 | b(1),
+        3 => (b0 & 0x1f) << 24 | b(0) << 16 | b(1) << 8 | b(2),
+        _ => (b0 & 0x0f) << 28 | b(0) << 20 | b(1) << 12 | b(2) << 4 | (b(3) & 0x0f),
+    })
+}
+
+/// Number of bytes following the first of an LTF8 integer.
+fn ltf8_extra_len(b0: u8) -> usize {
+    b0.leading_ones() as usize
+}
+
+fn get_ltf8(buf: &mut &[u8]) -> Result<i64> {
+    let b0 = get_u8(buf)?;
+    let len = ltf8_extra_len(b0);
+    anyhow::ensure!(buf.len() >= len, "Unexpected end of block");
+    let (bytes, rest) = buf.split_at(len);
+    *buf = rest;
+    // The leading ones count the bytes that follow; the rest of the first byte
+    // holds the most significant bits.
+    let mask = 0xffu8.checked_shr(len as u32 + 1).unwrap_or(0);
+    Ok(bytes
+        .iter()
+        .fold(i64::from(b0 & mask), |n, &b| n << 8 | i64::from(b)))
+}
+
+/// Reads the bytes of an ITF8 or LTF8 integer onto the end of `raw` and
+/// returns where they start.
+async fn read_int_bytes<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    raw: &mut Vec<u8>,
+    extra_len: fn(u8) -> usize,
+) -> Result<usize> {
+    let start = raw.len();
+    let b0 = reader.read_u8().await?;
+    raw.push(b0);
+    raw.resize(start + 1 + extra_len(b0), 0);
+    reader.read_exact(&mut raw[start + 1..]).await?;
+    Ok(start)
+}
+
+async fn read_itf8<R: AsyncRead + Unpin>(reader: &mut R, raw: &mut Vec<u8>) -> Result<i32> {
+    let start = read_int_bytes(reader, raw, itf8_extra_len).await?;
+    get_itf8(&mut &raw[start..])
+}
+
+async fn read_ltf8<R: AsyncRead + Unpin>(reader: &mut R, raw: &mut Vec<u8>) -> Result<i64> {
+    let start = read_int_bytes(reader, raw, ltf8_extra_len).await?;
+    get_ltf8(&mut &raw[start..])
+}
+
+async fn read_container_header<R: AsyncRead + Unpin>(reader: &mut R) -> Result<ContainerHeader> {
+    let mut raw = Vec::new();
+    let length = reader.read_i32_le().await?;
+    raw.extend_from_slice(&length.to_le_bytes());
+    let length = usize::try_from(length).context("Invalid container length")?;
+
+    let reference_sequence_id = read_itf8(reader, &mut raw).await?;
+    let alignment_start = read_itf8(reader, &mut raw).await?;
+    let _alignment_span = read_itf8(reader, &mut raw).await?;
+    let _record_count = read_itf8(reader, &mut raw).await?;
+    let _record_counter = read_ltf8(reader, &mut raw).await?;
+    let _base_count = read_ltf8(reader, &mut raw).await?;
+    let block_count = read_itf8(reader, &mut raw).await?;
+    let landmark_count = read_itf8(reader, &mut raw).await?;
+    let mut landmarks = Vec::new();
+    for _ in 0..landmark_count {
+        let landmark = read_itf8(reader, &mut raw).await?;
+        let landmark = usize::try_from(landmark)
+            .ok()
+            .filter(|&landmark| landmark < length)
+            .context("Invalid slice landmark")?;
+        landmarks.push(landmark);
+    }
+    let crc32 = reader.read_u32_le().await?;
+    raw.extend_from_slice(&crc32.to_le_bytes());
+
+    Ok(ContainerHeader {
+        length,
+        reference_sequence_id,
+        alignment_start,
+        block_count,
+        landmarks,
+        raw,
+    })
+}
+
+/// Reads the reference sequence ID, alignment start and span from the slice
+/// header block at the start of `block`, or `None` if the block is compressed.
+fn read_slice_header_context(mut block: &[u8]) -> Result<Option<(i32, i32, i32)>> {
+    let compression_method = get_u8(&mut block)?;
+    let content_type = get_u8(&mut block)?;
+    if content_type != SLICE_HEADER_CONTENT_TYPE {
+        anyhow::bail!(
+            "Expected a slice header block, found content type {}",
+            content_type
+        );
+    }
+    let _content_id = get_itf8(&mut block)?;
+    let _compressed_size = get_itf8(&mut block)?;
+    let _uncompressed_size = get_itf8(&mut block)?;
+    if compression_method != RAW_COMPRESSION_METHOD {
+        return Ok(None);
+    }
+    let reference_sequence_id = get_itf8(&mut block)?;
+    let alignment_start = get_itf8(&mut block)?;
+    let alignment_span = get_itf8(&mut block)?;
+    Ok(Some((
+        reference_sequence_id,
+        alignment_start,
+        alignment_span,
+    )))
+}
+
+/// Indexes a slice by its records: one entry per reference sequence, with
+/// unplaced records first.
+fn push_records_from_slice(
+    index: &mut crai::Index,
+    container: &DataContainer,
+    slice: &Slice,
+    offset: u64,
+    landmark: u64,
+    slice_length: u64,
+) -> Result<()> {
+    let mut ranges: BTreeMap<Option<usize>, (Option<Position>, Option<Position>)> = BTreeMap::new();
+    for record in slice.records(container.compression_header())? {
+        let (start, end) = ranges.entry(record.reference_sequence_id()).or_default();
+        if let Some(alignment_start) = record.alignment_start() {
+            *start = Some(start.map_or(alignment_start, |s| s.min(alignment_start)));
+        }
+        if let Some(alignment_end) = record.alignment_end() {
+            *end = Some(end.map_or(alignment_end, |e| e.max(alignment_end)));
+        }
+    }
+    for (reference_sequence_id, range) in ranges {
+        let (alignment_start, alignment_span) = match (reference_sequence_id, range) {
+            (Some(_), (Some(start), Some(end))) => {
+                (Some(start), usize::from(end) - usize::from(start) + 1)
+            }
+            _ => (None, 0),
+        };
+        index.push(crai::Record::new(
+            reference_sequence_id,
+            alignment_start,
+            alignment_span,
+            offset,
+            landmark,
+            slice_length,
+        ));
+    }
+    Ok(())
+}
+
+/// Builds a CRAI index from a CRAM 3 stream.
+///
+/// Single-reference and unmapped slices are indexed from their headers alone;
+/// only multi-reference slices are decoded, and never against the reference.
+pub async fn build_cram_index<R: AsyncRead + Unpin>(reader: &mut R) -> Result<crai::Index> {
+    let file_definition = cram::AsyncReader::new(&mut *reader)
+        .read_file_definition()
+        .await
+        .context("Not a CRAM file")?;
+    let version = file_definition.version();
+    if version.major() != 3 {
+        anyhow::bail!(
+            "CRAM {}.{} is not supported; only CRAM 3.x can be indexed",
+            version.major(),
+            version.minor()
+        );
+    }
+
+    // Skip the SAM header container.
+    let mut position = FILE_DEFINITION_LENGTH;
+    let header = read_container_header(reader).await?;
+    let mut buf = vec![0; header.length];
+    reader.read_exact(&mut buf).await?;
+    position += (header.raw.len() + header.length) as u64;
+
+    let mut index = Vec::new();
+    loop {
+        let header = read_container_header(reader)
+            .await
+            .with_context(|| format!("Failed to read the container at byte {}", position))?;
+        buf.resize(header.length, 0);
+        reader.read_exact(&mut buf).await?;
+        if header.is_eof() {
+            break;
+        }
+
+        // Decoded on demand, for multi-reference or compressed slice headers.
+        let mut container = None;
+        for (i, &landmark) in header.landmarks.iter().enumerate() {
+            let slice_end = header
+                .landmarks
+                .get(i + 1)
+                .copied()
+                .unwrap_or(header.length);
+            let slice_length = slice_end
+                .checked_sub(landmark)
+                .context("Slice landmarks are out of order")?;
+            let context = read_slice_header_context(&buf[landmark..]).with_context(|| {
+                format!("Invalid slice header in the container at byte {}", position)
+            })?;
+            let (reference_sequence_id, alignment_start, alignment_span) = match context {
+                Some((UNMAPPED, _, _)) => (None, None, 0),
+                Some((id, start, span)) if id >= 0 => {
+                    let id = usize::try_from(id)?;
+                    let start = usize::try_from(start)
+                        .ok()
+                        .and_then(Position::new)
+                        .context("Invalid slice alignment start")?;
+                    (Some(id), Some(start), usize::try_from(span)?)
+                }
+                Some((MULTIREF, _, _)) | None => {
+                    if container.is_none() {
+                        let mut raw = header.raw.clone();
+                        raw.extend_from_slice(&buf);
+                        container = cram::AsyncReader::new(&raw[..])
+                            .read_data_container()
+                            .await?;
+                    }
+                    let container = container.as_ref().context("Missing data container")?;
+                    let slice = container
+                        .slices()
+                        .get(i)
+                        .context("Missing slice in data container")?;
+                    push_records_from_slice(
+                        &mut index,
+                        container,
+                        slice,
+                        position,
+                        landmark as u64,
+                        slice_length as u64,
+                    )?;
+                    continue;
+                }
+                Some((id, _, _)) => anyhow::bail!("Invalid slice reference sequence ID {}", id),
+            };
+            index.push(crai::Record::new(
+                reference_sequence_id,
+                alignment_start,
+                alignment_span,
+                position,
+                landmark as u64,
+                slice_length as u64,
+            ));
+        }
+        position += (header.raw.len() + header.length) as u64;
+    }
+    Ok(index)
+}
+
+fn encode_crai(index: &crai::Index) -> Result<Vec<u8>> {
+    // In memory, as with CSI: the async writer shuts its inner writer down.
+    let mut crai_writer = crai::Writer::new(Vec::new());
+    crai_writer.write_index(index)?;
+    Ok(crai_writer.finish()?)
+}
+
+/// Streams a CRAM and writes its `.crai`, like `index_source` does for BAMs.
+pub async fn index_cram_source(
+    input: &Source,
+    output: Option<&Destination>,
+    if_exists: IfExists,
+    store_options: &StoreOptions,
+) -> Result<Outcome> {
+    let location = index_location(output, input, "crai")?;
+    if let Some(url) =
+        check_existing(std::slice::from_ref(&location), if_exists, store_options).await?
+    {
+        return Ok(Outcome::Skipped(url));
+    }
+
+    let mut reader = get_async_stream_reader(input, store_options).await?;
+    let index = build_cram_index(&mut reader).await?;
+
+    put_bytes(location.as_ref(), &encode_crai(&index)?, store_options).await?;
+    if let Some(url) = &location {
+        log::info!("Wrote index to {}", url);
+    }
+    Ok(Outcome::Indexed(vec![location]))
+}
+
+#[cfg(test)]
+mod tests {
+    use noodles::{fasta, sam};
+
+    use super::*;
+
+    const REFERENCE_SEQUENCE_LENGTH: usize = 1000;
+
+    /// Encodes SAM records as a CRAM, one container per 10240 records,
+    /// against reference sequences of all `A`s.
+    fn cram_from_sam(records: &[String]) -> Vec<u8> {
+        let mut sam = String::from("@HD\tVN:1.6\tSO:coordinate\n");
+        for name in ["chr1", "chr2"] {
+            sam.push_str(&format!(
+                "@SQ\tSN:{}\tLN:{}\n",
+                name, REFERENCE_SEQUENCE_LENGTH
+            ));
+        }
+        sam.extend(records.iter().cloned());
+
+        let repository = fasta::Repository::new(
+            ["chr1", "chr2"]
+                .iter()
+                .map(|name| {
+                    let sequence = vec![b'A'; REFERENCE_SEQUENCE_LENGTH];
+                    fasta::Record::new(fasta::record::Definition::new(*name, None), sequence.into())
+                })
+                .collect::<Vec<_>>(),
+        );
+        let mut reader = sam::Reader::new(sam.as_bytes());
+        let header = reader.read_header().unwrap();
+        let mut writer = cram::writer::Builder::default()
+            .set_reference_sequence_repository(repository)
+            .build_with_writer(Vec::new());
+        writer.write_file_definition().unwrap();
+        writer.write_file_header(&header).unwrap();
+        for result in reader.records(&header) {
+            let record = cram::Record::try_from_alignment_record(&header, &result.unwrap());
+            writer.write_record(&header, record.unwrap()).unwrap();
+        }
+        writer.try_finish(&header).unwrap();
+        writer.get_ref().clone()
+    }
+
+    fn record(name: &str, flags: u16, reference: &str, start: usize) -> String {
+        let cigar = if reference == "*" { "*" } else { "8M" };
+        format!(
+            "{}\t{}\t{}\t{}\t60\t{}\t*\t0\t0\tACGTACGT\tIIIIIIII\n",
+            name, flags, reference, start, cigar
+        )
+    }
+
+    fn mapped(reference: &str, starts: impl IntoIterator<Item = usize>) -> Vec<String> {
+        starts
+            .into_iter()
+            .map(|start| record(&format!("{}_{}", reference, start), 0, reference, start))
+            .collect()
+    }
+
+    /// The file definition and header container, then each container's header
+    /// and blocks, the EOF container last.
+    async fn split_containers(cram: &[u8]) -> (&[u8], Vec<(ContainerHeader, &[u8])>) {
+        let mut reader = &cram[FILE_DEFINITION_LENGTH as usize..];
+        let header = read_container_header(&mut reader).await.unwrap();
+        let (_, mut reader) = reader.split_at(header.length);
+        let head = &cram[..cram.len() - reader.len()];
+        let mut containers = Vec::new();
+        while !reader.is_empty() {
+            let header = read_container_header(&mut reader).await.unwrap();
+            let (blocks, rest) = reader.split_at(header.length);
+            containers.push((header, blocks));
+            reader = rest;
+        }
+        (head, containers)
+    }
+
+    fn put_itf8(buf: &mut Vec<u8>, n: i32) {
+        let n = n as u32;
+        match n {
+            0..=0x7f => buf.push(n as u8),
+            0x80..=0x3fff => buf.extend([0x80 | (n >> 8) as u8, n as u8]),
+            0x4000..=0x1f_ffff => buf.extend([0xc0 | (n >> 16) as u8, (n >> 8) as u8, n as u8]),
+            0x20_0000..=0x0fff_ffff => buf.extend([
+                0xe0 | (n >> 24) as u8,
+                (n >> 16) as u8,
+                (n >> 8) as u8,
+                n as u8,
+            ]),
+            _ => buf.extend([
+                0xf0 | (n >> 28) as u8,
+                (n >> 20) as u8,
+                (n >> 12) as u8,
+                (n >> 4) as u8,
+                n as u8 & 0x0f,
+            ]),
+        }
+    }
+
+    /// The length of the block at the start of `blocks`.
+    fn block_length(mut blocks: &[u8]) -> usize {
+        let len = blocks.len();
+        let _compression_method = get_u8(&mut blocks).unwrap();
+        let _content_type = get_u8(&mut blocks).unwrap();
+        let _content_id = get_itf8(&mut blocks).unwrap();
+        let compressed_size = get_itf8(&mut blocks).unwrap();
+        let _uncompressed_size = get_itf8(&mut blocks).unwrap();
+        // The data, then its CRC32.
+        len - blocks.len() + compressed_size as usize + 4
+    }
+
+    /// Rewrites a single-slice container with its slice repeated `copies`
+    /// times, and its landmarks where each slice starts. noodles writes where
+    /// each slice ends instead, less the compression header, which it can
+    /// read back but other readers can't.
+    fn with_slices((header, blocks): &(ContainerHeader, &[u8]), copies: usize) -> Vec<u8> {
+        assert_eq!(header.landmarks.len(), 1);
+        let compression_header_length = block_length(blocks);
+        let slice = &blocks[compression_header_length..];
+
+        let mut fields = &header.raw[4..];
+        let reference_sequence_id = get_itf8(&mut fields).unwrap();
+        let alignment_start = get_itf8(&mut fields).unwrap();
+        let alignment_span = get_itf8(&mut fields).unwrap();
+        let record_count = get_itf8(&mut fields).unwrap();
+        let counts_start = header.raw.len() - fields.len();
+        let _record_counter = get_ltf8(&mut fields).unwrap();
+        let _base_count = get_ltf8(&mut fields).unwrap();
+        // Neither is indexed, so they're left as they were.
+        let counts = &header.raw[counts_start..header.raw.len() - fields.len()];
+
+        let length = compression_header_length + copies * slice.len();
+        let mut raw = (length as i32).to_le_bytes().to_vec();
+        put_itf8(&mut raw, reference_sequence_id);
+        put_itf8(&mut raw, alignment_start);
+        put_itf8(&mut raw, alignment_span);
+        put_itf8(&mut raw, copies as i32 * record_count);
+        raw.extend_from_slice(counts);
+        put_itf8(&mut raw, 1 + copies as i32 * (header.block_count - 1));
+        put_itf8(&mut raw, copies as i32);
+        for i in 0..copies {
+            put_itf8(
+                &mut raw,
+                (compression_header_length + i * slice.len()) as i32,
+            );
+        }
+        raw.extend_from_slice(&crc32fast::hash(&raw).to_le_bytes());
+
+        raw.extend_from_slice(&blocks[..compression_header_length]);
+        for _ in 0..copies {
+            raw.extend_from_slice(slice);
+        }
+        raw
+    }
+
+    fn container_bytes((header, blocks): &(ContainerHeader, &[u8])) -> Vec<u8> {
+        [&header.raw[..], blocks].concat()
+    }
+
+    /// Indexes `cram` and checks the `.crai` is byte for byte what noodles'
+    /// indexer writes for it.
+    async fn index_as_noodles_does(cram: &[u8]) -> crai::Index {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), cram).unwrap();
+        let expected = cram::index(file.path()).unwrap();
+
+        let index = build_cram_index(&mut &cram[..]).await.unwrap();
+        assert_eq!(
+            encode_crai(&index).unwrap(),
+            encode_crai(&expected).unwrap()
+        );
+        index
+    }
+
+    type Entry = (Option<usize>, Option<usize>, usize, u64);
+
+    /// Each record's reference sequence ID, alignment start and span, and
+    /// landmark.
+    fn entries(index: &crai::Index) -> Vec<Entry> {
+        index
+            .iter()
+            .map(|record| {
+                (
+                    record.reference_sequence_id(),
+                    record.alignment_start().map(usize::from),
+                    record.alignment_span(),
+                    record.landmark(),
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn slices_are_indexed_as_noodles_indexes_them() {
+        let first = cram_from_sam(&mapped("chr1", [11, 21, 31]));
+        let mut records = mapped("chr1", [401, 411]);
+        records.extend(mapped("chr2", [5, 15]));
+        records.push(record("u0", 4, "*", 0));
+        let multi_reference = cram_from_sam(&records);
+        let unmapped = cram_from_sam(&[record("u1", 4, "*", 0), record("u2", 4, "*", 0)]);
+
+        let (head, first) = split_containers(&first).await;
+        let (_, multi_reference) = split_containers(&multi_reference).await;
+        let (_, unmapped) = split_containers(&unmapped).await;
+        let eof = &unmapped[1];
+        assert!(eof.0.is_eof());
+
+        // One container per slice context, then one of two copies of a slice.
+        let cram = [
+            head.to_vec(),
+            with_slices(&first[0], 1),
+            with_slices(&multi_reference[0], 1),
+            with_slices(&unmapped[0], 1),
+            with_slices(&first[0], 2),
+            container_bytes(eof),
+        ]
+        .concat();
+        let index = index_as_noodles_does(&cram).await;
+
+        let landmark = |(_, blocks): &(ContainerHeader, &[u8])| block_length(blocks) as u64;
+        let first_landmark = landmark(&first[0]);
+        let second_landmark = first[0].0.length as u64;
+        let multi_reference_landmark = landmark(&multi_reference[0]);
+        let unmapped_landmark = landmark(&unmapped[0]);
+        assert_eq!(
+            entries(&index),
+            [
+                (Some(0), Some(11), 28, first_landmark),
+                (None, None, 0, multi_reference_landmark),
+                (Some(0), Some(401), 18, multi_reference_landmark),
+                (Some(1), Some(5), 18, multi_reference_landmark),
+                (None, None, 0, unmapped_landmark),
+                (Some(0), Some(11), 28, first_landmark),
+                (Some(0), Some(11), 28, second_landmark),
+            ]
+        );
+        // The last two slices share their container.
+        assert_eq!(index[5].offset(), index[6].offset());
+        assert!(index[5].offset() > index[4].offset());
+    }
+
+    #[tokio::test]
+    async fn a_cram_of_only_the_eof_container_has_an_empty_index() {
+        let cram = cram_from_sam(&[]);
+        let (head, containers) = split_containers(&cram).await;
+        assert_eq!(containers.len(), 1);
+        assert!(containers[0].0.is_eof());
+        assert_eq!(cram.len(), head.len() + containers[0].0.raw.len() + 15);
+
+        let index = index_as_noodles_does(&cram).await;
+        assert!(index.is_empty());
+    }
+}
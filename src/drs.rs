@@ -0,0 +1,524 @@
+//! Resolving GA4GH Data Repository Service (DRS) URIs to URLs that can be read.
+//!
+//! `drs://host/object-id` is looked up at `https://host/ga4gh/drs/v1/objects/
+//! object-id`, and the object read from its first HTTPS access method, or else
+//! its first S3 one.
+
+use reqwest::{
+    header::{HeaderName, HeaderValue, ACCEPT, AUTHORIZATION, LOCATION, RANGE},
+    redirect::Policy,
+    StatusCode,
+};
+use serde::Deserialize;
+
+use crate::{
+    auth::{redact_url, HttpAuth},
+    Error, Result, StoreOptions,
+};
+
+/// Redirects followed before giving up, as many as reqwest follows.
+const MAX_REDIRECTS: usize = 10;
+
+/// Access method types that can be read, in order of preference.
+const ACCESS_TYPES: [&str; 2] = ["https", "s3"];
+
+/// The parts of a DRS object used here.
+#[derive(Deserialize)]
+struct DrsObject {
+    #[serde(default)]
+    access_methods: Vec<AccessMethod>,
+    #[serde(default)]
+    contents: Vec<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct AccessMethod {
+    #[serde(rename = "type")]
+    access_type: String,
+    access_url: Option<AccessUrl>,
+    access_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AccessUrl {
+    url: String,
+    /// `Name: value` headers to fetch `url` with.
+    #[serde(default)]
+    headers: Vec<String>,
+}
+
+/// The URL `uri` is read from, and the options to read it with. URLs other
+/// than `drs://` ones are returned as they are, with `options.http_auth`
+/// scoped to their host.
+///
+/// The DRS server is sent `options.http_auth`. The access URL is sent the
+/// headers the server lists for it instead, plus any netrc login for its
+/// host: the server's credentials are no use elsewhere, and a signed URL
+/// can't be sent a second set.
+///
+/// An HTTP(S) URL sent headers other than `Authorization` is read from where
+/// it redirects to, if that's another host, so they aren't sent there.
+pub async fn resolve(uri: &url::Url, options: &StoreOptions) -> Result<(url::Url, StoreOptions)> {
+    let mut read_options = options.clone();
+    let url = match uri.scheme() {
+        "drs" => {
+            let access_url = resolve_access_url(uri, options)
+                .await
+                .map_err(|e| Error::Drs {
+                    uri: uri.clone(),
+                    source: Box::new(e),
+                })?;
+            read_options.http_auth = access_url.auth;
+            access_url.url
+        }
+        _ => {
+            read_options.http_auth = options.http_auth.scoped_to(uri);
+            uri.clone()
+        }
+    };
+
+    let has_headers = read_options
+        .http_auth
+        .headers_for(&url)?
+        .keys()
+        .any(|name| name != AUTHORIZATION);
+    let url = match matches!(url.scheme(), "http" | "https") && has_headers {
+        true => {
+            let client = client()?;
+            let range = (RANGE, HeaderValue::from_static("bytes=0-0"));
+            let (last, _) = get(&client, &url, range, &read_options).await?;
+            match last.host_str() == url.host_str() {
+                true => url,
+                false => last,
+            }
+        }
+        false => url,
+    };
+    if url != *uri {
+        log::info!("Reading {} from {}", redact_url(uri), redact_url(&url));
+    }
+    Ok((url, read_options))
+}
+
+/// An access URL, and the credentials to read it with.
+struct Access {
+    url: url::Url,
+    auth: HttpAuth,
+}
+
+async fn resolve_access_url(uri: &url::Url, options: &StoreOptions) -> Result<Access> {
+    let host = uri
+        .host_str()
+        .filter(|host| !host.is_empty())
+        .ok_or_else(|| {
+            Error::InvalidLocation(
+                "DRS URI has no host; compact identifiers aren't supported".into(),
+            )
+        })?;
+    let id = uri.path().trim_start_matches('/');
+    if id.is_empty() {
+        return Err(Error::InvalidLocation("DRS URI has no object ID".into()));
+    }
+    let base = match &options.drs_endpoint {
+        Some(endpoint) => endpoint.trim_end_matches('/').to_string(),
+        None => match uri.port() {
+            Some(port) => format!("https://{}:{}", host, port),
+            None => format!("https://{}", host),
+        },
+    };
+    let objects = format!("{}/ga4gh/drs/v1/objects/{}", base, id);
+
+    let objects = url::Url::parse(&objects)?;
+    let mut server_options = options.clone();
+    server_options.http_auth = options.http_auth.scoped_to(&objects);
+    let client = client()?;
+    let object: DrsObject = get_json(&client, &objects, &server_options).await?;
+    let Some(method) = ACCESS_TYPES.iter().find_map(|access_type| {
+        object
+            .access_methods
+            .iter()
+            .find(|method| method.access_type == *access_type)
+    }) else {
+        let problem = match object.contents.is_empty() {
+            true => {
+                let types: Vec<_> = object
+                    .access_methods
+                    .iter()
+                    .map(|method| method.access_type.as_str())
+                    .collect();
+                format!("has no HTTPS or S3 access method, only {:?}", types)
+            }
+            false => "is a bundle, not a single object".into(),
+        };
+        return Err(Error::InvalidLocation(format!(
+            "The DRS object {}",
+            problem
+        )));
+    };
+    let access_url = match (&method.access_url, &method.access_id) {
+        (Some(access_url), _) => AccessUrl {
+            url: access_url.url.clone(),
+            headers: access_url.headers.clone(),
+        },
+        (None, Some(access_id)) => {
+            let access = url::Url::parse(&format!("{}/access/{}", objects, access_id))?;
+            get_json(&client, &access, &server_options).await?
+        }
+        (None, None) => {
+            return Err(Error::InvalidLocation(format!(
+                "The DRS object's {} access method has neither an access_url nor an access_id",
+                method.access_type
+            )))
+        }
+    };
+
+    let url = url::Url::parse(&access_url.url)?;
+    let headers = access_url
+        .headers
+        .iter()
+        .map(|header| {
+            let (name, value) = header.split_once(':').ok_or_else(|| {
+                Error::InvalidCredentials(
+                    "The DRS server lists an access URL header that isn't `Name: value`".into(),
+                )
+            })?;
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        })
+        .collect::<Result<_>>()?;
+    let auth = HttpAuth {
+        headers,
+        netrc: options.http_auth.netrc.clone(),
+        ..Default::default()
+    }
+    .scoped_to(&url);
+    Ok(Access { url, auth })
+}
+
+/// A client that leaves redirects to [`get`].
+fn client() -> Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .redirect(Policy::none())
+        .build()
+        .map_err(http_error)
+}
+
+/// GETs `url` as JSON.
+async fn get_json<T: serde::de::DeserializeOwned>(
+    client: &reqwest::Client,
+    url: &url::Url,
+    options: &StoreOptions,
+) -> Result<T> {
+    let accept = (ACCEPT, HeaderValue::from_static("application/json"));
+    let (_, response) = get(client, url, accept, options).await?;
+    let bytes = response.bytes().await.map_err(http_error)?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+/// GETs `url` with `header`, retrying connection errors and server errors as
+/// `options.retry` allows. Redirects are followed here, sending each URL
+/// only the credentials for its host: reqwest would send other hosts every
+/// header but `Authorization`. Returns the last URL and its response.
+async fn get(
+    client: &reqwest::Client,
+    url: &url::Url,
+    (name, value): (HeaderName, HeaderValue),
+    options: &StoreOptions,
+) -> Result<(url::Url, reqwest::Response)> {
+    let mut url = url.clone();
+    let mut redirects = 0;
+    let mut retries = 0;
+    loop {
+        let response = client
+            .get(url.clone())
+            .headers(options.http_auth.headers_for(&url)?)
+            .header(name.clone(), value.clone())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match response {
+            Ok(response) if response.status().is_redirection() => {
+                let location = response
+                    .headers()
+                    .get(LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .and_then(|location| url.join(location).ok());
+                match location {
+                    Some(location) if redirects < MAX_REDIRECTS => {
+                        redirects += 1;
+                        url = location;
+                    }
+                    Some(_) => {
+                        return Err(Error::Http(
+                            format!("Too many redirects from {}", redact_url(&url)).into(),
+                        ))
+                    }
+                    None => return Ok((url, response)),
+                }
+            }
+            Ok(response) => return Ok((url, response)),
+            Err(e) if retries < options.retry.max_retries && is_transient(&e) => {
+                let backoff = options.retry.backoff(retries);
+                retries += 1;
+                log::warn!("Retrying {} in {:?}: {}", redact_url(&url), backoff, e);
+                tokio::time::sleep(backoff).await;
+            }
+            Err(e) => return Err(http_error(e)),
+        }
+    }
+}
+
+fn http_error(e: reqwest::Error) -> Error {
+    Error::Http(Box::new(e))
+}
+
+fn is_transient(e: &reqwest::Error) -> bool {
+    match e.status() {
+        Some(status) => status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+        None => e.is_connect() || e.is_timeout() || e.is_request(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        convert::Infallible,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use hyper::{
+        header::LOCATION,
+        service::{make_service_fn, service_fn},
+        Body, HeaderMap, Request, Response, Server,
+    };
+
+    use super::*;
+    use crate::resume::RetryOptions;
+
+    const OBJECT: &str = "/ga4gh/drs/v1/objects/abc";
+
+    /// A DRS server answering each path with its queued responses in turn,
+    /// then 404s. Redirects are sent with their body as the `Location`.
+    #[derive(Default)]
+    struct Mock {
+        responses: Mutex<HashMap<String, Vec<(u16, String)>>>,
+        /// Each request's path and headers.
+        requests: Mutex<Vec<(String, HeaderMap)>>,
+    }
+
+    impl Mock {
+        fn respond(&self, path: &str, status: u16, body: &str) {
+            let mut responses = self.responses.lock().unwrap();
+            let queue = responses.entry(path.to_string()).or_default();
+            queue.push((status, body.to_string()));
+        }
+
+        /// Each request's path and `name` header.
+        fn sent(&self, name: &str) -> Vec<(String, Option<String>)> {
+            let requests = self.requests.lock().unwrap();
+            let header = |headers: &HeaderMap| {
+                let value = headers.get(name)?;
+                Some(value.to_str().unwrap().to_string())
+            };
+            requests
+                .iter()
+                .map(|(path, headers)| (path.clone(), header(headers)))
+                .collect()
+        }
+
+        fn answer(&self, request: &Request<Body>) -> Response<Body> {
+            let path = request.uri().path().to_string();
+            self.requests
+                .lock()
+                .unwrap()
+                .push((path.clone(), request.headers().clone()));
+            let (status, body) = match self.responses.lock().unwrap().get_mut(&path) {
+                Some(queue) if !queue.is_empty() => queue.remove(0),
+                _ => (404, String::new()),
+            };
+            let response = Response::builder().status(status);
+            match (300..400).contains(&status) {
+                true => response.header(LOCATION, body).body(Body::empty()),
+                false => response.body(Body::from(body)),
+            }
+            .unwrap()
+        }
+    }
+
+    /// Serves `mock` on a local port, and returns options that resolve DRS
+    /// URIs with it.
+    fn serve(mock: Arc<Mock>) -> StoreOptions {
+        let make_service = make_service_fn(move |_| {
+            let mock = mock.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let response = mock.answer(&request);
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+        StoreOptions {
+            drs_endpoint: Some(format!("http://{}", address)),
+            http_auth: HttpAuth {
+                bearer_token: Some("drs-token".into()),
+                ..Default::default()
+            },
+            retry: RetryOptions {
+                max_retries: 2,
+                initial_backoff: Duration::ZERO,
+                max_backoff: Duration::ZERO,
+            },
+            ..Default::default()
+        }
+    }
+
+    fn uri() -> url::Url {
+        url::Url::parse("drs://drs.example/abc").unwrap()
+    }
+
+    /// `path` on the mock, by its own address or, as another host, by name.
+    fn mock_url(options: &StoreOptions, host: &str, path: &str) -> url::Url {
+        let mut url = url::Url::parse(options.drs_endpoint.as_ref().unwrap()).unwrap();
+        url.set_host(Some(host)).unwrap();
+        url.join(path).unwrap()
+    }
+
+    #[tokio::test]
+    async fn access_urls_are_read_with_their_own_headers() {
+        let mock = Arc::new(Mock::default());
+        let options = serve(mock.clone());
+        let access_url = mock_url(&options, "localhost", "/x.bam?sig=1");
+        mock.respond(OBJECT, 503, "");
+        mock.respond(
+            OBJECT,
+            200,
+            &serde_json::json!({"access_methods": [
+                {"type": "gs", "access_url": {"url": "gs://bucket/x.bam"}},
+                {"type": "https", "access_url": {
+                    "url": access_url.as_str(),
+                    "headers": ["X-Signed: yes"]
+                }}
+            ]})
+            .to_string(),
+        );
+        mock.respond("/x.bam", 206, "B");
+
+        let (url, read_options) = resolve(&uri(), &options).await.unwrap();
+        assert_eq!(url, access_url);
+        let auth = &read_options.http_auth;
+        assert_eq!(auth.headers, [("X-Signed".into(), "yes".into())]);
+        assert!(auth.bearer_token.is_none());
+        assert_eq!(auth.host.as_deref(), Some("localhost"));
+
+        // Retried after the 503, with the DRS server's credentials both times,
+        // then checked for redirects with the access URL's.
+        let drs = (OBJECT.to_string(), Some("Bearer drs-token".to_string()));
+        let access = ("/x.bam".to_string(), None);
+        assert_eq!(mock.sent("authorization"), [drs.clone(), drs, access]);
+        let signed = ("/x.bam".to_string(), Some("yes".to_string()));
+        assert_eq!(mock.sent("x-signed")[2], signed);
+    }
+
+    #[tokio::test]
+    async fn credentials_are_not_sent_where_the_input_redirects() {
+        let mock = Arc::new(Mock::default());
+        let mut options = serve(mock.clone());
+        options.http_auth.headers = vec![("X-Api-Key".into(), "key".into())];
+        let input = mock_url(&options, "127.0.0.1", "/a.bam");
+        let elsewhere = mock_url(&options, "localhost", "/b.bam");
+        mock.respond("/a.bam", 302, "/a2.bam");
+        mock.respond("/a2.bam", 307, elsewhere.as_str());
+        mock.respond("/b.bam", 206, "B");
+
+        let (url, read_options) = resolve(&input, &options).await.unwrap();
+        assert_eq!(url, elsewhere);
+        let headers = read_options.http_auth.headers_for(&url).unwrap();
+        assert!(headers.is_empty(), "{:?}", headers);
+        assert!(!read_options
+            .http_auth
+            .headers_for(&input)
+            .unwrap()
+            .is_empty());
+
+        // Redirects within the host keep the credentials; others drop them.
+        let sent = |value: &str| {
+            let value = Some(value.to_string());
+            vec![
+                ("/a.bam".to_string(), value.clone()),
+                ("/a2.bam".to_string(), value),
+                ("/b.bam".to_string(), None),
+            ]
+        };
+        assert_eq!(mock.sent("authorization"), sent("Bearer drs-token"));
+        assert_eq!(mock.sent("x-api-key"), sent("key"));
+    }
+
+    #[tokio::test]
+    async fn drs_servers_redirecting_elsewhere_are_sent_no_credentials() {
+        let mock = Arc::new(Mock::default());
+        let options = serve(mock.clone());
+        let moved = mock_url(&options, "localhost", "/moved");
+        mock.respond(OBJECT, 301, moved.as_str());
+        mock.respond(
+            "/moved",
+            200,
+            r#"{"access_methods": [{"type": "s3", "access_url": {"url": "s3://bucket/x.bam"}}]}"#,
+        );
+
+        let (url, _) = resolve(&uri(), &options).await.unwrap();
+        assert_eq!(url.as_str(), "s3://bucket/x.bam");
+        assert_eq!(
+            mock.sent("authorization"),
+            [
+                (OBJECT.to_string(), Some("Bearer drs-token".to_string())),
+                ("/moved".to_string(), None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn access_ids_are_looked_up() {
+        let mock = Arc::new(Mock::default());
+        mock.respond(
+            OBJECT,
+            200,
+            r#"{"access_methods": [{"type": "s3", "access_id": "s3-id"}]}"#,
+        );
+        mock.respond(
+            &format!("{}/access/s3-id", OBJECT),
+            200,
+            r#"{"url": "s3://bucket/x.bam"}"#,
+        );
+        let options = serve(mock);
+
+        let (url, _) = resolve(&uri(), &options).await.unwrap();
+        assert_eq!(url.as_str(), "s3://bucket/x.bam");
+    }
+
+    #[tokio::test]
+    async fn unreadable_objects_fail() {
+        let mock = Arc::new(Mock::default());
+        mock.respond(
+            OBJECT,
+            200,
+            r#"{"access_methods": [], "contents": [{"name": "x.bam"}]}"#,
+        );
+        let options = serve(mock.clone());
+
+        let Err(Error::Drs { source, .. }) = resolve(&uri(), &options).await else {
+            panic!("expected a DRS error")
+        };
+        assert!(source.to_string().contains("is a bundle"), "{}", source);
+
+        // Not found, and not retried.
+        let Err(Error::Drs { source, .. }) = resolve(&uri(), &options).await else {
+            panic!("expected a DRS error")
+        };
+        assert!(matches!(*source, Error::Http(_)), "{:?}", source);
+        assert_eq!(mock.requests.lock().unwrap().len(), 2);
+    }
+}
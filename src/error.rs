@@ -0,0 +1,147 @@
+use std::io;
+
+use crate::{BAI_DEPTH, BAI_MIN_SHIFT, CSI_MAX_DEPTH, CSI_MAX_SHIFT};
+
+/// Why indexing failed.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// Reading the input or writing an output failed.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// An object store request failed.
+    #[error(transparent)]
+    ObjectStore(#[from] object_store::Error),
+    /// A location isn't a URL or path an object store can be opened for.
+    #[error("{0}")]
+    InvalidLocation(String),
+    /// A location isn't a valid URL.
+    #[error(transparent)]
+    InvalidUrl(#[from] url::ParseError),
+    /// A URL's path isn't a valid object store path.
+    #[error(transparent)]
+    InvalidPath(#[from] object_store::path::Error),
+    /// An HTTP request other than an object store's failed. The source is
+    /// opaque so the HTTP client isn't part of this API.
+    #[error(transparent)]
+    Http(Box<dyn std::error::Error + Send + Sync>),
+    /// A `drs://` URI couldn't be resolved to an access URL.
+    #[error("Failed to resolve {uri}")]
+    Drs {
+        /// The DRS URI.
+        uri: url::Url,
+        /// Why it couldn't be resolved.
+        #[source]
+        source: Box<Error>,
+    },
+    /// A header, login or netrc file is malformed. The message never includes
+    /// the secret itself.
+    #[error("{0}")]
+    InvalidCredentials(String),
+    /// The BAM header can't be parsed.
+    #[error("Invalid BAM header: {0}")]
+    InvalidHeader(#[from] noodles::sam::header::ParseError),
+    /// The header doesn't declare `SO:coordinate`, or `SO` at all, and
+    /// [`IndexOptions::allow_unsorted_header`](crate::IndexOptions) isn't set.
+    #[error(
+        "BAM header {}, and allow_unsorted_header isn't set",
+        describe_sort_order(.0)
+    )]
+    UndeclaredSortOrder(Option<String>),
+    /// The header declares another sort order, e.g. `SO:queryname`.
+    #[error("BAM file is not coordinate sorted (SO:{0})")]
+    NotCoordinateSorted(String),
+    /// A record comes after one it should precede.
+    #[error(
+        "BAM file is not coordinate sorted: {name} at {position} comes after a record at {previous}"
+    )]
+    Unsorted {
+        /// The out of order record's name.
+        name: String,
+        /// Its position, as `name:start`.
+        position: String,
+        /// The position of the record before it.
+        previous: String,
+    },
+    /// BAI can't address a reference sequence this long, and
+    /// [`IndexOptions::csi_fallback`](crate::IndexOptions) isn't set.
+    #[error(
+        "BAI cannot index reference sequences longer than {max} bp (longest is {length} bp); use CSI instead"
+    )]
+    ReferenceSequenceTooLong {
+        /// The longest reference sequence, in bp.
+        length: usize,
+        /// The longest BAI can address.
+        max: usize,
+    },
+    /// BAI was asked for with other binning than its fixed one.
+    #[error("BAI requires min_shift={} and depth={}", BAI_MIN_SHIFT, BAI_DEPTH)]
+    BaiBinning,
+    /// The CSI depth is too small for the longest reference sequence.
+    #[error(
+        "CSI depth {depth} is too small for a {length} bp reference sequence at min_shift {min_shift} (need at least {min_depth})"
+    )]
+    DepthTooSmall {
+        /// The depth asked for.
+        depth: u8,
+        /// The longest reference sequence, in bp.
+        length: usize,
+        /// The `min_shift` asked for.
+        min_shift: u8,
+        /// The smallest depth that would do.
+        min_depth: u8,
+    },
+    /// The CSI binning is deeper than bin IDs allow, or addresses positions
+    /// htslib can't read.
+    #[error(
+        "CSI min_shift={min_shift} and depth={depth} are out of range: depth can be at most {}, and min_shift + 3 * depth at most {}",
+        CSI_MAX_DEPTH,
+        CSI_MAX_SHIFT
+    )]
+    InvalidBinning {
+        /// The `min_shift` asked for.
+        min_shift: u8,
+        /// The depth asked for, or derived.
+        depth: u8,
+    },
+    /// A record can't be decoded.
+    #[error("{0}")]
+    InvalidRecord(&'static str),
+    /// The BAM ended inside the BGZF block at this compressed offset.
+    #[error("BAM ended inside the block at {0}")]
+    Truncated(u64),
+    /// A checkpoint couldn't be read.
+    #[error("Failed to read checkpoint {location}")]
+    Checkpoint {
+        /// Where the checkpoint is.
+        location: url::Url,
+        /// Why it couldn't be read.
+        #[source]
+        source: Box<Error>,
+    },
+    /// A compressed offset or block size is out of range for a virtual position.
+    #[error(transparent)]
+    InvalidVirtualPosition(#[from] noodles::bgzf::virtual_position::TryFromU64U16TupleError),
+    /// A tabix index has no header to say which columns it indexes.
+    #[error("Tabix index has no header")]
+    MissingTabixHeader,
+    /// A value is too large for the format it's written in.
+    #[error(transparent)]
+    Overflow(#[from] std::num::TryFromIntError),
+    /// A summary couldn't be encoded as JSON.
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    /// A task indexing part of a BAM panicked or was cancelled.
+    #[error(transparent)]
+    Join(#[from] tokio::task::JoinError),
+}
+
+fn describe_sort_order(sort_order: &Option<String>) -> String {
+    match sort_order {
+        Some(sort_order) => format!("declares SO:{}", sort_order),
+        None => "has no SO tag".into(),
+    }
+}
+
+/// A `Result` with this crate's [`Error`].
+pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -0,0 +1,489 @@
+use anyhow::{Context, Result};
+use noodles::{
+    bgzf::{self, gzi},
+    fasta::fai,
+};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+
+use stream_index::{bgzf_block_size, get_async_stream_reader, put_bytes};
+
+use crate::{check_existing, cli, index_location, Destination};
+
+/// Builds `.fai` records line by line, as `samtools faidx` does.
+#[derive(Default)]
+struct FaiIndexer {
+    index: fai::Index,
+    /// Uncompressed bytes seen so far.
+    offset: u64,
+    /// The line being read, up to the end of the current buffer.
+    line: Vec<u8>,
+    line_number: u64,
+    record: Option<PendingRecord>,
+}
+
+/// The sequence currently being read.
+struct PendingRecord {
+    name: String,
+    offset: u64,
+    length: u64,
+    line_bases: u64,
+    line_width: u64,
+    /// Set after a line shorter than the first; only the last line may be.
+    ended: bool,
+}
+
+impl PendingRecord {
+    fn finish(self) -> Result<fai::Record> {
+        if self.length == 0 {
+            anyhow::bail!("{} has an empty sequence", self.name);
+        }
+        Ok(fai::Record::new(
+            self.name,
+            self.length,
+            self.offset,
+            self.line_bases,
+            self.line_width,
+        ))
+    }
+}
+
+impl FaiIndexer {
+    fn push(&mut self, mut buf: &[u8]) -> Result<()> {
+        while let Some(i) = buf.iter().position(|&b| b == b'\n') {
+            let (line, rest) = buf.split_at(i + 1);
+            if self.line.is_empty() {
+                self.push_line(line)?;
+            } else {
+                let mut full = std::mem::take(&mut self.line);
+                full.extend_from_slice(line);
+                self.push_line(&full)?;
+                full.clear();
+                self.line = full;
+            }
+            buf = rest;
+        }
+        self.line.extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn push_line(&mut self, line: &[u8]) -> Result<()> {
+        self.line_number += 1;
+        let width = line.len() as u64;
+        let content = line.strip_suffix(b"\n").unwrap_or(line);
+        let content = content.strip_suffix(b"\r").unwrap_or(content);
+        self.offset += width;
+
+        if let Some(definition) = content.strip_prefix(b">") {
+            if let Some(record) = self.record.take() {
+                self.index.push(record.finish()?);
+            }
+            let name = definition
+                .split(u8::is_ascii_whitespace)
+                .next()
+                .filter(|name| !name.is_empty())
+                .with_context(|| format!("Line {}: missing sequence name", self.line_number))?;
+            self.record = Some(PendingRecord {
+                name: String::from_utf8(name.to_vec())
+                    .with_context(|| format!("Line {}: invalid sequence name", self.line_number))?,
+                offset: self.offset,
+                length: 0,
+                line_bases: 0,
+                line_width: 0,
+                ended: false,
+            });
+            return Ok(());
+        }
+
+        let bases = content.len() as u64;
+        let Some(record) = &mut self.record else {
+            if bases == 0 {
+                return Ok(());
+            }
+            anyhow::bail!(
+                "Line {}: sequence before the first `>` line",
+                self.line_number
+            );
+        };
+        if bases == 0 {
+            record.ended = true;
+            return Ok(());
+        }
+        if record.line_width == 0 {
+            record.line_bases = bases;
+            record.line_width = width;
+        } else if record.ended || bases > record.line_bases {
+            anyhow::bail!(
+                "Line {}: {} has lines of different lengths; only the last may be shorter",
+                self.line_number,
+                record.name
+            );
+        }
+        if bases < record.line_bases || width != record.line_width {
+            record.ended = true;
+        }
+        record.length += bases;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<fai::Index> {
+        if !self.line.is_empty() {
+            let line = std::mem::take(&mut self.line);
+            self.push_line(&line)?;
+        }
+        if let Some(record) = self.record.take() {
+            self.index.push(record.finish()?);
+        }
+        Ok(self.index)
+    }
+}
+
+async fn fill_indexer<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    indexer: &mut FaiIndexer,
+) -> Result<bool> {
+    let buf = reader.fill_buf().await?;
+    if buf.is_empty() {
+        return Ok(false);
+    }
+    let len = buf.len();
+    indexer.push(buf)?;
+    reader.consume(len);
+    Ok(true)
+}
+
+/// The length of a BGZF block header, enough to tell BGZF from plain text.
+const HEAD_LENGTH: usize = 18;
+
+/// Reads the first [`HEAD_LENGTH`] bytes of `reader`, or all of them if there
+/// are fewer, however the reads come back.
+async fn read_head<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut head = Vec::with_capacity(HEAD_LENGTH);
+    (&mut *reader)
+        .take(HEAD_LENGTH as u64)
+        .read_to_end(&mut head)
+        .await?;
+    Ok(head)
+}
+
+/// Whether a FASTA starting with `head` is BGZF-compressed.
+fn is_bgzf(head: &[u8]) -> Result<bool> {
+    if bgzf_block_size(head).is_some() {
+        return Ok(true);
+    }
+    if head.starts_with(&[0x1f, 0x8b]) {
+        anyhow::bail!("The FASTA is gzipped but not BGZF; recompress it with `bgzip`");
+    }
+    Ok(false)
+}
+
+/// Builds a `.fai` for a plain or BGZF-compressed FASTA stream, plus a `.gzi`
+/// for the latter. `head` is what [`read_head`] read from the start of it.
+async fn build_fasta_index<R: AsyncRead + Unpin>(
+    head: Vec<u8>,
+    reader: R,
+) -> Result<(fai::Index, Option<gzi::Index>)> {
+    let bgzf = is_bgzf(&head)?;
+    let mut reader = BufReader::new(std::io::Cursor::new(head).chain(reader));
+    let mut indexer = FaiIndexer::default();
+    if !bgzf {
+        while fill_indexer(&mut reader, &mut indexer).await? {}
+        return Ok((indexer.finish()?, None));
+    }
+
+    // One entry per block but the first, mapping its compressed offset to the
+    // uncompressed offset it starts at.
+    let mut reader = bgzf::AsyncReader::new(reader);
+    let mut gzi = Vec::new();
+    loop {
+        let uncompressed_offset = indexer.offset + indexer.line.len() as u64;
+        let buf = reader.fill_buf().await?;
+        if buf.is_empty() {
+            break;
+        }
+        let len = buf.len();
+        indexer.push(buf)?;
+        // The block was just read, so this points at its start.
+        let block_start = reader.virtual_position().compressed();
+        reader.consume(len);
+        if uncompressed_offset > 0 {
+            gzi.push((block_start, uncompressed_offset));
+        }
+    }
+    Ok((indexer.finish()?, Some(gzi)))
+}
+
+fn encode_fai(index: &fai::Index) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    fai::Writer::new(&mut buf).write_index(index)?;
+    Ok(buf)
+}
+
+/// A `.gzi`: the entry count, then each pair, as little-endian u64s.
+fn encode_gzi(index: &gzi::Index) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(8 + 16 * index.len());
+    buf.extend_from_slice(&(index.len() as u64).to_le_bytes());
+    for &(compressed, uncompressed) in index {
+        buf.extend_from_slice(&compressed.to_le_bytes());
+        buf.extend_from_slice(&uncompressed.to_le_bytes());
+    }
+    buf
+}
+
+pub async fn run_faidx(args: cli::FaidxArgs) -> Result<()> {
+    let store_options = args.store.store_options()?;
+    let single_output = matches!(
+        args.output,
+        Some(Destination::File(_) | Destination::Stdout)
+    );
+    // The content, not the name, says whether there's a `.gzi` to write too,
+    // so the locations to check can be known before the rest is read.
+    let mut reader = get_async_stream_reader(&args.input, &store_options).await?;
+    let head = read_head(&mut reader).await?;
+    let extensions: &[&str] = if is_bgzf(&head)? {
+        &["fai", "gzi"]
+    } else {
+        &["fai"]
+    };
+    if extensions.len() > 1 && single_output {
+        anyhow::bail!("--output must be a directory when writing both a .fai and a .gzi");
+    }
+    let locations = extensions
+        .iter()
+        .map(|extension| index_location(args.output.as_ref(), &args.input, extension))
+        .collect::<Result<Vec<_>>>()?;
+    if check_existing(&locations, args.if_exists, &store_options)
+        .await?
+        .is_some()
+    {
+        return Ok(());
+    }
+
+    let (fai, gzi) = build_fasta_index(head, reader).await?;
+    let indexes = [Some(encode_fai(&fai)?), gzi.as_ref().map(encode_gzi)];
+    for (location, index) in locations.iter().zip(indexes.iter().flatten()) {
+        put_bytes(location.as_ref(), index, &store_options).await?;
+        if let Some(url) = location {
+            log::info!("Wrote index to {}", url);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use bytes::Bytes;
+    use clap::Parser;
+    use tokio_util::io::StreamReader;
+
+    use super::*;
+
+    /// Feeds `data` to the indexer `chunk_size` bytes at a time.
+    fn index(data: &[u8], chunk_size: usize) -> Result<fai::Index> {
+        let mut indexer = FaiIndexer::default();
+        for chunk in data.chunks(chunk_size) {
+            indexer.push(chunk)?;
+        }
+        indexer.finish()
+    }
+
+    /// A reader whose reads return `chunk_size` bytes at most.
+    fn chunked(data: &[u8], chunk_size: usize) -> impl AsyncRead + Unpin {
+        let chunks: Vec<_> = data
+            .chunks(chunk_size)
+            .map(|chunk| Ok::<_, std::io::Error>(Bytes::copy_from_slice(chunk)))
+            .collect();
+        StreamReader::new(futures::stream::iter(chunks))
+    }
+
+    async fn build(data: &[u8], chunk_size: usize) -> Result<(fai::Index, Option<gzi::Index>)> {
+        let mut reader = chunked(data, chunk_size);
+        let head = read_head(&mut reader).await?;
+        build_fasta_index(head, reader).await
+    }
+
+    fn record(
+        name: &str,
+        length: u64,
+        offset: u64,
+        line_bases: u64,
+        line_width: u64,
+    ) -> fai::Record {
+        fai::Record::new(name.to_string(), length, offset, line_bases, line_width)
+    }
+
+    /// What noodles' indexer makes of `fasta`.
+    fn noodles_index(fasta: &[u8]) -> fai::Index {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), fasta).unwrap();
+        noodles::fasta::index(file.path()).unwrap()
+    }
+
+    #[test]
+    fn records_are_indexed_as_noodles_does() {
+        for (fasta, expected) in [
+            // Wrapped, with a short last line.
+            (
+                &b">a desc\nACGT\nACGT\nAC\n>b\nACG\n"[..],
+                vec![record("a", 10, 8, 4, 5), record("b", 3, 24, 3, 4)],
+            ),
+            // Unwrapped, without a final newline.
+            (
+                b">a\nACGTACGTAC\n>b\nACGTA",
+                vec![record("a", 10, 3, 10, 11), record("b", 5, 17, 5, 5)],
+            ),
+            // CRLF line endings.
+            (
+                b">a\r\nACGT\r\nAC\r\n>b\r\nACGT\r\n",
+                vec![record("a", 6, 4, 4, 6), record("b", 4, 18, 4, 6)],
+            ),
+            // Lines as long as the first, then a blank line.
+            (
+                b">a\nACGT\nACGT\n\n>b\nA\n",
+                vec![record("a", 8, 3, 4, 5), record("b", 1, 17, 1, 2)],
+            ),
+        ] {
+            assert_eq!(noodles_index(fasta), expected);
+            for chunk_size in [1, 3, fasta.len()] {
+                let index = index(fasta, chunk_size).unwrap();
+                assert_eq!(index, expected, "{:?}", String::from_utf8_lossy(fasta));
+            }
+        }
+    }
+
+    #[test]
+    fn ragged_lines_are_an_error() {
+        for (fasta, message) in [
+            (
+                &b">a\nACGT\nAC\nACGT\n"[..],
+                "Line 4: a has lines of different lengths",
+            ),
+            (
+                b">a\nACGT\nACGTA\n",
+                "Line 3: a has lines of different lengths",
+            ),
+            (
+                b">a\nACGT\n\nACGT\n",
+                "Line 4: a has lines of different lengths",
+            ),
+            (
+                b"ACGT\n>a\nACGT\n",
+                "Line 1: sequence before the first `>` line",
+            ),
+            (b">\nACGT\n", "Line 1: missing sequence name"),
+            (b">a\n>b\nACGT\n", "a has an empty sequence"),
+        ] {
+            let e = index(fasta, 2).unwrap_err().to_string();
+            assert!(e.starts_with(message), "{}", e);
+        }
+    }
+
+    #[tokio::test]
+    async fn short_reads_are_sniffed_whole() {
+        let fasta = b">a\nACGT\n";
+        let (fai, gzi) = build(fasta, 1).await.unwrap();
+        assert_eq!(fai, vec![record("a", 4, 3, 4, 5)]);
+        assert!(gzi.is_none());
+
+        // A gzip header with no FEXTRA, read a byte at a time.
+        let gzipped = [
+            0x1f, 0x8b, 0x08, 0x00, 0, 0, 0, 0, 0, 0xff, 0x03, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let e = build(&gzipped, 1).await.unwrap_err();
+        assert!(e.to_string().contains("gzipped but not BGZF"), "{}", e);
+
+        // Too short to be BGZF, so read as plain text.
+        let (fai, gzi) = build(b">a\nA", 1).await.unwrap();
+        assert_eq!(fai, vec![record("a", 1, 3, 1, 1)]);
+        assert!(gzi.is_none());
+    }
+
+    /// A FASTA of a few sequences, long enough for several BGZF blocks.
+    fn long_fasta() -> Vec<u8> {
+        let mut fasta = Vec::new();
+        for (i, length) in [150_000, 90_000, 10].into_iter().enumerate() {
+            writeln!(fasta, ">seq{}", i).unwrap();
+            let sequence: Vec<u8> = (0..length).map(|j| b"ACGT"[(i + j) % 4]).collect();
+            for line in sequence.chunks(60) {
+                fasta.extend_from_slice(line);
+                fasta.push(b'\n');
+            }
+        }
+        fasta
+    }
+
+    #[tokio::test]
+    async fn gzi_round_trips_through_noodles() {
+        let fasta = long_fasta();
+        let mut writer = bgzf::Writer::new(Vec::new());
+        writer.write_all(&fasta).unwrap();
+        let compressed = writer.finish().unwrap();
+
+        let (fai, gzi) = build(&compressed, 1000).await.unwrap();
+        assert_eq!(fai, build(&fasta, 1000).await.unwrap().0);
+        let gzi = gzi.unwrap();
+        assert!(gzi.len() > 2, "{} blocks", gzi.len() + 1);
+
+        let encoded = encode_gzi(&gzi);
+        let decoded = gzi::Reader::new(&encoded[..]).read_index().unwrap();
+        // noodles adds the first block.
+        assert_eq!(decoded[0], (0, 0));
+        assert_eq!(decoded[1..], gzi);
+
+        // Each sequence, and each block start, is read from where noodles
+        // seeks to with the index.
+        let starts = fai.iter().map(|record| record.offset());
+        let block_starts = gzi.iter().map(|&(_, uncompressed)| uncompressed);
+        let mut reader = bgzf::Reader::new(std::io::Cursor::new(&compressed));
+        for start in starts.chain(block_starts) {
+            reader
+                .seek_by_uncompressed_position(&decoded, start)
+                .unwrap();
+            let mut buf = [0; 100];
+            let len = reader.read(&mut buf).unwrap();
+            let start = start as usize;
+            assert_eq!(buf[..len], fasta[start..start + len]);
+        }
+    }
+
+    #[tokio::test]
+    async fn existing_indexes_are_found_by_what_the_fasta_is() {
+        let dir = tempfile::tempdir().unwrap();
+        // Named as if plain, but BGZF.
+        let input = dir.path().join("ref.fa");
+        let mut writer = bgzf::Writer::new(Vec::new());
+        writer.write_all(b">a\nACGT\n").unwrap();
+        std::fs::write(&input, writer.finish().unwrap()).unwrap();
+        let output = format!("{}/", dir.path().display());
+        let run = |if_exists: &str| {
+            let argv = [
+                "stream-index",
+                "faidx",
+                input.to_str().unwrap(),
+                "-o",
+                &output,
+                "--if-exists",
+                if_exists,
+            ];
+            let cli::Command::Faidx(args) = cli::Cli::parse_from(argv).command else {
+                unreachable!()
+            };
+            run_faidx(args)
+        };
+
+        run("error").await.unwrap();
+        let fai = dir.path().join("ref.fa.fai");
+        assert_eq!(std::fs::read(&fai).unwrap(), b"a\t4\t3\t4\t5\n");
+        assert!(dir.path().join("ref.fa.gzi").is_file());
+
+        // Skipped, as both exist, without being rewritten.
+        std::fs::write(&fai, b"").unwrap();
+        run("skip").await.unwrap();
+        assert_eq!(std::fs::read(&fai).unwrap(), b"");
+        std::fs::remove_file(dir.path().join("ref.fa.gzi")).unwrap();
+        run("skip").await.unwrap();
+        assert_eq!(std::fs::read(&fai).unwrap(), b"a\t4\t3\t4\t5\n");
+        assert!(run("error").await.is_err());
+    }
+}
@@ -0,0 +1,262 @@
+//! `samtools flagstat` counts, tallied while indexing.
+
+use noodles::sam::record::{Flags, MappingQuality};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::Result;
+
+/// Written next to the index, as `<input>.flagstat.json`.
+pub const EXTENSION: &str = "flagstat.json";
+
+/// Record counts for one side of the QC split, as `samtools flagstat`
+/// tallies them.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+struct Counts {
+    total: u64,
+    primary: u64,
+    secondary: u64,
+    supplementary: u64,
+    duplicates: u64,
+    primary_duplicates: u64,
+    mapped: u64,
+    primary_mapped: u64,
+    paired: u64,
+    read1: u64,
+    read2: u64,
+    properly_paired: u64,
+    with_mate_mapped: u64,
+    singletons: u64,
+    mate_on_other_chr: u64,
+    mate_on_other_chr_mapq5: u64,
+}
+
+impl Counts {
+    fn add(&mut self, other: &Counts) {
+        self.total += other.total;
+        self.primary += other.primary;
+        self.secondary += other.secondary;
+        self.supplementary += other.supplementary;
+        self.duplicates += other.duplicates;
+        self.primary_duplicates += other.primary_duplicates;
+        self.mapped += other.mapped;
+        self.primary_mapped += other.primary_mapped;
+        self.paired += other.paired;
+        self.read1 += other.read1;
+        self.read2 += other.read2;
+        self.properly_paired += other.properly_paired;
+        self.with_mate_mapped += other.with_mate_mapped;
+        self.singletons += other.singletons;
+        self.mate_on_other_chr += other.mate_on_other_chr;
+        self.mate_on_other_chr_mapq5 += other.mate_on_other_chr_mapq5;
+    }
+
+    /// The object `samtools flagstat -O json` prints for one side.
+    fn to_json(self) -> Value {
+        // samtools rounds to two places and prints "N/A" for 0 / 0.
+        let percent = |n: u64, d: u64| match d {
+            0 => json!("N/A"),
+            _ => json!((n as f64 / d as f64 * 10000.0).round() / 100.0),
+        };
+        json!({
+            "total": self.total,
+            "primary": self.primary,
+            "secondary": self.secondary,
+            "supplementary": self.supplementary,
+            "duplicates": self.duplicates,
+            "primary duplicates": self.primary_duplicates,
+            "mapped": self.mapped,
+            "mapped %": percent(self.mapped, self.total),
+            "primary mapped": self.primary_mapped,
+            "primary mapped %": percent(self.primary_mapped, self.primary),
+            "paired in sequencing": self.paired,
+            "read1": self.read1,
+            "read2": self.read2,
+            "properly paired": self.properly_paired,
+            "properly paired %": percent(self.properly_paired, self.paired),
+            "with itself and mate mapped": self.with_mate_mapped,
+            "singletons": self.singletons,
+            "singletons %": percent(self.singletons, self.paired),
+            "with mate mapped to a different chr": self.mate_on_other_chr,
+            "with mate mapped to a different chr (mapQ >= 5)": self.mate_on_other_chr_mapq5,
+        })
+    }
+}
+
+/// `samtools flagstat` counts, split by whether records passed QC.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct FlagStat {
+    passed: Counts,
+    failed: Counts,
+}
+
+impl FlagStat {
+    /// Counts a record the way `samtools flagstat` does.
+    pub fn add_record(
+        &mut self,
+        flags: Flags,
+        reference_sequence_id: Option<usize>,
+        mate_reference_sequence_id: Option<usize>,
+        mapping_quality: Option<MappingQuality>,
+    ) {
+        let counts = if flags.is_qc_fail() {
+            &mut self.failed
+        } else {
+            &mut self.passed
+        };
+        counts.total += 1;
+        if flags.is_secondary() {
+            counts.secondary += 1;
+        } else if flags.is_supplementary() {
+            counts.supplementary += 1;
+        } else {
+            counts.primary += 1;
+            if flags.is_segmented() {
+                counts.paired += 1;
+                if flags.is_properly_aligned() && !flags.is_unmapped() {
+                    counts.properly_paired += 1;
+                }
+                if flags.is_first_segment() {
+                    counts.read1 += 1;
+                }
+                if flags.is_last_segment() {
+                    counts.read2 += 1;
+                }
+                match (flags.is_unmapped(), flags.is_mate_unmapped()) {
+                    (false, true) => counts.singletons += 1,
+                    (false, false) => {
+                        counts.with_mate_mapped += 1;
+                        if mate_reference_sequence_id != reference_sequence_id {
+                            counts.mate_on_other_chr += 1;
+                            // A missing MAPQ is stored as 255.
+                            if mapping_quality.is_none_or(|q| u8::from(q) >= 5) {
+                                counts.mate_on_other_chr_mapq5 += 1;
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            if !flags.is_unmapped() {
+                counts.primary_mapped += 1;
+            }
+            if flags.is_duplicate() {
+                counts.primary_duplicates += 1;
+            }
+        }
+        if !flags.is_unmapped() {
+            counts.mapped += 1;
+        }
+        if flags.is_duplicate() {
+            counts.duplicates += 1;
+        }
+    }
+
+    /// Adds the counts from a later part of the same file.
+    pub fn add(&mut self, other: &FlagStat) {
+        self.passed.add(&other.passed);
+        self.failed.add(&other.failed);
+    }
+
+    /// As `samtools flagstat -O json`.
+    pub fn to_json(self) -> Result<Vec<u8>> {
+        let value = json!({
+            "QC-passed reads": self.passed.to_json(),
+            "QC-failed reads": self.failed.to_json(),
+        });
+        let mut buf = serde_json::to_vec_pretty(&value)?;
+        buf.push(b'\n');
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        build_bam_index,
+        testing::{bam_from_sam, sam_header},
+    };
+
+    #[tokio::test]
+    async fn counts_match_samtools() {
+        let mut sam = sam_header(&[("chr1", 10_000), ("chr2", 10_000)]);
+        // name, flags, reference, start, MAPQ, CIGAR, mate reference, mate start
+        for (name, flags, reference, start, mapq, cigar, mate, mate_start) in [
+            // A proper pair.
+            ("p1", 99, "chr1", 100, 60, "10M", "=", 200),
+            // A pair split across chromosomes, one end with a low MAPQ.
+            ("p2", 65, "chr1", 150, 60, "10M", "chr2", 150),
+            ("p1", 147, "chr1", 200, 60, "10M", "=", 100),
+            // A singleton and its unmapped mate, placed next to it.
+            ("s1", 73, "chr1", 300, 60, "10M", "=", 300),
+            ("s1", 133, "chr1", 300, 0, "*", "=", 300),
+            ("x1", 256, "chr1", 400, 0, "10M", "*", 0),
+            ("x2", 2048, "chr1", 500, 60, "10M", "*", 0),
+            ("d1", 1024, "chr1", 600, 60, "10M", "*", 0),
+            ("q1", 512, "chr1", 700, 60, "10M", "*", 0),
+            ("p2", 129, "chr2", 150, 3, "10M", "chr1", 150),
+        ] {
+            sam.push_str(&format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t0\t*\t*\n",
+                name, flags, reference, start, mapq, cigar, mate, mate_start
+            ));
+        }
+        let bam = bam_from_sam(&sam);
+        let bam_index = build_bam_index(&mut &bam[..], &Default::default())
+            .await
+            .unwrap();
+
+        let json = bam_index.flagstat.to_json().unwrap();
+        let json: Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "QC-passed reads": {
+                    "total": 9,
+                    "primary": 7,
+                    "secondary": 1,
+                    "supplementary": 1,
+                    "duplicates": 1,
+                    "primary duplicates": 1,
+                    "mapped": 8,
+                    "mapped %": 88.89,
+                    "primary mapped": 6,
+                    "primary mapped %": 85.71,
+                    "paired in sequencing": 6,
+                    "read1": 3,
+                    "read2": 3,
+                    "properly paired": 2,
+                    "properly paired %": 33.33,
+                    "with itself and mate mapped": 4,
+                    "singletons": 1,
+                    "singletons %": 16.67,
+                    "with mate mapped to a different chr": 2,
+                    "with mate mapped to a different chr (mapQ >= 5)": 1,
+                },
+                "QC-failed reads": {
+                    "total": 1,
+                    "primary": 1,
+                    "secondary": 0,
+                    "supplementary": 0,
+                    "duplicates": 0,
+                    "primary duplicates": 0,
+                    "mapped": 1,
+                    "mapped %": 100.0,
+                    "primary mapped": 1,
+                    "primary mapped %": 100.0,
+                    "paired in sequencing": 0,
+                    "read1": 0,
+                    "read2": 0,
+                    "properly paired": 0,
+                    "properly paired %": "N/A",
+                    "with itself and mate mapped": 0,
+                    "singletons": 0,
+                    "singletons %": "N/A",
+                    "with mate mapped to a different chr": 0,
+                    "with mate mapped to a different chr (mapQ >= 5)": 0,
+                },
+            })
+        );
+    }
+}
@@ -0,0 +1,1387 @@
+//! Builds BAI and CSI indexes for BAMs in a single streaming pass.
+//!
+//! [`build_bam_index`] reads a BAM from any [`AsyncRead`], checks that its
+//! records are coordinate sorted, and returns the index along with
+//! `samtools flagstat`-style counts and, if asked for, per-bin coverage.
+//! [`write_bam_index`] and [`write_csi_index`] encode the index
+//! byte-for-byte the same from run to run.
+//!
+//! ```no_run
+//! # async fn run() -> stream_index::Result<()> {
+//! let mut reader = tokio::fs::File::open("sample.bam").await?;
+//! let bam_index = stream_index::build_bam_index(&mut reader, &Default::default()).await?;
+//! let mut writer = tokio::fs::File::create("sample.bam.bai").await?;
+//! stream_index::write_bam_index(&mut writer, &bam_index.index).await?;
+//! println!("{}", String::from_utf8_lossy(&bam_index.flagstat.to_json()?));
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Inputs can also be streamed from object stores with
+//! [`get_async_stream_reader`], which reopens dropped connections from the
+//! last byte read, and large ones indexed in concurrent byte ranges with
+//! [`split::build_bam_index_split`].
+
+#![warn(missing_docs)]
+
+pub mod auth;
+pub mod checkpoint;
+pub mod coverage;
+pub mod drs;
+mod error;
+pub mod flagstat;
+pub mod resume;
+pub mod split;
+pub mod stats;
+#[cfg(test)]
+mod testing;
+
+use std::{num::NonZeroUsize, time::Duration};
+
+use noodles::{
+    bam, bgzf,
+    core::Position,
+    csi::{self, index::reference_sequence::bin::Chunk},
+    sam::{
+        self,
+        record::cigar::{op::Kind, Op},
+    },
+};
+use object_store::{aws, azure, gcp, http, local, ClientOptions, ObjectStore};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub use crate::error::{Error, Result};
+
+/// The `min_shift` and `depth` a BAI index is fixed to.
+pub const BAI_MIN_SHIFT: u8 = 14;
+/// See [`BAI_MIN_SHIFT`].
+pub const BAI_DEPTH: u8 = 5;
+
+/// BAI bins only address positions in [0, 2^29).
+pub const BAI_MAX_REFERENCE_SEQUENCE_LENGTH: usize = (1 << 29) - 1;
+
+/// The deepest CSI binning whose bin IDs fit in the 32 bits the format has.
+pub const CSI_MAX_DEPTH: u8 = 10;
+
+/// htslib reads CSI positions as signed 64-bit integers, so it rejects binning
+/// with `min_shift + 3 * depth` past this.
+pub const CSI_MAX_SHIFT: u8 = 63;
+
+/// Which index to build.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexFormat {
+    /// BAM index; reference sequences up to 2^29 - 1 bp.
+    Bai,
+    /// Coordinate-sorted index with configurable binning.
+    Csi,
+    /// Write both a BAI and a CSI.
+    Both,
+}
+
+impl IndexFormat {
+    /// Whether a BAI is among the indexes.
+    pub fn includes_bai(self) -> bool {
+        matches!(self, IndexFormat::Bai | IndexFormat::Both)
+    }
+
+    /// The indexes to write, one file each.
+    pub fn parts(self) -> &'static [IndexKind] {
+        match self {
+            IndexFormat::Bai => &[IndexKind::Bai],
+            IndexFormat::Csi => &[IndexKind::Csi],
+            IndexFormat::Both => &[IndexKind::Bai, IndexKind::Csi],
+        }
+    }
+}
+
+impl From<IndexKind> for IndexFormat {
+    fn from(kind: IndexKind) -> Self {
+        match kind {
+            IndexKind::Bai => IndexFormat::Bai,
+            IndexKind::Csi => IndexFormat::Csi,
+        }
+    }
+}
+
+/// The format of a single index file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexKind {
+    /// `.bai`
+    Bai,
+    /// `.csi`
+    Csi,
+}
+
+impl IndexKind {
+    /// The file extension, without the dot.
+    pub fn extension(self) -> &'static str {
+        match self {
+            IndexKind::Bai => "bai",
+            IndexKind::Csi => "csi",
+        }
+    }
+}
+
+/// How to index a BAM.
+#[derive(Clone, Debug)]
+pub struct IndexOptions {
+    /// The index to build.
+    pub format: IndexFormat,
+    /// Size of the smallest bins, as a power of two; BAI's is fixed at
+    /// [`BAI_MIN_SHIFT`].
+    pub min_shift: u8,
+    /// CSI depth; derived from the longest reference sequence when unset.
+    pub depth: Option<u8>,
+    /// Write CSI instead of failing when BAI can't address a reference sequence.
+    pub csi_fallback: bool,
+    /// Index BAMs without `SO:coordinate` in the header, if the records are sorted.
+    pub allow_unsorted_header: bool,
+    /// BGZF blocks to inflate at once; one per core when unset.
+    pub threads: Option<NonZeroUsize>,
+    /// How often [`checkpoint::build_bam_index_checkpointed`] saves progress.
+    pub checkpoint_interval: Duration,
+    /// What to count coverage with; coverage isn't counted when unset.
+    pub coverage: Option<coverage::CoverageOptions>,
+}
+
+impl Default for IndexOptions {
+    fn default() -> Self {
+        Self {
+            format: IndexFormat::Bai,
+            min_shift: BAI_MIN_SHIFT,
+            depth: None,
+            csi_fallback: true,
+            allow_unsorted_header: false,
+            threads: None,
+            checkpoint_interval: Duration::from_secs(300),
+            coverage: None,
+        }
+    }
+}
+
+/// Checks the header's `SO` tag before streaming the records, whose order is
+/// verified as they are indexed.
+fn check_sort_order(header: &sam::Header, options: &IndexOptions) -> Result<()> {
+    use sam::header::record::value::map::header::SortOrder;
+    match header.header().and_then(|hdr| hdr.sort_order()) {
+        Some(SortOrder::Coordinate) => Ok(()),
+        None | Some(SortOrder::Unknown | SortOrder::Unsorted) if options.allow_unsorted_header => {
+            log::debug!("BAM header doesn't declare SO:coordinate; checking the records instead");
+            Ok(())
+        }
+        None => Err(Error::UndeclaredSortOrder(None)),
+        Some(sort_order @ (SortOrder::Unknown | SortOrder::Unsorted)) => {
+            Err(Error::UndeclaredSortOrder(Some(sort_order.as_ref().into())))
+        }
+        Some(sort_order) => Err(Error::NotCoordinateSorted(sort_order.as_ref().into())),
+    }
+}
+
+fn max_reference_sequence_length(header: &sam::Header) -> usize {
+    header
+        .reference_sequences()
+        .values()
+        .map(|reference_sequence| reference_sequence.length().get())
+        .max()
+        .unwrap_or(0)
+}
+
+/// Smallest depth whose bins cover `max_len` at `min_shift` (mirrors htslib).
+fn min_depth_for_length(min_shift: u8, max_len: usize) -> u8 {
+    let max_len = max_len as u64 + 256;
+    let mut depth = 0;
+    let mut span = 1u64.checked_shl(min_shift.into()).unwrap_or(u64::MAX);
+    while max_len > span {
+        depth += 1;
+        span = span.saturating_mul(8);
+    }
+    depth
+}
+
+/// Checks that CSI binning can be indexed with and read back by htslib.
+pub fn check_csi_binning(min_shift: u8, depth: u8) -> Result<()> {
+    if depth > CSI_MAX_DEPTH || u32::from(min_shift) + 3 * u32::from(depth) > CSI_MAX_SHIFT.into() {
+        return Err(Error::InvalidBinning { min_shift, depth });
+    }
+    Ok(())
+}
+
+/// Resolves the format, `min_shift` and `depth` to index with, given the
+/// reference sequences declared in the header.
+fn resolve_binning(header: &sam::Header, options: &IndexOptions) -> Result<(IndexFormat, u8, u8)> {
+    let max_len = max_reference_sequence_length(header);
+    let mut format = options.format;
+
+    if format.includes_bai() && max_len > BAI_MAX_REFERENCE_SEQUENCE_LENGTH {
+        if !options.csi_fallback {
+            return Err(Error::ReferenceSequenceTooLong {
+                length: max_len,
+                max: BAI_MAX_REFERENCE_SEQUENCE_LENGTH,
+            });
+        }
+        log::warn!(
+            "Reference sequence of {} bp exceeds the BAI limit; writing CSI instead",
+            max_len
+        );
+        format = IndexFormat::Csi;
+    }
+
+    if format.includes_bai() {
+        if options.min_shift != BAI_MIN_SHIFT || options.depth.is_some_and(|d| d != BAI_DEPTH) {
+            return Err(Error::BaiBinning);
+        }
+        return Ok((format, BAI_MIN_SHIFT, BAI_DEPTH));
+    }
+
+    let min_depth = min_depth_for_length(options.min_shift, max_len);
+    let depth = match options.depth {
+        Some(depth) if depth < min_depth => {
+            return Err(Error::DepthTooSmall {
+                depth,
+                length: max_len,
+                min_shift: options.min_shift,
+                min_depth,
+            })
+        }
+        Some(depth) => depth,
+        // Deepen short binning to BAI's, as far as `min_shift` leaves room for.
+        None => min_depth.max(BAI_DEPTH.min(CSI_MAX_SHIFT.saturating_sub(options.min_shift) / 3)),
+    };
+    check_csi_binning(options.min_shift, depth)?;
+    Ok((format, options.min_shift, depth))
+}
+
+/// Formats a record's position as `name:start`, or `*` for unplaced records.
+fn format_position(header: &sam::Header, id: usize, start: Option<Position>) -> String {
+    match header.reference_sequences().get_index(id) {
+        Some((name, _)) => match start {
+            Some(start) => format!("{}:{}", name, start),
+            None => format!("{}:*", name),
+        },
+        None => "*".into(),
+    }
+}
+
+/// A record's CIGAR operations.
+///
+/// Records with more CIGAR operations than BAM can hold store a `kSmN`
+/// placeholder and keep the real CIGAR in the `CG` tag, which the full decoder
+/// swaps in.
+pub(crate) fn record_cigar(header: &sam::Header, record: &bam::lazy::Record) -> Result<Vec<Op>> {
+    use bam::lazy::record::data::field::{value::Array, Value};
+
+    let ops = record.cigar().iter().collect::<std::io::Result<Vec<_>>>()?;
+    let reference_sequence_length = record
+        .reference_sequence_id()?
+        .and_then(|id| header.reference_sequences().get_index(id))
+        .map(|(_, reference_sequence)| reference_sequence.length().get());
+    let is_placeholder = matches!(
+        (ops.as_slice(), reference_sequence_length),
+        ([op_0, op_1], Some(length))
+            if op_0.kind() == Kind::SoftClip
+                && op_0.len() == record.sequence().len()
+                && op_1.kind() == Kind::Skip
+                && op_1.len() == length
+    );
+    if !is_placeholder {
+        return Ok(ops);
+    }
+    match record.data().get(b"CG").transpose()? {
+        Some(Value::Array(Array::UInt32(buf))) => buf
+            .chunks_exact(4)
+            .map(|b| {
+                let n = u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+                let kind = match n & 0x0f {
+                    0 => Kind::Match,
+                    1 => Kind::Insertion,
+                    2 => Kind::Deletion,
+                    3 => Kind::Skip,
+                    4 => Kind::SoftClip,
+                    5 => Kind::HardClip,
+                    6 => Kind::Pad,
+                    7 => Kind::SequenceMatch,
+                    8 => Kind::SequenceMismatch,
+                    _ => return Err(Error::InvalidRecord("Invalid CIGAR operation in CG tag")),
+                };
+                Ok(Op::new(kind, (n >> 4) as usize))
+            })
+            .collect(),
+        Some(_) => Err(Error::InvalidRecord("Invalid CG tag type")),
+        None => Ok(ops),
+    }
+}
+
+/// Number of reference bases `ops` covers.
+pub(crate) fn alignment_span(ops: &[Op]) -> usize {
+    ops.iter()
+        .filter(|op| op.kind().consumes_reference())
+        .map(|op| op.len())
+        .sum()
+}
+
+/// Wraps a BAM stream in a BGZF reader that inflates blocks on the blocking
+/// pool, `threads` at a time and in order, so virtual positions still match
+/// the compressed file.
+pub(crate) fn bam_stream_reader<R: AsyncRead>(
+    reader: R,
+    threads: Option<NonZeroUsize>,
+) -> bam::AsyncReader<bgzf::AsyncReader<R>> {
+    let mut builder = bgzf::r#async::reader::Builder::default();
+    if let Some(threads) = threads {
+        builder = builder.set_worker_count(threads);
+    }
+    bam::AsyncReader::from(builder.build_with_reader(reader))
+}
+
+/// As `bam_stream_reader`, for a stream that starts at the block holding
+/// `start`; skips to the record there.
+pub(crate) async fn bam_stream_reader_at<R: AsyncRead + Unpin>(
+    reader: R,
+    threads: Option<NonZeroUsize>,
+    start: bgzf::VirtualPosition,
+) -> Result<bam::AsyncReader<bgzf::AsyncReader<R>>> {
+    let mut bam_reader = bam_stream_reader(reader, threads);
+    let skip = u64::from(start.uncompressed());
+    let skipped =
+        tokio::io::copy(&mut bam_reader.get_mut().take(skip), &mut tokio::io::sink()).await?;
+    if skipped != skip {
+        return Err(Error::Truncated(start.compressed()));
+    }
+    Ok(bam_reader)
+}
+
+/// The gzip magic, deflate method and FEXTRA flag every BGZF block starts
+/// with.
+pub(crate) const BGZF_MAGIC: [u8; 4] = [0x1f, 0x8b, 0x08, 0x04];
+
+/// The compressed size of the BGZF block `buf` starts with, read from its
+/// header, or `None` if `buf` doesn't start with a BGZF block header.
+pub fn bgzf_block_size(buf: &[u8]) -> Option<usize> {
+    let header = buf.get(..18)?;
+    if header[..4] != BGZF_MAGIC || header[10..16] != [6, 0, b'B', b'C', 2, 0] {
+        return None;
+    }
+    Some(usize::from(u16::from_le_bytes([header[16], header[17]])) + 1)
+}
+
+/// `chunks`, in order, with those that meet in a BGZF block joined, so that
+/// block is read once rather than in pieces.
+pub fn merge_spans(chunks: impl IntoIterator<Item = Chunk>) -> Vec<Chunk> {
+    let mut spans: Vec<Chunk> = Vec::new();
+    for chunk in chunks {
+        match spans.last_mut() {
+            Some(last) if chunk.start().compressed() <= last.end().compressed() => {
+                *last = Chunk::new(last.start(), last.end().max(chunk.end()));
+            }
+            _ => spans.push(chunk),
+        }
+    }
+    spans
+}
+
+/// Where the records read by `index_records` start and end.
+pub(crate) struct RecordSpan {
+    /// Name and (reference sequence, start) of the first record.
+    pub(crate) first: Option<(String, (usize, Option<Position>))>,
+    /// (reference sequence, start) of the last record.
+    pub(crate) last: Option<(usize, Option<Position>)>,
+    /// Virtual position reading stopped at.
+    pub(crate) end: bgzf::VirtualPosition,
+    /// Flag counts of the records read.
+    pub(crate) flagstat: flagstat::FlagStat,
+    /// Their coverage, if asked for.
+    pub(crate) coverage: Option<coverage::Coverage>,
+}
+
+fn record_name(record: &bam::lazy::Record) -> String {
+    record.read_name().map_or_else(
+        || "*".into(),
+        |name| {
+            let name: &[u8] = name.as_ref();
+            String::from_utf8_lossy(name.strip_suffix(b"\0").unwrap_or(name)).into_owned()
+        },
+    )
+}
+
+/// Adds records to `indexer` until EOF or the first record starting at or past
+/// `until`, checking that they're sorted.
+///
+/// `offset` is where the stream starts in the file; virtual positions are
+/// shifted by it. With `coverage`, the records' coverage is counted too. With a
+/// `checkpointer`, progress is saved as it comes due.
+pub(crate) async fn index_records<R: AsyncRead + Unpin>(
+    bam_reader: &mut bam::AsyncReader<bgzf::AsyncReader<R>>,
+    header: &sam::Header,
+    indexer: &mut csi::index::Indexer,
+    offset: u64,
+    until: Option<bgzf::VirtualPosition>,
+    coverage: Option<coverage::CoverageOptions>,
+    mut checkpointer: Option<&mut checkpoint::Checkpointer>,
+) -> Result<RecordSpan> {
+    let shift = |position: bgzf::VirtualPosition| {
+        bgzf::VirtualPosition::try_from((position.compressed() + offset, position.uncompressed()))
+    };
+    let mut start_position = shift(bam_reader.virtual_position())?;
+    let mut span = RecordSpan {
+        first: None,
+        last: None,
+        end: start_position,
+        flagstat: flagstat::FlagStat::default(),
+        coverage: coverage.map(|options| coverage::Coverage::new(header, options)),
+    };
+    let mut record = bam::lazy::Record::default();
+    // Records sort by (reference sequence, start), with unplaced records last.
+    while until.is_none_or(|until| start_position < until)
+        && bam_reader.read_lazy_record(&mut record).await? != 0
+    {
+        let end_position = shift(bam_reader.virtual_position())?;
+        let reference_sequence_id = record.reference_sequence_id()?;
+        let alignment_start = record.alignment_start()?;
+        let position = (reference_sequence_id.unwrap_or(usize::MAX), alignment_start);
+        if let Some((last_id, last_start)) = span.last.filter(|&last| position < last) {
+            return Err(Error::Unsorted {
+                name: record_name(&record),
+                position: format_position(header, position.0, position.1),
+                previous: format_position(header, last_id, last_start),
+            });
+        }
+        if span.first.is_none() {
+            span.first = Some((record_name(&record), position));
+        }
+        span.last = Some(position);
+        span.flagstat.add_record(
+            record.flags(),
+            reference_sequence_id,
+            record.mate_reference_sequence_id()?,
+            record.mapping_quality(),
+        );
+        let chunk = csi::index::reference_sequence::bin::Chunk::new(start_position, end_position);
+        let alignment_context = match (reference_sequence_id, alignment_start) {
+            (Some(id), Some(start)) => {
+                let ops = record_cigar(header, &record)?;
+                if let Some(coverage) = &mut span.coverage {
+                    coverage.add_record(
+                        record.flags(),
+                        record.mapping_quality(),
+                        (id, start),
+                        &ops,
+                    );
+                }
+                // As `sam::alignment::Record::alignment_end`.
+                let span = alignment_span(&ops);
+                Position::new(usize::from(start) + span - 1)
+                    .map(|end| (id, start, end, !record.flags().is_unmapped()))
+            }
+            _ => None,
+        };
+        indexer.add_record(alignment_context, chunk)?;
+        start_position = end_position;
+        if let Some(checkpointer) = checkpointer.as_deref_mut().filter(|c| c.is_due()) {
+            checkpointer
+                .save(indexer, header, start_position, &span)
+                .await;
+        }
+    }
+    span.end = start_position;
+    Ok(span)
+}
+
+pub(crate) fn finish_index(
+    indexer: csi::index::Indexer,
+    header: &sam::Header,
+    min_shift: u8,
+    depth: u8,
+) -> csi::Index {
+    // `Indexer::build` drops the last reference sequence and leaves the binning
+    // parameters at their defaults, so ask for one extra and rebuild.
+    let index = indexer.build(header.reference_sequences().len() + 1);
+    let mut index_builder = csi::Index::builder()
+        .set_min_shift(min_shift)
+        .set_depth(depth)
+        .set_reference_sequences(index.reference_sequences().to_vec());
+    if let Some(n) = index.unplaced_unmapped_record_count() {
+        index_builder = index_builder.set_unplaced_unmapped_record_count(n);
+    }
+    index_builder.build()
+}
+
+/// Reads the BAM header and checks it can be indexed with `options`.
+pub(crate) async fn read_bam_header<R: AsyncRead + Unpin>(
+    bam_reader: &mut bam::AsyncReader<bgzf::AsyncReader<R>>,
+    options: &IndexOptions,
+) -> Result<(sam::Header, IndexFormat, u8, u8)> {
+    let header: sam::Header = bam_reader.read_header().await?.parse()?;
+    bam_reader.read_reference_sequences().await?; // idk, need to read this first
+    check_sort_order(&header, options)?;
+    let (format, min_shift, depth) = resolve_binning(&header, options)?;
+    Ok((header, format, min_shift, depth))
+}
+
+/// What indexing a BAM produces.
+pub struct BamIndex {
+    /// The index built, which is CSI if a BAI was asked for but a reference
+    /// sequence is too long for one and [`IndexOptions::csi_fallback`] is set.
+    pub format: IndexFormat,
+    /// The index, in noodles' form; [`write_index`] encodes it.
+    pub index: csi::Index,
+    /// The BAM's header.
+    pub header: sam::Header,
+    /// `samtools flagstat` counts of all the records.
+    pub flagstat: flagstat::FlagStat,
+    /// Their coverage, if [`IndexOptions::coverage`] is set.
+    pub coverage: Option<coverage::Coverage>,
+}
+
+impl BamIndex {
+    /// `samtools idxstats` counts per reference sequence.
+    pub fn idxstats(&self) -> stats::IdxStats<'_> {
+        stats::IdxStats::new(&self.header, &self.index)
+    }
+}
+
+/// Indexes the BAM `reader` streams, checking that its records are sorted.
+///
+/// The stream is read to the end once and never seeked, so it can come
+/// from anywhere: a pipe, a socket, or [`get_async_stream_reader`].
+pub async fn build_bam_index<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    options: &IndexOptions,
+) -> Result<BamIndex> {
+    // Records are read lazily, decoding only what the index needs.
+    let mut bam_reader = bam_stream_reader(reader, options.threads);
+    let (header, format, min_shift, depth) = read_bam_header(&mut bam_reader, options).await?;
+    let mut indexer = csi::index::Indexer::new(min_shift, depth);
+    let span = index_records(
+        &mut bam_reader,
+        &header,
+        &mut indexer,
+        0,
+        None,
+        options.coverage,
+        None,
+    )
+    .await?;
+    let index = finish_index(indexer, &header, min_shift, depth);
+    Ok(BamIndex {
+        format,
+        index,
+        header,
+        flagstat: span.flagstat,
+        coverage: span.coverage,
+    })
+}
+
+/// Bins in ID order. noodles writes them in `HashMap` order, which changes from
+/// run to run, so indexes are encoded here to keep them byte-for-byte stable.
+fn sorted_bins(
+    reference_sequence: &csi::index::ReferenceSequence,
+) -> Vec<(usize, &csi::index::reference_sequence::Bin)> {
+    let mut bins: Vec<_> = reference_sequence
+        .bins()
+        .iter()
+        .map(|(&id, bin)| (id, bin))
+        .collect();
+    bins.sort_unstable_by_key(|&(id, _)| id);
+    bins
+}
+
+fn put_chunks(buf: &mut Vec<u8>, chunks: &[csi::index::reference_sequence::bin::Chunk]) {
+    buf.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+    for chunk in chunks {
+        buf.extend_from_slice(&u64::from(chunk.start()).to_le_bytes());
+        buf.extend_from_slice(&u64::from(chunk.end()).to_le_bytes());
+    }
+}
+
+/// Appends the pseudo-bin holding a reference sequence's metadata, without the
+/// `loffset` CSI puts after the bin ID.
+fn put_metadata(buf: &mut Vec<u8>, metadata: &csi::index::reference_sequence::Metadata) {
+    buf.extend_from_slice(&2u32.to_le_bytes());
+    for n in [
+        u64::from(metadata.start_position()),
+        u64::from(metadata.end_position()),
+        metadata.mapped_record_count(),
+        metadata.unmapped_record_count(),
+    ] {
+        buf.extend_from_slice(&n.to_le_bytes());
+    }
+}
+
+/// Encodes a BAI, with bins in ID order.
+pub fn encode_bai(index: &csi::Index) -> Vec<u8> {
+    let mut buf = b"BAI\x01".to_vec();
+    buf.extend_from_slice(&(index.reference_sequences().len() as u32).to_le_bytes());
+    put_linear_indexed_reference_sequences(&mut buf, index);
+    buf
+}
+
+/// Appends the reference sequences and unplaced unmapped record count of a
+/// BAI or tabix index, which lay them out the same.
+fn put_linear_indexed_reference_sequences(buf: &mut Vec<u8>, index: &csi::Index) {
+    use csi::index::reference_sequence::Bin;
+
+    for reference_sequence in index.reference_sequences() {
+        let metadata = reference_sequence.metadata();
+        let n_bin = reference_sequence.bins().len() + usize::from(metadata.is_some());
+        buf.extend_from_slice(&(n_bin as u32).to_le_bytes());
+        for (id, bin) in sorted_bins(reference_sequence) {
+            buf.extend_from_slice(&(id as u32).to_le_bytes());
+            put_chunks(buf, bin.chunks());
+        }
+        if let Some(metadata) = metadata {
+            buf.extend_from_slice(&(Bin::metadata_id(BAI_DEPTH) as u32).to_le_bytes());
+            put_metadata(buf, metadata);
+        }
+        let linear_index = reference_sequence.linear_index();
+        buf.extend_from_slice(&(linear_index.len() as u32).to_le_bytes());
+        for &position in linear_index {
+            buf.extend_from_slice(&u64::from(position).to_le_bytes());
+        }
+    }
+    if let Some(n) = index.unplaced_unmapped_record_count() {
+        buf.extend_from_slice(&n.to_le_bytes());
+    }
+}
+
+/// Encodes a tabix index, with bins in ID order, BGZF-compressed.
+pub fn encode_tabix(index: &csi::Index) -> Result<Vec<u8>> {
+    use std::io::Write;
+
+    let header = index.header().ok_or(Error::MissingTabixHeader)?;
+    let column = |i: usize| i32::try_from(i + 1);
+    let mut buf = b"TBI\x01".to_vec();
+    buf.extend_from_slice(&i32::try_from(index.reference_sequences().len())?.to_le_bytes());
+    for n in [
+        i32::from(header.format()),
+        column(header.reference_sequence_name_index())?,
+        column(header.start_position_index())?,
+        header.end_position_index().map_or(Ok(0), column)?,
+        i32::from(header.line_comment_prefix()),
+        i32::try_from(header.line_skip_count())?,
+    ] {
+        buf.extend_from_slice(&n.to_le_bytes());
+    }
+    let names = header.reference_sequence_names();
+    let l_nm: usize = names.iter().map(|name| name.len() + 1).sum();
+    buf.extend_from_slice(&i32::try_from(l_nm)?.to_le_bytes());
+    for name in names {
+        buf.extend_from_slice(name.as_bytes());
+        buf.push(0);
+    }
+    put_linear_indexed_reference_sequences(&mut buf, index);
+
+    let mut writer = bgzf::Writer::new(Vec::new());
+    writer.write_all(&buf)?;
+    Ok(writer.finish()?)
+}
+
+/// Encodes a CSI for an alignment file (no tabix header), BGZF-compressed.
+pub fn encode_csi(index: &csi::Index) -> Result<Vec<u8>> {
+    use csi::index::reference_sequence::Bin;
+    use std::io::Write;
+
+    let mut buf = b"CSI\x01".to_vec();
+    buf.extend_from_slice(&i32::from(index.min_shift()).to_le_bytes());
+    buf.extend_from_slice(&i32::from(index.depth()).to_le_bytes());
+    buf.extend_from_slice(&0i32.to_le_bytes()); // l_aux
+    buf.extend_from_slice(&(index.reference_sequences().len() as i32).to_le_bytes());
+    for reference_sequence in index.reference_sequences() {
+        let metadata = reference_sequence.metadata();
+        let n_bin = reference_sequence.bins().len() + usize::from(metadata.is_some());
+        buf.extend_from_slice(&(n_bin as i32).to_le_bytes());
+        for (id, bin) in sorted_bins(reference_sequence) {
+            buf.extend_from_slice(&(id as u32).to_le_bytes());
+            buf.extend_from_slice(&u64::from(bin.loffset()).to_le_bytes());
+            put_chunks(&mut buf, bin.chunks());
+        }
+        if let Some(metadata) = metadata {
+            buf.extend_from_slice(&(Bin::metadata_id(index.depth()) as u32).to_le_bytes());
+            buf.extend_from_slice(&0u64.to_le_bytes()); // loffset
+            put_metadata(&mut buf, metadata);
+        }
+    }
+    if let Some(n) = index.unplaced_unmapped_record_count() {
+        buf.extend_from_slice(&n.to_le_bytes());
+    }
+
+    let mut writer = bgzf::Writer::new(Vec::new());
+    writer.write_all(&buf)?;
+    Ok(writer.finish()?)
+}
+
+/// Writes `index` as a BAI.
+pub async fn write_bam_index<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    index: &csi::Index,
+) -> Result<()> {
+    writer.write_all(&encode_bai(index)).await?;
+    Ok(())
+}
+
+/// Writes `index` as a CSI.
+pub async fn write_csi_index<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    index: &csi::Index,
+) -> Result<()> {
+    writer.write_all(&encode_csi(index)?).await?;
+    Ok(())
+}
+
+/// Object store settings that can't be derived from a URL. Each overrides the
+/// corresponding environment variable: `AWS_*` for S3, `GOOGLE_*` and
+/// `STORAGE_EMULATOR_HOST` for GCS, and `AZURE_*` for Azure.
+#[derive(Clone, Default)]
+pub struct StoreOptions {
+    /// S3 region; `us-east-1` if it isn't set here or in the environment.
+    pub region: Option<String>,
+    /// e.g. a local MinIO.
+    pub endpoint: Option<String>,
+    /// S3 access key ID, used together with the secret access key.
+    pub access_key_id: Option<String>,
+    /// S3 secret access key.
+    pub secret_access_key: Option<String>,
+    /// S3 session token, for temporary credentials.
+    pub session_token: Option<String>,
+    /// GCS endpoint of an emulator such as fake-gcs-server, which is sent no
+    /// credentials.
+    pub gcs_endpoint: Option<String>,
+    /// Azure Blob endpoint, including the account for Azurite, e.g.
+    /// `http://127.0.0.1:10000/devstoreaccount1`.
+    pub azure_endpoint: Option<String>,
+    /// Base URL of the DRS server `drs://` URIs are resolved with, instead of
+    /// `https://` and the URI's host, e.g. a local mock.
+    pub drs_endpoint: Option<String>,
+    /// Headers and credentials for HTTP(S) requests.
+    pub http_auth: auth::HttpAuth,
+    /// How requests are retried and streams resumed.
+    pub retry: resume::RetryOptions,
+}
+
+/// Opens the object store holding `url`, and the path of `url` within it.
+///
+/// `http(s)`, `s3`, `gs`, `az` (or `azure`) and `file` URLs are supported.
+/// Credentials for the cloud stores come from the environment.
+pub fn get_object_store(
+    url: &url::Url,
+    options: &StoreOptions,
+) -> Result<(Box<dyn ObjectStore>, object_store::path::Path)> {
+    match url.scheme() {
+        "http" | "https" => {
+            let path: object_store::path::Path = "".into();
+            let client_options = ClientOptions::new()
+                .with_allow_http(url.scheme() == "http")
+                .with_default_headers(options.http_auth.headers_for(url)?);
+            let store = http::HttpBuilder::new()
+                .with_url(url.clone())
+                .with_client_options(client_options)
+                .with_retry(options.retry.retry_config())
+                .build()?;
+            Ok((Box::new(store), path))
+        }
+        "s3" => {
+            let (bucket, path) = bucket_and_path(url, "S3", "bucket")?;
+            let mut builder = aws::AmazonS3Builder::from_env()
+                .with_bucket_name(bucket)
+                .with_retry(options.retry.retry_config());
+            if let Some(region) = &options.region {
+                builder = builder.with_region(region);
+            } else if builder
+                .get_config_value(&aws::AmazonS3ConfigKey::Region)
+                .is_none()
+            {
+                // Same default as the AWS CLI; MinIO doesn't care.
+                builder = builder.with_region("us-east-1");
+            }
+            if let Some(endpoint) = &options.endpoint {
+                builder = builder
+                    .with_endpoint(endpoint)
+                    .with_allow_http(endpoint.starts_with("http://"));
+            }
+            if let (Some(id), Some(secret)) = (&options.access_key_id, &options.secret_access_key) {
+                builder = builder
+                    .with_access_key_id(id)
+                    .with_secret_access_key(secret);
+            }
+            if let Some(token) = &options.session_token {
+                builder = builder.with_token(token);
+            }
+            Ok((Box::new(builder.build()?), path))
+        }
+        "gs" => {
+            let (bucket, path) = bucket_and_path(url, "GCS", "bucket")?;
+            let endpoint = options
+                .gcs_endpoint
+                .clone()
+                .or_else(|| std::env::var("STORAGE_EMULATOR_HOST").ok());
+            let builder = match endpoint {
+                // object_store only takes another endpoint from a service
+                // account key, and documents this key, with no private key
+                // and OAuth disabled, for emulators. Requests then carry no
+                // credentials.
+                Some(endpoint) => {
+                    let endpoint = match endpoint.contains("://") {
+                        true => endpoint,
+                        false => format!("http://{}", endpoint),
+                    };
+                    let key = serde_json::json!({
+                        "gcs_base_url": endpoint.trim_end_matches('/'),
+                        "disable_oauth": true,
+                        "client_email": "",
+                        "private_key": "",
+                    });
+                    gcp::GoogleCloudStorageBuilder::new()
+                        .with_service_account_key(key.to_string())
+                        .with_client_options(
+                            ClientOptions::new().with_allow_http(endpoint.starts_with("http://")),
+                        )
+                }
+                None => gcp::GoogleCloudStorageBuilder::from_env(),
+            };
+            let store = builder
+                .with_bucket_name(bucket)
+                .with_retry(options.retry.retry_config())
+                .build()?;
+            Ok((Box::new(store), path))
+        }
+        "az" | "azure" => {
+            let (container, path) = bucket_and_path(url, "Azure", "container")?;
+            let mut builder = azure::MicrosoftAzureBuilder::from_env()
+                .with_container_name(container)
+                .with_retry(options.retry.retry_config());
+            if let Some(endpoint) = &options.azure_endpoint {
+                // Azurite's endpoint ends in its account, which object_store
+                // needs apart from the endpoint to sign requests.
+                let account = url::Url::parse(endpoint).ok().and_then(|endpoint| {
+                    let account = endpoint.path_segments()?.next()?;
+                    (!account.is_empty()).then(|| account.to_string())
+                });
+                let has_account = builder
+                    .get_config_value(&azure::AzureConfigKey::AccountName)
+                    .is_some();
+                if let (Some(account), false) = (account, has_account) {
+                    builder = builder.with_account(account);
+                }
+                builder = builder
+                    .with_endpoint(endpoint.clone())
+                    .with_allow_http(endpoint.starts_with("http://"));
+            }
+            Ok((Box::new(builder.build()?), path))
+        }
+        "file" => {
+            let path = object_store::path::Path::from_url_path(url.path())?;
+            Ok((Box::new(local::LocalFileSystem::new()), path))
+        }
+        scheme => Err(Error::InvalidLocation(format!(
+            "Unsupported URL scheme {:?}",
+            scheme
+        ))),
+    }
+}
+
+/// Splits an object store URL into its bucket, or container, and the path
+/// within it.
+fn bucket_and_path<'a>(
+    url: &'a url::Url,
+    store: &str,
+    bucket: &str,
+) -> Result<(&'a str, object_store::path::Path)> {
+    let name = url
+        .host_str()
+        .ok_or_else(|| Error::InvalidLocation(format!("{} URL has no {}", store, bucket)))?;
+    let path = object_store::path::Path::from_url_path(url.path())?;
+    Ok((name, path))
+}
+
+/// Where a BAM or CRAM is streamed from.
+#[derive(Clone, Debug)]
+pub enum Source {
+    /// Standard input.
+    Stdin,
+    /// An object, or a local file as a `file` URL.
+    Url(url::Url),
+}
+
+impl Source {
+    /// The last path segment, used to name the index.
+    pub fn file_name(&self) -> Option<&str> {
+        match self {
+            Source::Stdin => None,
+            Source::Url(url) => url
+                .path_segments()
+                .and_then(|mut segments| segments.next_back())
+                .filter(|name| !name.is_empty()),
+        }
+    }
+
+    /// Whether the source is named like a CRAM; anything else is read as BAM.
+    pub fn is_cram(&self) -> bool {
+        self.file_name().is_some_and(|name| name.ends_with(".cram"))
+    }
+}
+
+impl std::fmt::Display for Source {
+    /// `-`, or the URL with any password or query string redacted.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Stdin => f.write_str("-"),
+            Source::Url(url) => f.write_str(&auth::redact_url(url)),
+        }
+    }
+}
+
+impl std::str::FromStr for Source {
+    type Err = Error;
+
+    /// Accepts `-` for stdin, a URL, or a filesystem path.
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "-" {
+            return Ok(Source::Stdin);
+        }
+        parse_url_or_path(s).map(Source::Url)
+    }
+}
+
+/// Parses `s` as a URL, or else as a (possibly not yet existing) local path.
+pub fn parse_url_or_path(s: &str) -> Result<url::Url> {
+    match url::Url::parse(s) {
+        // Single letters are Windows drive prefixes, not schemes.
+        Ok(url) if url.scheme().len() > 1 => Ok(url),
+        // Meant as a URL, so not to be read as an oddly named path.
+        Err(e) if s.contains("://") => Err(e.into()),
+        _ => {
+            let path = std::path::absolute(s)
+                .map_err(|_| Error::InvalidLocation(format!("Invalid path {:?}", s)))?;
+            let url = if s.ends_with(std::path::MAIN_SEPARATOR) || path.is_dir() {
+                url::Url::from_directory_path(&path)
+            } else {
+                url::Url::from_file_path(&path)
+            };
+            url.map_err(|()| Error::InvalidLocation(format!("Invalid path {:?}", path)))
+        }
+    }
+}
+
+/// Opens `source` for streaming. Object store reads are resumed from the last
+/// byte read if the connection drops, as `options.retry` allows.
+pub async fn get_async_stream_reader(
+    source: &Source,
+    options: &StoreOptions,
+) -> Result<Box<dyn AsyncRead + Unpin + Send>> {
+    match source {
+        Source::Stdin => Ok(Box::new(tokio::io::stdin())),
+        Source::Url(url) => {
+            let (read_url, read_options) = drs::resolve(url, options).await?;
+            let (store, path) = get_object_store(&read_url, &read_options)?;
+            let reader =
+                resume::open_resumable(url, store.into(), path, None, &read_options.retry).await?;
+            Ok(Box::new(reader))
+        }
+    }
+}
+
+/// Writes `index` as `kind`.
+pub async fn write_index<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    kind: IndexKind,
+    index: &csi::Index,
+) -> Result<()> {
+    match kind {
+        IndexKind::Bai => write_bam_index(writer, index).await,
+        IndexKind::Csi => write_csi_index(writer, index).await,
+    }
+}
+
+/// Whether indexes can be written next to objects at URLs with `scheme`:
+/// local files and the object stores, but not HTTP(S) or DRS.
+pub fn is_writable_scheme(scheme: &str) -> bool {
+    matches!(scheme, "file" | "s3" | "gs" | "az" | "azure")
+}
+
+/// Opens `location` for writing as a multipart upload, or stdout for `None`.
+pub async fn create_writer(
+    location: Option<&url::Url>,
+    options: &StoreOptions,
+) -> Result<Box<dyn AsyncWrite + Unpin + Send>> {
+    let Some(url) = location else {
+        return Ok(Box::new(tokio::io::stdout()));
+    };
+    let (store, path) = get_object_store(url, options)?;
+    let (_id, writer) = store.put_multipart(&path).await?;
+    Ok(writer)
+}
+
+/// Writes `bytes` to `location`, or stdout for `None`.
+pub async fn put_bytes(
+    location: Option<&url::Url>,
+    bytes: &[u8],
+    options: &StoreOptions,
+) -> Result<()> {
+    let mut writer = create_writer(location, options).await?;
+    writer.write_all(bytes).await?;
+    writer.flush().await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// Whether an object exists at `url`.
+pub async fn object_exists(url: &url::Url, options: &StoreOptions) -> Result<bool> {
+    let (store, path) = get_object_store(url, options)?;
+    match store.head(&path).await {
+        Ok(_) => Ok(true),
+        Err(object_store::Error::NotFound { .. }) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{bam_from_sam, many_records, sam_header, sam_record};
+
+    async fn index(sam: &str, options: &IndexOptions) -> Result<BamIndex> {
+        build_bam_index(&mut &bam_from_sam(sam)[..], options).await
+    }
+
+    fn csi_options(min_shift: u8, depth: Option<u8>) -> IndexOptions {
+        IndexOptions {
+            format: IndexFormat::Csi,
+            min_shift,
+            depth,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn csi_reads_back_with_chunk_ends() {
+        let bam_index = index(&many_records(2000), &csi_options(12, None))
+            .await
+            .unwrap();
+        let csi = encode_csi(&bam_index.index).unwrap();
+        let decoded = csi::Reader::new(&csi[..]).read_index().unwrap();
+
+        assert_eq!(decoded.min_shift(), 12);
+        assert_eq!(decoded.depth(), bam_index.index.depth());
+        assert_eq!(decoded.reference_sequences().len(), 2);
+        for (decoded, built) in decoded
+            .reference_sequences()
+            .iter()
+            .zip(bam_index.index.reference_sequences())
+        {
+            assert_eq!(decoded.bins(), built.bins());
+            assert_eq!(decoded.metadata(), built.metadata());
+            for bin in decoded.bins().values() {
+                for chunk in bin.chunks() {
+                    assert!(chunk.end() > chunk.start(), "{:?}", chunk);
+                }
+            }
+        }
+        assert_eq!(decoded.unplaced_unmapped_record_count(), Some(3));
+    }
+
+    /// Indexes `bam` as before records were read lazily: decoding each in
+    /// full and taking its end from noodles.
+    async fn index_decoding_records(bam: &[u8]) -> csi::Index {
+        let mut reader = bam::AsyncReader::new(bam);
+        let header: sam::Header = reader.read_header().await.unwrap().parse().unwrap();
+        reader.read_reference_sequences().await.unwrap();
+        let mut indexer = csi::index::Indexer::new(BAI_MIN_SHIFT, BAI_DEPTH);
+        let mut record = sam::alignment::Record::default();
+        let mut start = reader.virtual_position();
+        while reader.read_record(&header, &mut record).await.unwrap() != 0 {
+            let end = reader.virtual_position();
+            let context = match (
+                record.reference_sequence_id(),
+                record.alignment_start(),
+                record.alignment_end(),
+            ) {
+                (Some(id), Some(start), Some(end)) => {
+                    Some((id, start, end, !record.flags().is_unmapped()))
+                }
+                _ => None,
+            };
+            let chunk = csi::index::reference_sequence::bin::Chunk::new(start, end);
+            indexer.add_record(context, chunk).unwrap();
+            start = end;
+        }
+        finish_index(indexer, &header, BAI_MIN_SHIFT, BAI_DEPTH)
+    }
+
+    #[tokio::test]
+    async fn lazy_records_index_as_decoded_ones() {
+        let mut sam = sam_header(&[("chr1", 1_000_000), ("chr2", 1_000_000)]);
+        sam.push_str(&sam_record("a", 0, "chr1", 100, "50M2I48M"));
+        sam.push_str(&sam_record("b", 0, "chr1", 200, "10S30M5D60M"));
+        // An unmapped read placed with its mate.
+        sam.push_str(&sam_record("c", 4, "chr1", 200, "*"));
+        // A long read with more CIGAR operations than BAM holds, which are
+        // kept in its CG tag.
+        let cigar = "2M1D".repeat(33_000);
+        sam.push_str(&format!(
+            "long\t0\tchr1\t300\t60\t{}\t*\t0\t0\t{}\t*\n",
+            cigar,
+            "A".repeat(66_000)
+        ));
+        sam.push_str(&sam_record("d", 0, "chr1", 150_000, "100M"));
+        sam.push_str(&sam_record("e", 16, "chr2", 1, "100M"));
+        sam.push_str(&sam_record("f", 4, "*", 0, "*"));
+        let bam = bam_from_sam(&sam);
+
+        let expected = index_decoding_records(&bam).await;
+        let bam_index = build_bam_index(&mut &bam[..], &Default::default())
+            .await
+            .unwrap();
+        assert_eq!(bam_index.index, expected);
+        // The long read spans the 99,000 bases of its CG tag, which fit in a
+        // 128 kbp bin, rather than its placeholder's whole chr1.
+        let bins = expected.reference_sequences()[0].bins();
+        assert!(bins.contains_key(&585), "{:?}", bins.keys());
+    }
+
+    #[tokio::test]
+    async fn out_of_range_binning_is_an_error() {
+        let sam = sam_header(&[("chr1", 1000)]);
+        for (min_shift, depth) in [(64, None), (14, Some(25)), (40, Some(8))] {
+            let result = index(&sam, &csi_options(min_shift, depth)).await;
+            assert!(
+                matches!(result, Err(Error::InvalidBinning { .. })),
+                "min_shift={} depth={:?}",
+                min_shift,
+                depth
+            );
+        }
+        // Unless asked for, the depth is kept shallow enough for min_shift.
+        let bam_index = index(&sam, &csi_options(60, None)).await.unwrap();
+        assert_eq!(bam_index.index.depth(), 1);
+    }
+
+    #[test]
+    fn tabix_indexes_need_a_header() {
+        let result = encode_tabix(&csi::Index::default());
+        assert!(matches!(result, Err(Error::MissingTabixHeader)));
+    }
+
+    #[tokio::test]
+    async fn records_out_of_order_are_unsorted() {
+        for (records, expected) in [
+            // Backwards within a reference sequence.
+            (
+                [("a", 0, "chr1", 100), ("b", 0, "chr1", 50)],
+                ("b", "chr1:50", "chr1:100"),
+            ),
+            // Reference sequences out of order.
+            (
+                [("a", 0, "chr2", 10), ("b", 0, "chr1", 500)],
+                ("b", "chr1:500", "chr2:10"),
+            ),
+            // Unplaced records before placed ones.
+            (
+                [("a", 4, "*", 0), ("b", 0, "chr1", 10)],
+                ("b", "chr1:10", "*"),
+            ),
+        ] {
+            let mut sam = sam_header(&[("chr1", 1000), ("chr2", 1000)]);
+            for (name, flags, reference, start) in records {
+                let cigar = if flags == 4 { "*" } else { "10M" };
+                sam.push_str(&sam_record(name, flags, reference, start, cigar));
+            }
+            match index(&sam, &IndexOptions::default()).await {
+                Err(Error::Unsorted {
+                    name,
+                    position,
+                    previous,
+                }) => assert_eq!((&name[..], &position[..], &previous[..]), expected),
+                result => panic!("{:?}: {:?}", expected, result.map(|_| ())),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn headers_not_declaring_coordinate_order_need_allowing() {
+        let records = sam_record("a", 0, "chr1", 10, "10M");
+        let allowed = IndexOptions {
+            allow_unsorted_header: true,
+            ..Default::default()
+        };
+        for sort_order in [None, Some("unsorted"), Some("unknown")] {
+            let hd = match sort_order {
+                Some(sort_order) => format!("@HD\tVN:1.6\tSO:{}\n", sort_order),
+                None => "@HD\tVN:1.6\n".into(),
+            };
+            let sam = format!("{}@SQ\tSN:chr1\tLN:1000\n{}", hd, records);
+            let result = index(&sam, &IndexOptions::default()).await;
+            assert!(
+                matches!(&result, Err(Error::UndeclaredSortOrder(s)) if s.as_deref() == sort_order),
+                "{:?}",
+                sort_order
+            );
+            let bam_index = index(&sam, &allowed).await.unwrap();
+            assert_eq!(bam_index.index.reference_sequences().len(), 1);
+        }
+
+        // Another declared order is never indexed.
+        let sam = format!(
+            "@HD\tVN:1.6\tSO:queryname\n@SQ\tSN:chr1\tLN:1000\n{}",
+            records
+        );
+        for options in [IndexOptions::default(), allowed] {
+            let result = index(&sam, &options).await;
+            assert!(matches!(&result, Err(Error::NotCoordinateSorted(s)) if s == "queryname"));
+        }
+    }
+
+    #[test]
+    fn s3_urls_open_their_bucket_at_their_key() {
+        let options = StoreOptions {
+            region: Some("eu-west-2".into()),
+            endpoint: Some("http://127.0.0.1:9000".into()),
+            ..Default::default()
+        };
+        let url = url::Url::parse("s3://bucket/dir/a%20b.bam").unwrap();
+        let (store, path) = get_object_store(&url, &options).unwrap();
+        assert_eq!(store.to_string(), "AmazonS3(bucket)");
+        assert_eq!(path.as_ref(), "dir/a b.bam");
+
+        let url = url::Url::parse("s3:///key.bam").unwrap();
+        let result = get_object_store(&url, &options);
+        assert!(matches!(result, Err(Error::InvalidLocation(_))));
+    }
+
+    #[test]
+    fn gcs_and_azure_urls_open_their_bucket_or_container_at_their_path() {
+        let options = StoreOptions {
+            gcs_endpoint: Some("127.0.0.1:4443".into()),
+            azure_endpoint: Some("http://127.0.0.1:10000/devstoreaccount1".into()),
+            ..Default::default()
+        };
+        let url = url::Url::parse("gs://bucket/dir/a%20b.bam").unwrap();
+        let (store, path) = get_object_store(&url, &options).unwrap();
+        assert_eq!(store.to_string(), "GoogleCloudStorage(bucket)");
+        assert_eq!(path.as_ref(), "dir/a b.bam");
+
+        for scheme in ["az", "azure"] {
+            let url = url::Url::parse(&format!("{}://container/dir/a.cram", scheme)).unwrap();
+            let (store, path) = get_object_store(&url, &options).unwrap();
+            assert_eq!(
+                store.to_string(),
+                "MicrosoftAzure { account: devstoreaccount1, container: container }"
+            );
+            assert_eq!(path.as_ref(), "dir/a.cram");
+        }
+
+        for url in ["gs:///a.bam", "az:///a.bam"] {
+            let url = url::Url::parse(url).unwrap();
+            let result = get_object_store(&url, &options);
+            assert!(matches!(result, Err(Error::InvalidLocation(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn gcs_emulators_are_sent_no_credentials() {
+        use std::{
+            convert::Infallible,
+            sync::{Arc, Mutex},
+        };
+
+        use hyper::{
+            service::{make_service_fn, service_fn},
+            Body, Request, Response, Server,
+        };
+
+        // Each request's path and `Authorization` header.
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&requests);
+        let make_service = make_service_fn(move |_| {
+            let seen = Arc::clone(&seen);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let authorization = request
+                        .headers()
+                        .get(hyper::header::AUTHORIZATION)
+                        .map(|value| value.to_str().unwrap().to_string());
+                    seen.lock()
+                        .unwrap()
+                        .push((request.uri().path().to_string(), authorization));
+                    let response = Response::builder()
+                        .header("Last-Modified", "Thu, 19 Oct 2023 00:00:00 GMT")
+                        .header("ETag", "\"1\"")
+                        .body(Body::from("BAM"))
+                        .unwrap();
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let options = StoreOptions {
+            gcs_endpoint: Some(server.local_addr().to_string()),
+            ..Default::default()
+        };
+        tokio::spawn(server);
+
+        let url = url::Url::parse("gs://bucket/dir/a.bam").unwrap();
+        let (store, path) = get_object_store(&url, &options).unwrap();
+        let bytes = store.get(&path).await.unwrap().bytes().await.unwrap();
+        assert_eq!(&bytes[..], b"BAM");
+        assert_eq!(
+            *requests.lock().unwrap(),
+            [("/bucket/dir%2Fa%2Ebam".to_string(), None)]
+        );
+    }
+
+    #[test]
+    fn paths_parse_as_file_urls() {
+        let cwd = std::env::current_dir().unwrap();
+        let parse = |s| parse_url_or_path(s).unwrap();
+
+        assert_eq!(
+            parse("data/a.bam"),
+            url::Url::from_file_path(cwd.join("data/a.bam")).unwrap()
+        );
+        assert_eq!(parse("/data/a.bam").as_str(), "file:///data/a.bam");
+        assert_eq!(parse("/data/").as_str(), "file:///data/");
+        assert_eq!(parse(".").as_str(), format!("file://{}/", cwd.display()));
+        assert_eq!(parse("file:///data/a.bam").as_str(), "file:///data/a.bam");
+        assert_eq!(parse("s3://bucket/a.bam").as_str(), "s3://bucket/a.bam");
+        // A drive letter isn't a scheme.
+        let url = parse("C:/data/a.bam");
+        assert_eq!(url.scheme(), "file");
+        assert!(url.path().ends_with("/C:/data/a.bam"), "{}", url);
+
+        assert!(matches!(
+            parse_url_or_path("https://[::1/a.bam"),
+            Err(Error::InvalidUrl(_))
+        ));
+        assert!(matches!(
+            parse_url_or_path("s3://bucket name/a.bam"),
+            Err(Error::InvalidUrl(_))
+        ));
+    }
+
+    #[test]
+    fn sources_are_stdin_urls_or_paths() {
+        assert!(matches!("-".parse(), Ok(Source::Stdin)));
+        let source: Source = "/data/a.cram".parse().unwrap();
+        assert_eq!(source.to_string(), "file:///data/a.cram");
+        assert_eq!(source.file_name(), Some("a.cram"));
+        assert!(source.is_cram());
+
+        let source: Source = "https://example.com/dir/".parse().unwrap();
+        assert_eq!(source.file_name(), None);
+        assert!(!source.is_cram());
+        assert_eq!(Source::Stdin.file_name(), None);
+    }
+
+    #[tokio::test]
+    async fn file_urls_stream_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a b.bam");
+        std::fs::write(&file, b"BAM\x01").unwrap();
+        let source = Source::Url(url::Url::from_file_path(&file).unwrap());
+
+        let mut reader = get_async_stream_reader(&source, &Default::default())
+            .await
+            .unwrap();
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"BAM\x01");
+    }
+}
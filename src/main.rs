@@ -1,95 +1,509 @@
+mod batch;
+mod cli;
+mod cram;
+mod faidx;
+mod serve;
+mod tabix;
+mod tee;
+#[cfg(test)]
+#[path = "testing.rs"]
+#[allow(dead_code)] // Shared with the library's tests.
+mod testing;
+mod verify;
+mod view;
+
+use std::{io::Write, num::NonZeroUsize, sync::Arc};
+
 use anyhow::{Context, Result};
-use noodles::{bam, csi, sam};
-use object_store::{http, ObjectStore};
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
-use tokio_util::io::StreamReader;
+use clap::Parser;
+use noodles::csi;
+use stream_index::{
+    auth, build_bam_index, checkpoint, coverage::CoverageFormat, create_writer, flagstat,
+    get_async_stream_reader, get_object_store, is_writable_scheme, object_exists,
+    parse_url_or_path, put_bytes, split, stats::StatsFormat, write_index, BamIndex, IndexFormat,
+    IndexKind, IndexOptions, Source, StoreOptions,
+};
+use tokio::io::AsyncWriteExt;
+
+/// What to do when an index already exists at the destination.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum IfExists {
+    /// Replace the existing index.
+    Overwrite,
+    /// Leave the existing index alone and don't stream the input.
+    Skip,
+    /// Exit with an error.
+    Error,
+}
+
+/// What an `index` or `batch` run builds and writes, beyond the index itself.
+#[derive(Clone, Debug, Default)]
+struct RunOptions {
+    indexing: IndexOptions,
+    /// Byte ranges to index concurrently; the whole stream at once when unset.
+    split: Option<NonZeroUsize>,
+    /// Where to save progress, and resume from, for `index` runs.
+    checkpoint: Option<url::Url>,
+    /// Where to copy the input to while indexing it, for `index` runs.
+    tee: Option<url::Url>,
+    /// Per-reference record counts to write next to the index.
+    idxstats: Option<StatsFormat>,
+    /// Write `samtools flagstat`-style counts next to the index.
+    flagstat: bool,
+    /// Coverage tracks to write next to the index; what they count is in
+    /// `indexing.coverage`.
+    coverage: Option<CoverageFormat>,
+}
+
+/// Where `--tee` copies `input` to: `url` itself, or the input's name under it
+/// if it ends in `/`.
+fn copy_location(url: &url::Url, input: &Source) -> Result<url::Url> {
+    if !url.path().ends_with('/') {
+        return Ok(url.clone());
+    }
+    let name = input
+        .file_name()
+        .context("--tee must name the copy when reading from stdin")?;
+    Ok(url.join(name)?)
+}
+
+/// Where an index is written.
+#[derive(Clone, Debug)]
+enum Destination {
+    Stdout,
+    /// A directory (or object prefix) the index is named into.
+    Directory(url::Url),
+    File(url::Url),
+}
+
+impl std::str::FromStr for Destination {
+    type Err = anyhow::Error;
 
-fn is_coordinate_sorted(header: &sam::Header) -> bool {
-    use sam::header::record::value::map::header::SortOrder;
-    if let Some(hdr) = header.header() {
-        if let Some(sort_order) = hdr.sort_order() {
-            return sort_order == SortOrder::Coordinate;
+    /// Accepts `-` for stdout, or a URL or path; a trailing `/` or an
+    /// existing local directory means "put the index in here".
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "-" {
+            return Ok(Destination::Stdout);
+        }
+        let url = parse_url_or_path(s)?;
+        if url.path().ends_with('/') {
+            Ok(Destination::Directory(url))
+        } else {
+            Ok(Destination::File(url))
         }
     }
-    false
 }
 
-async fn build_bam_index<R: AsyncRead + Unpin>(reader: &mut R) -> Result<csi::Index> {
-    let mut bam_reader = bam::AsyncReader::new(reader);
-    let header: sam::Header = bam_reader.read_header().await?.parse()?;
-    bam_reader.read_reference_sequences().await?; // idk, need to read this first
-    if !is_coordinate_sorted(&header) {
-        anyhow::bail!("BAM file is not coordinate sorted");
-    }
-    let mut start_position = bam_reader.virtual_position();
-    let mut builder = csi::index::Indexer::default();
-    let mut record = sam::alignment::Record::default();
-    while bam_reader.read_record(&header, &mut record).await? != 0 {
-        let end_position = bam_reader.virtual_position();
-        let chunk = csi::index::reference_sequence::bin::Chunk::new(start_position, end_position);
-        let alignment_context = match (
-            record.reference_sequence_id(),
-            record.alignment_start(),
-            record.alignment_end(),
-        ) {
-            (Some(id), Some(start), Some(end)) => {
-                Some((id, start, end, !record.flags().is_unmapped()))
+/// Resolves where the index with `extension` for `source` goes; `None` means
+/// stdout.
+///
+/// Without an explicit destination the index is written next to the source
+/// if the source lives in a writable store, and into the working directory
+/// otherwise.
+fn index_location(
+    destination: Option<&Destination>,
+    source: &Source,
+    extension: &str,
+) -> Result<Option<url::Url>> {
+    let fname = format!("{}.{}", source.file_name().unwrap_or("stdin"), extension);
+    let url = match destination {
+        Some(Destination::Stdout) => return Ok(None),
+        Some(Destination::File(url)) => url.clone(),
+        Some(Destination::Directory(url)) => url.join(&fname)?,
+        None => match source {
+            Source::Url(url) if is_writable_scheme(url.scheme()) => {
+                let mut url = url.clone();
+                url.set_path(&format!("{}.{}", url.path(), extension));
+                url
             }
-            _ => None,
-        };
-        builder.add_record(alignment_context, chunk)?;
-        start_position = end_position;
-    }
-    let index = builder.build(header.reference_sequences().len());
-    Ok(index)
+            _ => parse_url_or_path(".")?.join(&fname)?,
+        },
+    };
+    Ok(Some(url))
 }
 
-async fn write_bam_index<W: AsyncWrite + Unpin>(writer: &mut W, index: &csi::Index) -> Result<()> {
-    let mut writer = bam::bai::AsyncWriter::new(writer);
-    writer.write_header().await?;
-    writer.write_index(index).await?;
+async fn put_index(
+    location: Option<&url::Url>,
+    kind: IndexKind,
+    index: &csi::Index,
+    options: &StoreOptions,
+) -> Result<()> {
+    let mut writer = create_writer(location, options).await?;
+    write_index(&mut writer, kind, index).await?;
+    writer.flush().await?;
+    writer.shutdown().await?;
     Ok(())
 }
 
-async fn get_async_stream_reader(url: &url::Url) -> Result<impl AsyncRead + Unpin> {
-    let (store, path) = match url.scheme() {
-        "http" | "https" => {
-            let path: object_store::path::Path = "".try_into().unwrap();
-            let store = http::HttpBuilder::new().with_url(url.clone()).build()?;
-            (store, path)
+/// Applies `if_exists` to the indexes about to be written.
+///
+/// Returns an existing location if indexing should be skipped.
+async fn check_existing(
+    locations: &[Option<url::Url>],
+    if_exists: IfExists,
+    options: &StoreOptions,
+) -> Result<Option<url::Url>> {
+    if if_exists == IfExists::Overwrite {
+        return Ok(None);
+    }
+    // Stdout (`None`) never has an index already.
+    let urls: Vec<_> = locations.iter().flatten().collect();
+    let mut existing = Vec::new();
+    for &url in &urls {
+        if object_exists(url, options).await? {
+            existing.push(url.clone());
+        }
+    }
+    match if_exists {
+        IfExists::Skip if !existing.is_empty() && existing.len() == urls.len() => {
+            log::info!("Index already exists at {}; skipping", existing[0]);
+            Ok(existing.into_iter().next())
+        }
+        IfExists::Error if !existing.is_empty() => {
+            anyhow::bail!("Index already exists at {} (see --if-exists)", existing[0])
+        }
+        _ => Ok(None),
+    }
+}
+
+/// What indexing a single input did.
+enum Outcome {
+    /// The indexes were written to these locations (`None` is stdout).
+    Indexed(Vec<Option<url::Url>>),
+    /// An index already existed here and `--if-exists skip` was given.
+    Skipped(url::Url),
+}
+
+async fn index_source(
+    input: &Source,
+    output: Option<&Destination>,
+    options: &RunOptions,
+    if_exists: IfExists,
+    store_options: &StoreOptions,
+) -> Result<Outcome> {
+    if input.is_cram() {
+        if options.indexing.format != IndexFormat::Bai {
+            anyhow::bail!("--format applies to BAMs only; CRAMs are indexed as .crai");
+        }
+        if options.tee.is_some()
+            || options.idxstats.is_some()
+            || options.flagstat
+            || options.coverage.is_some()
+        {
+            anyhow::bail!("--tee, --idxstats, --flagstat and --coverage apply to BAMs only");
+        }
+        return cram::index_cram_source(input, output, if_exists, store_options).await;
+    }
+    let copy = match &options.tee {
+        Some(_) if options.split.is_some() || options.checkpoint.is_some() => {
+            anyhow::bail!("--tee can't be combined with --split or --checkpoint")
+        }
+        Some(url) => Some(copy_location(url, input)?),
+        None => None,
+    };
+    // The index goes next to the copy unless told otherwise.
+    let copy_source = copy.clone().map(Source::Url);
+    let index_base = match (&copy_source, output) {
+        (Some(copy_source), None) => copy_source,
+        _ => input,
+    };
+    let single_output = matches!(output, Some(Destination::File(_) | Destination::Stdout));
+    if options.indexing.format.parts().len() > 1 && single_output {
+        anyhow::bail!("--output must be a directory when writing more than one index");
+    }
+    if (options.idxstats.is_some() || options.flagstat || options.coverage.is_some())
+        && single_output
+    {
+        anyhow::bail!("--output must be a directory when writing summaries next to the index");
+    }
+
+    let extensions = options
+        .indexing
+        .format
+        .parts()
+        .iter()
+        .map(|part| part.extension())
+        .chain(
+            options
+                .idxstats
+                .iter()
+                .flat_map(|format| format.parts())
+                .map(|part| part.extension()),
+        )
+        .chain(options.flagstat.then_some(flagstat::EXTENSION))
+        .chain(
+            options
+                .coverage
+                .iter()
+                .flat_map(|format| format.parts())
+                .map(|part| part.extension()),
+        );
+    let locations = extensions
+        .map(|extension| index_location(output, index_base, extension))
+        .collect::<Result<Vec<_>>>()?;
+    if let Some(url) = check_existing(&locations, if_exists, store_options).await? {
+        return Ok(Outcome::Skipped(url));
+    }
+
+    if let (Some(_), Some(checkpoint)) = (options.split, &options.checkpoint) {
+        anyhow::bail!("--split and --checkpoint {} can't be combined", checkpoint);
+    }
+    let bam_index = match (input, &options.checkpoint, options.split) {
+        (Source::Stdin, Some(_), _) => {
+            anyhow::bail!("--checkpoint needs a URL or file input, not stdin")
+        }
+        (Source::Stdin, _, Some(_)) => {
+            anyhow::bail!("--split needs a URL or file input, not stdin")
+        }
+        (Source::Url(url), Some(checkpoint), _) => {
+            checkpoint::build_bam_index_checkpointed(
+                url,
+                checkpoint,
+                &options.indexing,
+                store_options,
+            )
+            .await?
+        }
+        (Source::Url(url), None, Some(parts)) if parts.get() > 1 => {
+            match split::build_bam_index_split(url, parts, &options.indexing, store_options).await?
+            {
+                Some(bam_index) => bam_index,
+                None => {
+                    let mut stream_reader = get_async_stream_reader(input, store_options).await?;
+                    build_bam_index(&mut stream_reader, &options.indexing).await?
+                }
+            }
         }
         _ => {
-            unimplemented!("Only HTTP(S) is supported");
+            let mut stream_reader = get_async_stream_reader(input, store_options).await?;
+            match &copy {
+                Some(copy) => {
+                    let (store, path) = get_object_store(copy, store_options)?;
+                    let mut reader =
+                        tee::TeeReader::new(stream_reader, Arc::from(store), path).await?;
+                    let bam_index = match build_bam_index(&mut reader, &options.indexing).await {
+                        Ok(bam_index) => bam_index,
+                        Err(e) => {
+                            reader.abort().await;
+                            return Err(e.into());
+                        }
+                    };
+                    let n = reader.finish().await?;
+                    log::info!("Copied {} bytes to {}", n, copy);
+                    bam_index
+                }
+                None => build_bam_index(&mut stream_reader, &options.indexing).await?,
+            }
         }
     };
-    let stream = store.get(&path).await?.into_stream();
-    Ok(StreamReader::new(stream))
+
+    let locations = write_outputs(index_base, output, &bam_index, options, store_options).await?;
+    if let Some(checkpoint) = &options.checkpoint {
+        checkpoint::remove_checkpoint(checkpoint, store_options).await;
+    }
+    Ok(Outcome::Indexed(locations))
+}
+
+/// Writes the indexes, and any summaries, for `input`.
+async fn write_outputs(
+    input: &Source,
+    output: Option<&Destination>,
+    bam_index: &BamIndex,
+    options: &RunOptions,
+    store_options: &StoreOptions,
+) -> Result<Vec<Option<url::Url>>> {
+    let mut locations = Vec::new();
+    for &part in bam_index.format.parts() {
+        let location = index_location(output, input, part.extension())?;
+        put_index(location.as_ref(), part, &bam_index.index, store_options).await?;
+        if let Some(url) = &location {
+            log::info!("Wrote index to {}", url);
+        }
+        locations.push(location);
+    }
+
+    let stats_parts = options.idxstats.map_or(&[][..], |format| format.parts());
+    if !stats_parts.is_empty() {
+        let stats = bam_index.idxstats();
+        for &part in stats_parts {
+            let location = index_location(output, input, part.extension())?;
+            put_bytes(location.as_ref(), &stats.encode(part)?, store_options).await?;
+            if let Some(url) = &location {
+                log::info!("Wrote idxstats to {}", url);
+            }
+            locations.push(location);
+        }
+    }
+
+    if options.flagstat {
+        let location = index_location(output, input, flagstat::EXTENSION)?;
+        let json = bam_index.flagstat.to_json()?;
+        put_bytes(location.as_ref(), &json, store_options).await?;
+        if let Some(url) = &location {
+            log::info!("Wrote flagstat to {}", url);
+        }
+        locations.push(location);
+    }
+
+    if let (Some(format), Some(coverage)) = (options.coverage, &bam_index.coverage) {
+        for &part in format.parts() {
+            let location = index_location(output, input, part.extension())?;
+            let bytes = coverage.encode(&bam_index.header, part)?;
+            put_bytes(location.as_ref(), &bytes, store_options).await?;
+            if let Some(url) = &location {
+                log::info!("Wrote coverage to {}", url);
+            }
+            locations.push(location);
+        }
+    }
+    Ok(locations)
+}
+
+async fn run_index(args: cli::IndexArgs) -> Result<()> {
+    let mut options = args.indexing.run_options();
+    options.checkpoint = args.checkpoint;
+    options.indexing.checkpoint_interval = args.checkpoint_interval;
+    options.tee = args.tee;
+    index_source(
+        &args.input,
+        args.output.as_ref(),
+        &options,
+        args.indexing.if_exists,
+        &args.store.store_options()?,
+    )
+    .await?;
+    Ok(())
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    if let Some(href) = std::env::args().nth(1) {
-        let url = url::Url::parse(&href)?;
-        let mut stream_reader = get_async_stream_reader(&url).await?;
-        let index = build_bam_index(&mut stream_reader).await?;
-
-        // Write as multi-part stream to other object store...
-        // Use local file system for example
-        let store = object_store::local::LocalFileSystem::new_with_prefix("./tmp")?;
-        let fname = format!("{}.bai", url.path_segments().unwrap().last().unwrap());
-        let path: object_store::path::Path = fname
-            .clone()
-            .try_into()
-            .context("Failed to convert filename into path object")?;
-        {
-            let (_id, mut writer) = store.put_multipart(&path).await?;
-            write_bam_index(&mut writer, &index).await?;
-            writer.flush().await?;
-            writer.shutdown().await?;
-        }
-        println!("Wrote index to ./tmp/{}", fname);
-        Ok(())
-    } else {
-        anyhow::bail!("No URL provided");
+async fn main() {
+    let cli = cli::Cli::parse();
+    env_logger::Builder::new()
+        .filter_level(cli.log_level())
+        // Messages can quote request URLs, which may be signed.
+        .format(|buf, record| {
+            let style = buf.default_level_style(record.level());
+            writeln!(
+                buf,
+                "[{style}{:<5}{style:#}] {}",
+                record.level(),
+                auth::redact_urls(&record.args().to_string())
+            )
+        })
+        .parse_default_env()
+        .init();
+    let result = match cli.command {
+        cli::Command::Index(args) => run_index(args).await,
+        cli::Command::Batch(args) => batch::run_batch(args).await,
+        cli::Command::Tabix(args) => tabix::run_tabix(args).await,
+        cli::Command::Faidx(args) => faidx::run_faidx(args).await,
+        cli::Command::Verify(args) => verify::run_verify(args)
+            .await
+            .map(|verdict| std::process::exit(verdict.exit_code())),
+        cli::Command::Serve(args) => serve::run_serve(args).await,
+        cli::Command::View(args) => view::run_view(args).await,
+    };
+    // As returning the error from `main` would print it, but redacted.
+    if let Err(e) = result {
+        eprintln!("Error: {}", auth::redact_urls(&format!("{:?}", e)));
+        if let Some(hint) = hint(&e) {
+            eprintln!("Hint: {}", hint);
+        }
+        std::process::exit(1);
+    }
+}
+
+/// The flag to fix `e` with, for errors the library words in terms of
+/// [`IndexOptions`].
+fn hint(e: &anyhow::Error) -> Option<&'static str> {
+    e.chain().find_map(|cause| match cause.downcast_ref() {
+        Some(stream_index::Error::UndeclaredSortOrder(_)) => {
+            Some("pass --allow-unsorted-header to index it anyway if the records are sorted")
+        }
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(s: &str) -> url::Url {
+        url::Url::parse(s).unwrap()
+    }
+
+    #[test]
+    fn destinations_are_stdout_directories_or_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let parse = |s: &str| s.parse::<Destination>().unwrap();
+
+        assert!(matches!(parse("-"), Destination::Stdout));
+        assert!(matches!(
+            parse("s3://bucket/indexes/"),
+            Destination::Directory(_)
+        ));
+        assert!(matches!(parse("out/"), Destination::Directory(_)));
+        // An existing directory, even without the slash.
+        let Destination::Directory(url) = parse(dir.path().to_str().unwrap()) else {
+            panic!("expected a directory")
+        };
+        assert!(url.path().ends_with('/'));
+        assert!(matches!(parse("s3://bucket/a.bai"), Destination::File(_)));
+        assert!(matches!(parse("a.bai"), Destination::File(_)));
+    }
+
+    #[test]
+    fn indexes_go_next_to_writable_inputs_or_where_they_are_sent() {
+        let cwd = parse_url_or_path(".").unwrap();
+        let s3 = Source::Url(url("s3://bucket/dir/a.bam"));
+        let https = Source::Url(url("https://example.com/dir/a.bam?sig=1"));
+        let location =
+            |destination, source: &Source| index_location(destination, source, "bai").unwrap();
+
+        assert_eq!(location(None, &s3), Some(url("s3://bucket/dir/a.bam.bai")));
+        assert_eq!(location(None, &https), Some(cwd.join("a.bam.bai").unwrap()));
+        assert_eq!(
+            location(None, &Source::Stdin),
+            Some(cwd.join("stdin.bai").unwrap())
+        );
+        assert_eq!(location(Some(&Destination::Stdout), &s3), None);
+
+        let directory = Destination::Directory(url("gs://bucket/indexes/"));
+        let expected = url("gs://bucket/indexes/a.bam.bai");
+        assert_eq!(location(Some(&directory), &https), Some(expected));
+        let file = Destination::File(url("file:///tmp/x.bai"));
+        assert_eq!(location(Some(&file), &s3), Some(url("file:///tmp/x.bai")));
+    }
+
+    #[tokio::test]
+    async fn existing_indexes_are_overwritten_skipped_or_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.bam.bai"), b"").unwrap();
+        let exists = Some(url::Url::from_file_path(dir.path().join("a.bam.bai")).unwrap());
+        let missing = Some(url::Url::from_file_path(dir.path().join("a.bam.csi")).unwrap());
+        let check = |locations: Vec<Option<url::Url>>, if_exists| async move {
+            check_existing(&locations, if_exists, &Default::default()).await
+        };
+
+        let all = vec![exists.clone()];
+        let some = vec![exists.clone(), missing.clone()];
+        let with_stdout = vec![exists.clone(), None];
+        for locations in [&all, &some, &with_stdout] {
+            let result = check(locations.clone(), IfExists::Overwrite).await;
+            assert_eq!(result.unwrap(), None);
+        }
+
+        // Skipped only if every index but those for stdout exists.
+        let skipped = check(all.clone(), IfExists::Skip).await.unwrap();
+        assert_eq!(skipped, exists);
+        let skipped = check(with_stdout.clone(), IfExists::Skip).await.unwrap();
+        assert_eq!(skipped, exists);
+        assert_eq!(check(some.clone(), IfExists::Skip).await.unwrap(), None);
+        assert_eq!(check(vec![None], IfExists::Skip).await.unwrap(), None);
+
+        // An error if any exists.
+        assert!(check(some, IfExists::Error).await.is_err());
+        let result = check(vec![missing, None], IfExists::Error).await;
+        assert_eq!(result.unwrap(), None);
     }
 }
@@ -0,0 +1,272 @@
+//! Streaming objects that reopen dropped connections where they left off.
+
+use std::{io, ops::Range, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use futures::{stream::BoxStream, StreamExt};
+use object_store::{path::Path, BackoffConfig, GetOptions, ObjectStore, RetryConfig};
+use tokio::io::AsyncRead;
+use tokio_util::io::StreamReader;
+
+use crate::Result;
+
+/// How often, and how patiently, to retry failed reads.
+#[derive(Clone, Debug)]
+pub struct RetryOptions {
+    /// Retries in a row before giving up; 0 fails on the first error.
+    pub max_retries: usize,
+    /// Wait before the first retry, doubling with each one after.
+    pub initial_backoff: Duration,
+    /// Longest wait between retries.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryOptions {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryOptions {
+    pub(crate) fn backoff(&self, retry: usize) -> Duration {
+        let factor = 1u32 << retry.min(16);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+
+    /// The same limits for the object store's own request retries.
+    pub fn retry_config(&self) -> RetryConfig {
+        RetryConfig {
+            backoff: BackoffConfig {
+                init_backoff: self.initial_backoff,
+                max_backoff: self.max_backoff,
+                base: 2.0,
+            },
+            max_retries: self.max_retries,
+            ..Default::default()
+        }
+    }
+}
+
+/// Errors worth reopening the object for. The client has already retried the
+/// request itself, so this is mostly connections dropped mid-body.
+fn is_transient(e: &object_store::Error) -> bool {
+    matches!(
+        e,
+        object_store::Error::Generic { .. } | object_store::Error::JoinError { .. }
+    )
+}
+
+/// An object read as a stream of chunks, reopened from the next unread byte
+/// after a transient error.
+struct Resumable {
+    url: url::Url,
+    store: Arc<dyn ObjectStore>,
+    path: Path,
+    /// The bytes left to read; unknown until the object is first opened.
+    range: Option<Range<usize>>,
+    /// Compared on every reopen to catch objects replaced mid-read.
+    e_tag: Option<String>,
+    stream: Option<BoxStream<'static, object_store::Result<Bytes>>>,
+    opened: bool,
+    options: RetryOptions,
+    /// Failures since the last chunk read.
+    retries: usize,
+    failed: bool,
+}
+
+impl Resumable {
+    async fn open(&mut self) -> object_store::Result<()> {
+        let get_options = GetOptions {
+            range: self.range.clone(),
+            ..Default::default()
+        };
+        let result = match self.store.get_opts(&self.path, get_options).await {
+            Ok(result) => result,
+            // A replaced object may be too short for the range, so check it
+            // hasn't changed before retrying.
+            Err(e) if self.opened => {
+                let meta = self.store.head(&self.path).await;
+                if let Ok(meta) = meta {
+                    self.check_e_tag(meta.e_tag.as_deref())?;
+                }
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        if !self.opened {
+            self.range.get_or_insert(0..result.meta.size);
+            self.e_tag = result.meta.e_tag.clone();
+            self.opened = true;
+        } else {
+            self.check_e_tag(result.meta.e_tag.as_deref())?;
+        }
+        self.stream = Some(result.into_stream());
+        Ok(())
+    }
+
+    fn check_e_tag(&self, e_tag: Option<&str>) -> object_store::Result<()> {
+        match self.e_tag.as_deref() {
+            Some(expected) if e_tag != Some(expected) => Err(object_store::Error::Precondition {
+                path: self.url.to_string(),
+                source: format!(
+                    "ETag changed from {} to {} while reading; the object was replaced",
+                    expected,
+                    e_tag.unwrap_or("none")
+                )
+                .into(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    async fn next_chunk(&mut self) -> Option<io::Result<Bytes>> {
+        loop {
+            if self.failed {
+                return None;
+            }
+            let error = match &mut self.stream {
+                None => match self.open().await {
+                    Ok(()) => continue,
+                    Err(e) => e,
+                },
+                Some(stream) => match stream.next().await {
+                    Some(Ok(bytes)) => {
+                        if let Some(range) = &mut self.range {
+                            range.start += bytes.len();
+                        }
+                        self.retries = 0;
+                        return Some(Ok(bytes));
+                    }
+                    Some(Err(e)) => e,
+                    None if self.range.as_ref().is_none_or(|range| range.is_empty()) => {
+                        return None
+                    }
+                    None => object_store::Error::Generic {
+                        store: "stream",
+                        source: "connection closed before the end of the object".into(),
+                    },
+                },
+            };
+
+            // Everything was read before the connection dropped.
+            if self.range.as_ref().is_some_and(|range| range.is_empty()) {
+                return None;
+            }
+            if !is_transient(&error) || self.retries >= self.options.max_retries {
+                self.failed = true;
+                return Some(Err(error.into()));
+            }
+            let backoff = self.options.backoff(self.retries);
+            self.retries += 1;
+            log::warn!(
+                "Reading {} failed at byte {} ({}); retrying in {:?} ({} of {})",
+                self.url,
+                self.range.as_ref().map_or(0, |range| range.start),
+                error,
+                backoff,
+                self.retries,
+                self.options.max_retries
+            );
+            tokio::time::sleep(backoff).await;
+            self.stream = None;
+        }
+    }
+}
+
+/// Streams `url`, or just `range` of it, reopening it with a range request
+/// from the next unread byte when the connection drops.
+///
+/// The object is opened before this returns, so e.g. a missing object fails
+/// here rather than on the first read.
+pub async fn open_resumable(
+    url: &url::Url,
+    store: Arc<dyn ObjectStore>,
+    path: Path,
+    range: Option<Range<usize>>,
+    options: &RetryOptions,
+) -> Result<impl AsyncRead + Unpin + Send> {
+    let mut resumable = Resumable {
+        url: url.clone(),
+        store,
+        path,
+        range,
+        e_tag: None,
+        stream: None,
+        opened: false,
+        options: options.clone(),
+        retries: 0,
+        failed: false,
+    };
+    resumable.open().await?;
+    let stream = futures::stream::unfold(resumable, |mut resumable| async move {
+        let chunk = resumable.next_chunk().await?;
+        Some((chunk, resumable))
+    });
+    // Fused, as `StreamReader` may poll again after the end.
+    Ok(StreamReader::new(Box::pin(stream.fuse())))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::{testing::TestStore, Error};
+
+    const OPTIONS: RetryOptions = RetryOptions {
+        max_retries: 2,
+        initial_backoff: Duration::ZERO,
+        max_backoff: Duration::ZERO,
+    };
+
+    fn data(len: usize) -> Bytes {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    async fn read(store: Arc<TestStore>, range: Option<Range<usize>>) -> Result<Vec<u8>> {
+        let url = url::Url::parse("memory:///object").unwrap();
+        let mut reader = open_resumable(&url, store, Path::from("object"), range, &OPTIONS).await?;
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).await?;
+        Ok(buf)
+    }
+
+    #[tokio::test]
+    async fn dropped_reads_resume_from_the_next_byte() {
+        let store = Arc::new(TestStore::default());
+        store
+            .put(&Path::from("object"), data(10_000))
+            .await
+            .unwrap();
+
+        store.drop_next_read(3000, None);
+        assert_eq!(read(store.clone(), None).await.unwrap(), data(10_000));
+        store.drop_next_read(3000, None);
+        let range = Some(1000..9000);
+        assert_eq!(read(store, range).await.unwrap(), data(10_000)[1000..9000]);
+    }
+
+    #[tokio::test]
+    async fn replaced_objects_fail_the_read() {
+        let store = Arc::new(TestStore::default());
+        store
+            .put(&Path::from("object"), data(10_000))
+            .await
+            .unwrap();
+
+        // Replaced by something as long, and by something too short for the
+        // rest of the range.
+        for replacement in [data(10_000).slice(1..).to_vec(), data(2000).to_vec()] {
+            store.drop_next_read(3000, Some(replacement.into()));
+            let e = read(store.clone(), None).await.unwrap_err();
+            let Error::Io(e) = e else { panic!("{:?}", e) };
+            assert!(e.to_string().contains("ETag changed"), "{}", e);
+        }
+    }
+}
@@ -0,0 +1,864 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    io::{Read, Write},
+    ops::Range,
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use futures::TryStreamExt;
+use hyper::{
+    header,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, StatusCode,
+};
+use noodles::{
+    bam,
+    bgzf::{self, VirtualPosition},
+    core::Position,
+    csi, sam,
+};
+use object_store::{path::Path, GetOptions, ObjectMeta, ObjectStore};
+use serde::Serialize;
+use tokio::sync::{Mutex, OnceCell};
+
+use stream_index::{
+    auth, bgzf_block_size, build_bam_index, create_writer, drs, get_async_stream_reader,
+    get_object_store, is_writable_scheme, merge_spans, write_index, IndexKind, IndexOptions,
+    Source, StoreOptions,
+};
+
+use crate::{
+    cli,
+    verify::{index_next_to, read_index},
+};
+
+const CONTENT_TYPE: &str = "application/vnd.ga4gh.htsget.v1.3.0+json";
+
+/// The most a BGZF block can take up, compressed.
+const MAX_BLOCK_SIZE: usize = 1 << 16;
+
+/// How many BAMs' headers and indexes are kept in memory.
+const CACHED_BAMS: usize = 64;
+
+/// An htsget error, sent as `{"htsget": {"error": ..., "message": ...}}`.
+#[derive(Debug)]
+struct HtsgetError {
+    status: StatusCode,
+    error: &'static str,
+    message: String,
+}
+
+impl HtsgetError {
+    fn new(status: StatusCode, error: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            error,
+            message: message.into(),
+        }
+    }
+
+    fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "NotFound", message)
+    }
+
+    fn invalid_input(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "InvalidInput", message)
+    }
+
+    fn invalid_range(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "InvalidRange", message)
+    }
+
+    fn into_response(self) -> Response<Body> {
+        let mut response = json_response(serde_json::json!({
+            "htsget": { "error": self.error, "message": self.message }
+        }));
+        *response.status_mut() = self.status;
+        response
+    }
+}
+
+impl From<anyhow::Error> for HtsgetError {
+    fn from(e: anyhow::Error) -> Self {
+        let not_found = e.chain().any(|cause| {
+            matches!(
+                cause.downcast_ref(),
+                Some(object_store::Error::NotFound { .. })
+            ) || matches!(
+                cause.downcast_ref(),
+                Some(stream_index::Error::ObjectStore(
+                    object_store::Error::NotFound { .. }
+                ))
+            )
+        });
+        let message = auth::redact_urls(&format!("{:#}", e));
+        if not_found {
+            return Self::not_found(message);
+        }
+        log::warn!("{}", message);
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "InternalError", message)
+    }
+}
+
+impl From<object_store::Error> for HtsgetError {
+    fn from(e: object_store::Error) -> Self {
+        anyhow::Error::from(e).into()
+    }
+}
+
+impl From<stream_index::Error> for HtsgetError {
+    fn from(e: stream_index::Error) -> Self {
+        anyhow::Error::from(e).into()
+    }
+}
+
+#[derive(Serialize)]
+struct Ticket {
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    headers: Option<HashMap<&'static str, String>>,
+    class: &'static str,
+}
+
+impl Ticket {
+    /// `range` of the object at `url`.
+    fn range(url: &url::Url, range: Range<u64>, class: &'static str) -> Self {
+        let bytes = format!("bytes={}-{}", range.start, range.end - 1);
+        Self {
+            url: url.to_string(),
+            headers: Some(HashMap::from([("Range", bytes)])),
+            class,
+        }
+    }
+
+    /// `data`, compressed as BGZF, inline.
+    fn data(data: &[u8], class: &'static str) -> Result<Self> {
+        let mut writer = bgzf::Writer::new(Vec::new());
+        writer.write_all(data)?;
+        let block = writer.finish()?;
+        Ok(Self {
+            url: format!(
+                "data:application/vnd.ga4gh.bam;base64,{}",
+                base64::engine::general_purpose::STANDARD.encode(block)
+            ),
+            headers: None,
+            class,
+        })
+    }
+}
+
+/// Where a BAM is, and what's needed to answer queries for it.
+struct Served {
+    store: Arc<dyn ObjectStore>,
+    path: Path,
+    size: u64,
+    /// The object as it was when it was loaded.
+    meta: ObjectMeta,
+    /// Where clients fetch the BAM's bytes from.
+    ticket_url: url::Url,
+    header: sam::Header,
+    /// Where the first record starts.
+    header_end: VirtualPosition,
+    index: csi::Index,
+}
+
+impl Served {
+    /// Whether the object is still the one that was loaded.
+    async fn is_current(&self) -> Result<bool> {
+        Ok(self.store.head(&self.path).await? == self.meta)
+    }
+
+    /// The block at `offset`, inflated, and its compressed size.
+    async fn read_block(&self, offset: u64) -> Result<(Vec<u8>, u64)> {
+        let start = offset as usize;
+        let end = (start + MAX_BLOCK_SIZE).min(self.size as usize);
+        let buf = self.store.get_range(&self.path, start..end).await?;
+        let size = bgzf_block_size(&buf)
+            .with_context(|| format!("No BGZF block starts at byte {}", offset))?;
+        let mut data = Vec::new();
+        bgzf::Reader::new(buf.get(..size).context("Truncated BGZF block")?)
+            .read_to_end(&mut data)?;
+        Ok((data, size as u64))
+    }
+
+    /// Tickets for the records from `start` up to `end`, or the end of the
+    /// file. Whole blocks are fetched from the BAM; the parts of blocks the
+    /// span starts or ends inside, which other records (or the header) share,
+    /// are sent inline.
+    async fn tickets(
+        &self,
+        start: VirtualPosition,
+        end: Option<VirtualPosition>,
+        class: &'static str,
+    ) -> Result<Vec<Ticket>> {
+        let end = end.unwrap_or(VirtualPosition::try_from((self.size, 0))?);
+        let (start_offset, end_offset) = (start.compressed(), end.compressed());
+        let (start_within, end_within) = (
+            usize::from(start.uncompressed()),
+            usize::from(end.uncompressed()),
+        );
+        let mut tickets = Vec::new();
+        if start_offset == end_offset {
+            if start_within < end_within {
+                let (data, _) = self.read_block(start_offset).await?;
+                tickets.push(Ticket::data(&data[start_within..end_within], class)?);
+            }
+            return Ok(tickets);
+        }
+        let mut whole_blocks = start_offset..end_offset;
+        if start_within > 0 {
+            let (data, size) = self.read_block(start_offset).await?;
+            tickets.push(Ticket::data(&data[start_within..], class)?);
+            whole_blocks.start += size;
+        }
+        if !whole_blocks.is_empty() {
+            tickets.push(Ticket::range(&self.ticket_url, whole_blocks, class));
+        }
+        if end_within > 0 {
+            let (data, _) = self.read_block(end_offset).await?;
+            tickets.push(Ticket::data(&data[..end_within], class)?);
+        }
+        Ok(tickets)
+    }
+}
+
+/// The parameters of a reads request that narrow what's returned. `fields`,
+/// `tags` and `notags` are accepted but ignored, as the spec allows.
+struct ReadsQuery {
+    header_only: bool,
+    reference_name: Option<String>,
+    start: Option<u64>,
+    end: Option<u64>,
+}
+
+impl ReadsQuery {
+    fn parse(query: Option<&str>) -> Result<Self, HtsgetError> {
+        let mut reads_query = ReadsQuery {
+            header_only: false,
+            reference_name: None,
+            start: None,
+            end: None,
+        };
+        let parse_position = |name, value: &str| {
+            value.parse().map_err(|_| {
+                HtsgetError::invalid_input(format!("{} must be a non-negative integer", name))
+            })
+        };
+        for (name, value) in url::form_urlencoded::parse(query.unwrap_or("").as_bytes()) {
+            match &*name {
+                "format" if value != "BAM" => {
+                    return Err(HtsgetError::new(
+                        StatusCode::BAD_REQUEST,
+                        "UnsupportedFormat",
+                        format!("Only BAM is served, not {}", value),
+                    ))
+                }
+                "class" if value == "header" => reads_query.header_only = true,
+                "class" => return Err(HtsgetError::invalid_input("class must be header")),
+                "referenceName" => reads_query.reference_name = Some(value.into_owned()),
+                "start" => reads_query.start = Some(parse_position("start", &value)?),
+                "end" => reads_query.end = Some(parse_position("end", &value)?),
+                _ => {}
+            }
+        }
+        let has_range = reads_query.start.is_some() || reads_query.end.is_some();
+        match reads_query.reference_name.as_deref() {
+            None | Some("*") if has_range => Err(HtsgetError::invalid_input(
+                "start and end need a referenceName other than *",
+            )),
+            _ if reads_query.header_only && reads_query.reference_name.is_some() => Err(
+                HtsgetError::invalid_input("class=header can't be combined with a region"),
+            ),
+            _ => match (reads_query.start.unwrap_or(0), reads_query.end) {
+                (start, Some(end)) if start >= end => Err(HtsgetError::invalid_range(format!(
+                    "start {} isn't before end {}",
+                    start, end
+                ))),
+                _ => Ok(reads_query),
+            },
+        }
+    }
+}
+
+/// A BAM, once it's loaded.
+type Cell = Arc<OnceCell<Arc<Served>>>;
+
+/// The BAMs loaded, or being loaded, by ID.
+#[derive(Default)]
+struct Cache {
+    /// Each ID's cell, and when it was last used.
+    entries: HashMap<String, (u64, Cell)>,
+    uses: u64,
+}
+
+impl Cache {
+    /// The cell for `id`, added if it's not there, in place of the least
+    /// recently used one if the cache is full.
+    fn cell(&mut self, id: &str) -> Cell {
+        if !self.entries.contains_key(id) && self.entries.len() >= CACHED_BAMS {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.uses += 1;
+        let entry = self.entries.entry(id.to_string()).or_default();
+        entry.0 = self.uses;
+        entry.1.clone()
+    }
+
+    /// Forgets `id`, unless it's been replaced by another cell already.
+    fn remove(&mut self, id: &str, cell: &Cell) {
+        if let Some((_, cached)) = self.entries.get(id) {
+            if Arc::ptr_eq(cached, cell) {
+                self.entries.remove(id);
+            }
+        }
+    }
+}
+
+/// Serves the BAMs under `root`, indexing each on its first request unless
+/// there's an index next to it.
+struct Server {
+    root: url::Url,
+    public_url: url::Url,
+    options: IndexOptions,
+    store_options: StoreOptions,
+    served: Mutex<Cache>,
+}
+
+impl Server {
+    /// `<root>/<id>.bam`, as long as `id` doesn't lead out of `root`.
+    fn object_url(&self, id: &str) -> Result<url::Url, HtsgetError> {
+        let invalid = || HtsgetError::invalid_input(format!("Invalid ID {:?}", id));
+        if id.is_empty() {
+            return Err(invalid());
+        }
+        let url = self
+            .root
+            .join(&format!("./{}.bam", id))
+            .map_err(|_| invalid())?;
+        match url.as_str().starts_with(self.root.as_str()) {
+            true => Ok(url),
+            false => Err(invalid()),
+        }
+    }
+
+    /// Clients can fetch public HTTP(S) objects themselves; anything else is
+    /// served from `/data/`.
+    fn ticket_url(&self, id: &str, url: &url::Url) -> Result<url::Url> {
+        let direct = matches!(url.scheme(), "http" | "https")
+            && url.username().is_empty()
+            && url.password().is_none()
+            && self.store_options.http_auth.headers_for(url)?.is_empty();
+        match direct {
+            true => Ok(url.clone()),
+            false => Ok(self.public_url.join(&format!("data/{}", id))?),
+        }
+    }
+
+    /// The BAM `id`, loaded on its first request, and again if the object
+    /// has changed since.
+    async fn served(&self, id: &str) -> Result<Arc<Served>, HtsgetError> {
+        let url = self.object_url(id)?;
+        self.cached(id, &url)
+            .await
+            .map_err(|e| match HtsgetError::from(e) {
+                e if e.status == StatusCode::NOT_FOUND => {
+                    HtsgetError::not_found(format!("No BAM with ID {:?}", id))
+                }
+                e => e,
+            })
+    }
+
+    async fn cached(&self, id: &str, url: &url::Url) -> Result<Arc<Served>> {
+        let mut cell = self.served.lock().await.cell(id);
+        if let Some(served) = cell.get() {
+            if served.is_current().await? {
+                return Ok(served.clone());
+            }
+            log::info!("{} has changed; reloading it", id);
+            let mut cache = self.served.lock().await;
+            cache.remove(id, &cell);
+            cell = cache.cell(id);
+        }
+        let served = cell
+            .get_or_try_init(|| async { self.load(id, url).await.map(Arc::new) })
+            .await?;
+        Ok(served.clone())
+    }
+
+    async fn load(&self, id: &str, url: &url::Url) -> Result<Served> {
+        let (read_url, read_options) = drs::resolve(url, &self.store_options).await?;
+        let (store, path) = get_object_store(&read_url, &read_options)?;
+        let store: Arc<dyn ObjectStore> = Arc::from(store);
+        let meta = store.head(&path).await?;
+        let size = meta.size as u64;
+
+        let source = Source::Url(url.clone());
+        let reader = get_async_stream_reader(&source, &self.store_options).await?;
+        let mut reader = bam::AsyncReader::new(reader);
+        let header: sam::Header = reader.read_header().await?.parse()?;
+        reader.read_reference_sequences().await?;
+        let header_end = reader.virtual_position();
+        drop(reader);
+
+        let index = match self.find_index(url).await? {
+            Some(index) => index,
+            None => self.build_index(&source).await?,
+        };
+        Ok(Served {
+            store,
+            path,
+            size,
+            meta,
+            ticket_url: self.ticket_url(id, url)?,
+            header,
+            header_end,
+            index,
+        })
+    }
+
+    /// Reads `<url>.bai`, or `<url>.csi` if there's no `.bai`.
+    async fn find_index(&self, url: &url::Url) -> Result<Option<csi::Index>> {
+        let Some(location) = index_next_to(url, &self.store_options).await? else {
+            return Ok(None);
+        };
+        let index = read_index(&location, &self.store_options).await?;
+        log::info!("Using {}", auth::redact_url(&location));
+        Ok(Some(index))
+    }
+
+    /// Indexes `source`, writing the index next to it if that's writable.
+    async fn build_index(&self, source: &Source) -> Result<csi::Index> {
+        log::info!("Indexing {}", source);
+        let mut reader = get_async_stream_reader(source, &self.store_options).await?;
+        let bam_index = build_bam_index(&mut reader, &self.options).await?;
+        if let Source::Url(url) = source {
+            if is_writable_scheme(url.scheme()) {
+                for &kind in bam_index.format.parts() {
+                    let mut location = url.clone();
+                    location.set_path(&format!("{}.{}", url.path(), kind.extension()));
+                    match self.write_index(&location, kind, &bam_index.index).await {
+                        Ok(()) => log::info!("Wrote index to {}", location),
+                        Err(e) => log::warn!("Failed to write index to {}: {:#}", location, e),
+                    }
+                }
+            }
+        }
+        Ok(bam_index.index)
+    }
+
+    async fn write_index(
+        &self,
+        location: &url::Url,
+        kind: IndexKind,
+        index: &csi::Index,
+    ) -> Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        let mut writer = create_writer(Some(location), &self.store_options).await?;
+        write_index(&mut writer, kind, index).await?;
+        writer.shutdown().await?;
+        Ok(())
+    }
+
+    /// Answers `GET /reads/<id>` with the URLs holding the records asked for.
+    async fn reads(&self, id: &str, query: Option<&str>) -> Result<serde_json::Value, HtsgetError> {
+        let query = ReadsQuery::parse(query)?;
+        let served = self.served(id).await?;
+        let mut urls = served
+            .tickets(
+                VirtualPosition::default(),
+                Some(served.header_end),
+                "header",
+            )
+            .await?;
+        if query.header_only {
+            return Ok(htsget_response(urls));
+        }
+        match query.reference_name.as_deref() {
+            None => urls.extend(served.tickets(served.header_end, None, "body").await?),
+            Some("*") => {
+                let start = served
+                    .index
+                    .first_record_in_last_linear_bin_start_position()
+                    .unwrap_or(served.header_end);
+                urls.extend(served.tickets(start, None, "body").await?);
+            }
+            Some(name) => {
+                let (id, _, reference_sequence) = served
+                    .header
+                    .reference_sequences()
+                    .get_full(name)
+                    .ok_or_else(|| {
+                        HtsgetError::not_found(format!("No reference sequence named {}", name))
+                    })?;
+                let length = usize::from(reference_sequence.length()) as u64;
+                let start = query.start.unwrap_or(0);
+                if start >= length {
+                    return Err(HtsgetError::invalid_range(format!(
+                        "start {} is past the end of {}, which is {} bp long",
+                        start, name, length
+                    )));
+                }
+                // htsget is 0-based and half-open; noodles is 1-based.
+                let position = |n: u64| Position::try_from(n as usize + 1).unwrap_or(Position::MIN);
+                let start = position(start);
+                let chunks = match query.end {
+                    Some(end) => served
+                        .index
+                        .query(id, start..=position(end.min(length) - 1)),
+                    None => served.index.query(id, start..),
+                }
+                .map_err(anyhow::Error::from)?;
+                for span in merge_spans(chunks) {
+                    urls.extend(
+                        served
+                            .tickets(span.start(), Some(span.end()), "body")
+                            .await?,
+                    );
+                }
+                // The chunks stop short of the file's own EOF block.
+                urls.push(Ticket::data(&[], "body")?);
+            }
+        }
+        Ok(htsget_response(urls))
+    }
+
+    /// Answers `GET /data/<id>`, streaming the BAM, or the part of it the
+    /// `Range` header asks for.
+    async fn data(&self, id: &str, range: Option<&str>) -> Result<Response<Body>, HtsgetError> {
+        let served = self.served(id).await?;
+        let size = served.size as usize;
+        let range = match range {
+            Some(range) => Some(parse_range(range, size).ok_or_else(|| {
+                HtsgetError::invalid_range(format!("Unsupported Range {:?}", range))
+            })?),
+            None => None,
+        };
+        let mut response = Response::builder().header(header::ACCEPT_RANGES, "bytes");
+        match &range {
+            Some(range) if range.is_empty() => {
+                let message = format!("The BAM is only {} bytes long", size);
+                let mut response =
+                    HtsgetError::new(StatusCode::RANGE_NOT_SATISFIABLE, "InvalidRange", message)
+                        .into_response();
+                let content_range = format!("bytes */{}", size).parse().expect("valid header");
+                response
+                    .headers_mut()
+                    .insert(header::CONTENT_RANGE, content_range);
+                return Ok(response);
+            }
+            Some(range) => {
+                response = response.status(StatusCode::PARTIAL_CONTENT).header(
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", range.start, range.end - 1, size),
+                );
+            }
+            None => {}
+        }
+        let options = GetOptions {
+            range,
+            ..Default::default()
+        };
+        let result = served.store.get_opts(&served.path, options).await?;
+        let body = Body::wrap_stream(result.into_stream().map_err(std::io::Error::from));
+        Ok(response.body(body).expect("valid response"))
+    }
+
+    async fn handle(&self, request: Request<Body>) -> Response<Body> {
+        let path = request.uri().path().to_string();
+        let query = request.uri().query().map(str::to_string);
+        let result = match (request.method(), path.split_once('/').map(|(_, rest)| rest)) {
+            (&Method::GET, Some("reads/service-info")) => Ok(json_response(service_info())),
+            (&Method::GET, Some(rest)) if rest.starts_with("reads/") => self
+                .reads(&rest["reads/".len()..], query.as_deref())
+                .await
+                .map(json_response),
+            (&Method::GET, Some(rest)) if rest.starts_with("data/") => {
+                let range = request
+                    .headers()
+                    .get(header::RANGE)
+                    .and_then(|range| range.to_str().ok());
+                self.data(&rest["data/".len()..], range).await
+            }
+            (&Method::GET, _) => Err(HtsgetError::not_found(format!("No such endpoint {}", path))),
+            (method, _) => Err(HtsgetError::new(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "InvalidInput",
+                format!("{} isn't supported", method),
+            )),
+        };
+        let response = result.unwrap_or_else(HtsgetError::into_response);
+        log::debug!("{} {} {}", request.method(), path, response.status());
+        response
+    }
+}
+
+fn htsget_response(urls: Vec<Ticket>) -> serde_json::Value {
+    serde_json::json!({ "htsget": { "format": "BAM", "urls": urls } })
+}
+
+fn json_response(body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .header(header::CONTENT_TYPE, CONTENT_TYPE)
+        .body(Body::from(body.to_string()))
+        .expect("valid response")
+}
+
+fn service_info() -> serde_json::Value {
+    serde_json::json!({
+        "id": "stream-index",
+        "name": "stream-index",
+        "type": { "group": "org.ga4gh", "artifact": "htsget", "version": "1.3.0" },
+        "version": env!("CARGO_PKG_VERSION"),
+        "htsget": {
+            "datatype": "reads",
+            "formats": ["BAM"],
+            "fieldsParametersEffective": false,
+            "tagsParametersEffective": false,
+        },
+    })
+}
+
+/// `bytes=<first>-<last>`, `bytes=<first>-` or `bytes=-<length>`, as a
+/// half-open range of an object `size` bytes long. Ranges running past the
+/// end are cut short there, so one starting past it is empty.
+fn parse_range(range: &str, size: usize) -> Option<Range<usize>> {
+    let (first, last) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let range = match (first, last) {
+        ("", length) => size.saturating_sub(length.parse().ok()?)..size,
+        (first, "") => first.parse().ok()?..size,
+        (first, last) => {
+            let (first, last): (usize, usize) = (first.parse().ok()?, last.parse().ok()?);
+            match first <= last {
+                true => first..last.saturating_add(1),
+                false => return None,
+            }
+        }
+    };
+    Some(range.start.min(size)..range.end.min(size))
+}
+
+pub async fn run_serve(args: cli::ServeArgs) -> Result<()> {
+    let mut root = args.root;
+    if !root.path().ends_with('/') {
+        root.set_path(&format!("{}/", root.path()));
+    }
+    let public_url = match args.public_url {
+        Some(url) => url,
+        None => url::Url::parse(&format!("http://{}/", args.listen))?,
+    };
+    let server = Arc::new(Server {
+        root,
+        public_url,
+        options: IndexOptions {
+            csi_fallback: true,
+            ..args.reindex.index_options()
+        },
+        store_options: args.store.store_options()?,
+        served: Mutex::default(),
+    });
+
+    let make_service = make_service_fn(move |_| {
+        let server = server.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let server = server.clone();
+                async move { Ok::<_, Infallible>(server.handle(request).await) }
+            }))
+        }
+    });
+    let listener = hyper::Server::try_bind(&args.listen)
+        .with_context(|| format!("Failed to listen on {}", args.listen))?
+        .serve(make_service);
+    log::info!(
+        "Serving htsget reads on http://{}/reads/",
+        listener.local_addr()
+    );
+    listener
+        .with_graceful_shutdown(async {
+            tokio::signal::ctrl_c().await.ok();
+        })
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{bam_from_sam, many_records};
+
+    /// A server for a temporary directory holding `sample.bam`.
+    fn server(bam: &[u8]) -> (tempfile::TempDir, Server) {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("sample.bam"), bam).unwrap();
+        let server = Server {
+            root: url::Url::from_directory_path(dir.path()).unwrap(),
+            public_url: url::Url::parse("http://htsget.example/").unwrap(),
+            options: Default::default(),
+            store_options: Default::default(),
+            served: Mutex::default(),
+        };
+        (dir, server)
+    }
+
+    /// What a client fetching `response`'s tickets gets: the BAM's header
+    /// and the names of its records.
+    async fn fetch(bam: &[u8], response: &serde_json::Value) -> (String, Vec<String>) {
+        assert_eq!(response["htsget"]["format"], "BAM");
+        let mut buf = Vec::new();
+        for ticket in response["htsget"]["urls"].as_array().unwrap() {
+            let url = ticket["url"].as_str().unwrap();
+            match url.strip_prefix("data:application/vnd.ga4gh.bam;base64,") {
+                Some(data) => {
+                    let data = base64::engine::general_purpose::STANDARD.decode(data);
+                    buf.extend(data.unwrap());
+                }
+                None => {
+                    assert_eq!(url, "http://htsget.example/data/sample");
+                    let range = ticket["headers"]["Range"].as_str().unwrap();
+                    let range = parse_range(range, bam.len()).unwrap();
+                    buf.extend(&bam[range]);
+                }
+            }
+        }
+        let mut reader = bam::AsyncReader::new(&buf[..]);
+        let header_text = reader.read_header().await.unwrap();
+        let header: sam::Header = header_text.parse().unwrap();
+        reader.read_reference_sequences().await.unwrap();
+        let mut names = Vec::new();
+        let mut record = sam::alignment::Record::default();
+        while reader.read_record(&header, &mut record).await.unwrap() != 0 {
+            names.push(record.read_name().unwrap().to_string());
+        }
+        (header_text, names)
+    }
+
+    #[tokio::test]
+    async fn tickets_assemble_into_the_records_asked_for() {
+        let sam = many_records(4000);
+        let bam = bam_from_sam(&sam);
+        let (_dir, server) = server(&bam);
+        let header = sam.lines().take_while(|line| line.starts_with('@'));
+        let header: String = header.map(|line| format!("{}\n", line)).collect();
+
+        let response = server.reads("sample", Some("class=header")).await.unwrap();
+        assert_eq!(fetch(&bam, &response).await, (header.clone(), vec![]));
+
+        // Every record overlapping the region, and perhaps some around it
+        // that share its blocks.
+        let query = "referenceName=chr2&start=1000&end=2000";
+        let response = server.reads("sample", Some(query)).await.unwrap();
+        let (text, names) = fetch(&bam, &response).await;
+        assert_eq!(text, header);
+        for i in 2000 + 451..2000 + 1000 {
+            assert!(names.contains(&format!("r{}", i)), "r{} is missing", i);
+        }
+        assert!(names.len() < 4000, "{} records", names.len());
+
+        let response = server
+            .reads("sample", Some("referenceName=*"))
+            .await
+            .unwrap();
+        let (_, names) = fetch(&bam, &response).await;
+        assert!(names.ends_with(&["u0".into(), "u1".into(), "u2".into()]));
+    }
+
+    /// The status, `Content-Range` and body of `GET /data/sample`.
+    async fn get_data(server: &Server, range: Option<&str>) -> (u16, Option<String>, Vec<u8>) {
+        let response = match server.data("sample", range).await {
+            Ok(response) => response,
+            Err(e) => e.into_response(),
+        };
+        let content_range = response
+            .headers()
+            .get(header::CONTENT_RANGE)
+            .map(|value| value.to_str().unwrap().to_string());
+        let status = response.status().as_u16();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, content_range, body.to_vec())
+    }
+
+    #[tokio::test]
+    async fn data_is_served_whole_or_in_the_range_asked_for() {
+        let bam = bam_from_sam(&many_records(100));
+        let (_dir, server) = server(&bam);
+        let n = bam.len();
+
+        assert_eq!(get_data(&server, None).await, (200, None, bam.clone()));
+        for (range, expected) in [
+            ("bytes=10-19", 10..20),
+            ("bytes=10-", 10..n),
+            ("bytes=-10", n - 10..n),
+            ("bytes=10-999999", 10..n),
+            ("bytes=-999999", 0..n),
+        ] {
+            let content_range = format!("bytes {}-{}/{}", expected.start, expected.end - 1, n);
+            assert_eq!(
+                get_data(&server, Some(range)).await,
+                (206, Some(content_range), bam[expected].to_vec()),
+                "{}",
+                range
+            );
+        }
+
+        for range in [format!("bytes={}-", n), "bytes=-0".to_string()] {
+            let (status, content_range, body) = get_data(&server, Some(&range)).await;
+            assert_eq!(status, 416, "{}", range);
+            assert_eq!(content_range, Some(format!("bytes */{}", n)));
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body["htsget"]["error"], "InvalidRange");
+        }
+        for range in ["bytes=20-10", "items=0-10", "bytes=a-", "bytes=0-1,5-6"] {
+            let (status, _, _) = get_data(&server, Some(range)).await;
+            assert_eq!(status, 400, "{}", range);
+        }
+    }
+
+    #[tokio::test]
+    async fn changed_bams_are_reloaded() {
+        let (dir, server) = server(&bam_from_sam(&many_records(100)));
+        let first = server.served("sample").await.unwrap();
+        assert!(Arc::ptr_eq(&first, &server.served("sample").await.unwrap()));
+
+        let bam = bam_from_sam(&many_records(200));
+        std::fs::write(dir.path().join("sample.bam"), &bam).unwrap();
+        let second = server.served("sample").await.unwrap();
+        assert_eq!(second.size, bam.len() as u64);
+        assert_eq!(get_data(&server, None).await.2, bam);
+
+        std::fs::remove_file(dir.path().join("sample.bam")).unwrap();
+        let e = server.served("sample").await.err().unwrap();
+        assert_eq!(e.status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn the_least_recently_used_bams_are_dropped() {
+        let mut cache = Cache::default();
+        let first = cache.cell("0");
+        for i in 1..CACHED_BAMS {
+            cache.cell(&i.to_string());
+        }
+        assert!(Arc::ptr_eq(&first, &cache.cell("0")));
+
+        cache.cell("new");
+        assert_eq!(cache.entries.len(), CACHED_BAMS);
+        assert!(!cache.entries.contains_key("1"));
+        assert!(Arc::ptr_eq(&first, &cache.cell("0")));
+
+        let replaced = cache.cell("new");
+        cache.remove("new", &replaced);
+        cache.remove("0", &Cell::default());
+        assert!(!cache.entries.contains_key("new"));
+        assert!(cache.entries.contains_key("0"));
+    }
+}
@@ -0,0 +1,574 @@
+//! Indexing byte ranges of a BAM concurrently and merging the results.
+
+use std::{collections::HashMap, io::BufRead, num::NonZeroUsize, sync::Arc};
+
+use noodles::{
+    bgzf::{self, VirtualPosition},
+    csi::{
+        self,
+        index::{
+            reference_sequence::{bin::Chunk, Bin, Metadata},
+            ReferenceSequence,
+        },
+    },
+    sam,
+};
+use object_store::{path::Path, ObjectStore};
+use tokio::io::AsyncRead;
+
+use crate::{
+    bam_stream_reader, bam_stream_reader_at, bgzf_block_size, coverage::CoverageOptions, drs,
+    finish_index, format_position, get_object_store, index_records, read_bam_header,
+    resume::open_resumable, BamIndex, Error, IndexOptions, RecordSpan, Result, StoreOptions,
+    BGZF_MAGIC,
+};
+
+/// How much of each part to fetch when looking for its first record. Enough
+/// for a few BGZF blocks, which hold at most 64 KiB each.
+const PROBE_LENGTH: usize = 256 * 1024;
+
+/// Where the BGZF block at `i` ends, if a block header starts there.
+fn block_end(buf: &[u8], i: usize) -> Option<usize> {
+    Some(i + bgzf_block_size(buf.get(i..)?)?)
+}
+
+/// Finds the first BGZF block in `buf`, which starts at `offset` in an object
+/// of `size` bytes. A candidate only counts if another block or the end of the
+/// object follows it.
+fn find_block_start(buf: &[u8], offset: u64, size: u64) -> Option<usize> {
+    (0..buf.len()).find(|&i| {
+        block_end(buf, i).is_some_and(|end| {
+            offset + end as u64 == size || buf.get(end..end + 4) == Some(&BGZF_MAGIC[..])
+        })
+    })
+}
+
+fn read_i32(buf: &[u8], i: usize) -> i32 {
+    i32::from_le_bytes(buf[i..i + 4].try_into().unwrap())
+}
+
+/// Complete records that have to check out in a row before a guess is
+/// trusted.
+const MIN_RECORD_CHAIN: usize = 4;
+
+/// Where the next record would start if a plausible one starts at `i`, or
+/// `None`. Records cut off by the end of `buf` are checked as far as they go.
+fn check_record(buf: &[u8], i: usize, header: &sam::Header) -> Option<usize> {
+    let fields = buf.get(i..i + 36)?;
+    let reference_sequences = header.reference_sequences();
+    let reference_sequence = |id: i32| match id {
+        -1 => Some(None),
+        _ => reference_sequences
+            .get_index(usize::try_from(id).ok()?)
+            .map(Some),
+    };
+
+    let block_size = usize::try_from(read_i32(fields, 0)).ok()?;
+    let reference_sequence_id = read_i32(fields, 4);
+    let position = read_i32(fields, 8);
+    let read_name_len = usize::from(fields[12]);
+    let cigar_op_count = usize::from(u16::from_le_bytes([fields[16], fields[17]]));
+    let sequence_len = usize::try_from(read_i32(fields, 20)).ok()?;
+    let mate_reference_sequence_id = read_i32(fields, 24);
+    let mate_position = read_i32(fields, 28);
+
+    match reference_sequence(reference_sequence_id)? {
+        None if position != -1 => return None,
+        Some((_, map)) if position < 0 || position as usize >= usize::from(map.length()) => {
+            return None
+        }
+        _ => {}
+    }
+    reference_sequence(mate_reference_sequence_id)?;
+    if mate_position < -1 || read_name_len == 0 {
+        return None;
+    }
+    let fixed_len =
+        32 + read_name_len + 4 * cigar_op_count + sequence_len.div_ceil(2) + sequence_len;
+    if block_size < fixed_len {
+        return None;
+    }
+
+    let end = i + 4 + block_size;
+    let read_name = &buf[i + 36..(i + 36 + read_name_len).min(buf.len())];
+    let (last, name) = read_name.split_last()?;
+    let complete = read_name.len() == read_name_len;
+    if (complete && *last != 0) || !name.iter().all(|&b| (b'!'..=b'~').contains(&b)) {
+        return None;
+    }
+    let cigar_start = i + 36 + read_name_len;
+    let cigar = buf.get(cigar_start..(cigar_start + 4 * cigar_op_count).min(buf.len()))?;
+    // Op kinds are 0..=8 (MIDNSHP=X).
+    if cigar.chunks_exact(4).any(|op| op[0] & 0x0f > 8) {
+        return None;
+    }
+    Some(end)
+}
+
+/// Whether a run of plausible records starts at `i`: at least
+/// `MIN_RECORD_CHAIN` complete ones, or every one up to the end of the object
+/// when `buf` reaches it.
+fn is_record_start(buf: &[u8], mut i: usize, at_eof: bool, header: &sam::Header) -> bool {
+    let mut complete = 0;
+    while complete < MIN_RECORD_CHAIN {
+        match check_record(buf, i, header) {
+            Some(next) if next <= buf.len() => {
+                complete += 1;
+                i = next;
+            }
+            _ => return at_eof && complete > 0 && i == buf.len(),
+        }
+    }
+    true
+}
+
+/// Guesses the virtual position of the first record starting in the blocks
+/// after `offset`. Wrong guesses are caught when the parts are indexed.
+async fn find_record_start(
+    store: &dyn ObjectStore,
+    path: &Path,
+    offset: u64,
+    size: u64,
+    header: &sam::Header,
+) -> Result<Option<VirtualPosition>> {
+    let end = (offset + PROBE_LENGTH as u64).min(size);
+    let buf = store.get_range(path, offset as usize..end as usize).await?;
+    let Some(block_start) = find_block_start(&buf, offset, size) else {
+        return Ok(None);
+    };
+
+    // Inflate the whole blocks in the probe, noting where each one starts.
+    let mut reader = bgzf::Reader::new(&buf[block_start..]);
+    let mut data = Vec::new();
+    let mut blocks = Vec::new();
+    while let Ok(len) = reader.fill_buf().map(|block| block.len()) {
+        if len == 0 {
+            break;
+        }
+        // The block was just read, so this points at its start.
+        blocks.push((reader.virtual_position().compressed(), data.len()));
+        data.extend_from_slice(reader.fill_buf()?);
+        reader.consume(len);
+    }
+
+    let Some(i) = (0..data.len()).find(|&i| is_record_start(&data, i, end == size, header)) else {
+        return Ok(None);
+    };
+    let &(compressed, uncompressed) = blocks
+        .iter()
+        .rev()
+        .find(|(_, start)| *start <= i)
+        .expect("the first block starts at 0");
+    let compressed = offset + block_start as u64 + compressed;
+    Ok(Some(VirtualPosition::try_from((
+        compressed,
+        (i - uncompressed) as u16,
+    ))?))
+}
+
+/// Indexes the records from `start`, the virtual position `reader` starts
+/// at, until the record at `until`.
+async fn index_part<R: AsyncRead + Unpin>(
+    reader: R,
+    header: Arc<sam::Header>,
+    threads: Option<NonZeroUsize>,
+    (min_shift, depth): (u8, u8),
+    (start, until): (VirtualPosition, Option<VirtualPosition>),
+    coverage: Option<CoverageOptions>,
+) -> Result<(RecordSpan, csi::Index)> {
+    let mut bam_reader = bam_stream_reader_at(reader, threads, start).await?;
+    let mut indexer = csi::index::Indexer::new(min_shift, depth);
+    let span = index_records(
+        &mut bam_reader,
+        &header,
+        &mut indexer,
+        start.compressed(),
+        until,
+        coverage,
+        None,
+    )
+    .await?;
+    Ok((span, finish_index(indexer, &header, min_shift, depth)))
+}
+
+/// Merges a reference sequence's parts, in file order, into what indexing it
+/// in one pass would give.
+fn merge_reference_sequences(parts: &[&ReferenceSequence]) -> ReferenceSequence {
+    let mut bins: HashMap<usize, (VirtualPosition, Vec<Chunk>)> = HashMap::new();
+    for part in parts {
+        for (&id, bin) in part.bins() {
+            let (loffset, chunks) = bins.entry(id).or_insert((VirtualPosition::MAX, Vec::new()));
+            *loffset = (*loffset).min(bin.loffset());
+            for &chunk in bin.chunks() {
+                // As `Bin` building: chunks that touch are joined.
+                match chunks.last_mut() {
+                    Some(last) if chunk.start() <= last.end() => {
+                        *last = Chunk::new(last.start(), chunk.end());
+                    }
+                    _ => chunks.push(chunk),
+                }
+            }
+        }
+    }
+    // A bin's loffset covers its descendants, which may be in other parts.
+    let loffsets: Vec<_> = bins
+        .iter()
+        .map(|(&id, (loffset, _))| (id, *loffset))
+        .collect();
+    for (mut id, loffset) in loffsets {
+        while id > 0 {
+            id = (id - 1) / 8;
+            if let Some((parent_loffset, _)) = bins.get_mut(&id) {
+                *parent_loffset = (*parent_loffset).min(loffset);
+            }
+        }
+    }
+    let bins = bins
+        .into_iter()
+        .map(|(id, (loffset, chunks))| (id, Bin::new(loffset, chunks)))
+        .collect();
+
+    // Windows no record touched are left at 0.
+    let len = parts.iter().map(|part| part.linear_index().len()).max();
+    let linear_index = (0..len.unwrap_or(0))
+        .map(|i| {
+            parts
+                .iter()
+                .filter_map(|part| part.linear_index().get(i))
+                .copied()
+                .find(|&position| position != VirtualPosition::default())
+                .unwrap_or_default()
+        })
+        .collect();
+
+    let metadata = parts
+        .iter()
+        .filter_map(|part| part.metadata())
+        .cloned()
+        .reduce(|a, b| {
+            Metadata::new(
+                a.start_position().min(b.start_position()),
+                a.end_position().max(b.end_position()),
+                a.mapped_record_count() + b.mapped_record_count(),
+                a.unmapped_record_count() + b.unmapped_record_count(),
+            )
+        });
+
+    ReferenceSequence::new(bins, linear_index, metadata)
+}
+
+/// Merges indexes of consecutive parts of one BAM, in order, as if the
+/// records had been indexed in one pass.
+pub fn merge_indexes(indexes: &[csi::Index], min_shift: u8, depth: u8) -> csi::Index {
+    let reference_sequence_count = indexes[0].reference_sequences().len();
+    let reference_sequences = (0..reference_sequence_count)
+        .map(|i| {
+            let parts: Vec<_> = indexes
+                .iter()
+                .map(|index| &index.reference_sequences()[i])
+                .collect();
+            merge_reference_sequences(&parts)
+        })
+        .collect();
+    let unplaced_unmapped_record_count = indexes
+        .iter()
+        .filter_map(|index| index.unplaced_unmapped_record_count())
+        .sum();
+    csi::Index::builder()
+        .set_min_shift(min_shift)
+        .set_depth(depth)
+        .set_reference_sequences(reference_sequences)
+        .set_unplaced_unmapped_record_count(unplaced_unmapped_record_count)
+        .build()
+}
+
+/// Indexes a BAM as `parts` byte ranges streamed concurrently, then merges the
+/// parts into the index a single pass would build.
+///
+/// Parts start at the first record found after an even split of the object;
+/// returns `None` when those guesses turn out wrong, so the caller can index
+/// the BAM in one pass instead.
+pub async fn build_bam_index_split(
+    url: &url::Url,
+    parts: NonZeroUsize,
+    options: &IndexOptions,
+    store_options: &StoreOptions,
+) -> Result<Option<BamIndex>> {
+    let (read_url, read_options) = drs::resolve(url, store_options).await?;
+    let (store, path) = get_object_store(&read_url, &read_options)?;
+    let store: Arc<dyn ObjectStore> = Arc::from(store);
+    let size = store.head(&path).await?.size as u64;
+
+    // The first part carries on from the header.
+    let reader =
+        open_resumable(url, store.clone(), path.clone(), None, &store_options.retry).await?;
+    let mut bam_reader = bam_stream_reader(reader, options.threads);
+    let (header, format, min_shift, depth) = read_bam_header(&mut bam_reader, options).await?;
+    let header = Arc::new(header);
+    let header_end = bam_reader.virtual_position();
+
+    let guesses = futures::future::try_join_all((1..parts.get()).map(|i| {
+        let offset = size * i as u64 / parts.get() as u64;
+        find_record_start(&*store, &path, offset, size, &header)
+    }))
+    .await;
+    // E.g. an HTTP server that ignores range requests.
+    let guesses = match guesses {
+        Ok(guesses) => guesses,
+        Err(e) => {
+            log::warn!("Failed to split {} ({:#}); indexing it in one pass", url, e);
+            return Ok(None);
+        }
+    };
+    let mut starts = vec![header_end];
+    for start in guesses.into_iter().flatten() {
+        if start > *starts.last().unwrap() {
+            starts.push(start);
+        }
+    }
+    log::debug!(
+        "Indexing {} in {} parts starting at {:?}",
+        url,
+        starts.len(),
+        starts
+    );
+
+    let mut tasks = Vec::with_capacity(starts.len());
+    let first_part_header = header.clone();
+    let first_until = starts.get(1).copied();
+    let coverage = options.coverage;
+    tasks.push(tokio::spawn(async move {
+        let mut indexer = csi::index::Indexer::new(min_shift, depth);
+        let span = index_records(
+            &mut bam_reader,
+            &first_part_header,
+            &mut indexer,
+            0,
+            first_until,
+            coverage,
+            None,
+        )
+        .await?;
+        Ok((
+            span,
+            finish_index(indexer, &first_part_header, min_shift, depth),
+        ))
+    }));
+    for (i, &start) in starts.iter().enumerate().skip(1) {
+        let range = start.compressed() as usize..size as usize;
+        let reader = open_resumable(
+            url,
+            store.clone(),
+            path.clone(),
+            Some(range),
+            &store_options.retry,
+        )
+        .await?;
+        tasks.push(tokio::spawn(index_part(
+            reader,
+            header.clone(),
+            options.threads,
+            (min_shift, depth),
+            (start, starts.get(i + 1).copied()),
+            coverage,
+        )));
+    }
+    let mut spans = Vec::with_capacity(tasks.len());
+    let mut indexes = Vec::with_capacity(tasks.len());
+    let mut failed = None;
+    for task in tasks {
+        let result: Result<(RecordSpan, csi::Index)> = task.await?;
+        match result {
+            Ok((span, index)) => {
+                spans.push(span);
+                indexes.push(index);
+            }
+            Err(e) => failed = failed.or(Some(e)),
+        }
+    }
+
+    // A part that fails, or doesn't end exactly where the next one starts,
+    // likely began inside a record. Indexing in one pass settles it, and
+    // reports any real error.
+    if let Some(e) = failed {
+        if starts.len() == 1 {
+            return Err(e);
+        }
+        log::warn!(
+            "Failed to index {} in parts ({:#}); indexing it in one pass",
+            url,
+            e
+        );
+        return Ok(None);
+    }
+    for (span, &next_start) in spans.iter().zip(&starts[1..]) {
+        if span.end != next_start {
+            log::warn!(
+                "Part boundary {:?} in {} isn't a record start; indexing it in one pass",
+                next_start,
+                url
+            );
+            return Ok(None);
+        }
+    }
+    for (a, b) in spans.iter().zip(&spans[1..]) {
+        let (Some(last), Some((name, first))) = (a.last, &b.first) else {
+            continue;
+        };
+        if *first < last {
+            return Err(Error::Unsorted {
+                name: name.clone(),
+                position: format_position(&header, first.0, first.1),
+                previous: format_position(&header, last.0, last.1),
+            });
+        }
+    }
+
+    let index = merge_indexes(&indexes, min_shift, depth);
+    let mut spans = spans.into_iter();
+    let first = spans.next().expect("at least one part");
+    let (mut flagstat, mut coverage) = (first.flagstat, first.coverage);
+    for span in spans {
+        flagstat.add(&span.flagstat);
+        if let (Some(coverage), Some(part)) = (&mut coverage, &span.coverage) {
+            coverage.add(part);
+        }
+    }
+    let header = Arc::try_unwrap(header).unwrap_or_else(|header| (*header).clone());
+    Ok(Some(BamIndex {
+        format,
+        index,
+        header,
+        flagstat,
+        coverage,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use noodles::bam;
+
+    use super::*;
+    use crate::{
+        build_bam_index, encode_bai, encode_csi,
+        testing::{bam_from_sam, many_records, sam_header, sam_record},
+        IndexFormat, BAI_MIN_SHIFT,
+    };
+
+    fn write_temp(bam: &[u8]) -> (tempfile::NamedTempFile, url::Url) {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(bam).unwrap();
+        let url = url::Url::from_file_path(file.path()).unwrap();
+        (file, url)
+    }
+
+    fn options(format: IndexFormat) -> IndexOptions {
+        IndexOptions {
+            format,
+            min_shift: if format == IndexFormat::Csi {
+                12
+            } else {
+                BAI_MIN_SHIFT
+            },
+            ..Default::default()
+        }
+    }
+
+    async fn split(url: &url::Url, parts: usize, options: &IndexOptions) -> Option<BamIndex> {
+        let parts = NonZeroUsize::new(parts).unwrap();
+        build_bam_index_split(url, parts, options, &Default::default())
+            .await
+            .unwrap()
+    }
+
+    /// The BAM blocks' offsets, from their headers.
+    fn block_offsets(bam: &[u8]) -> Vec<usize> {
+        let mut offsets = vec![0];
+        while let Some(end) = block_end(bam, *offsets.last().unwrap()) {
+            offsets.push(end);
+        }
+        offsets
+    }
+
+    #[tokio::test]
+    async fn split_indexes_encode_as_one_pass() {
+        let bam = bam_from_sam(&many_records(5000));
+        assert!(block_offsets(&bam).len() > 10);
+        let (_file, url) = write_temp(&bam);
+        for format in [IndexFormat::Bai, IndexFormat::Csi] {
+            let options = options(format);
+            let one_pass = build_bam_index(&mut &bam[..], &options).await.unwrap();
+            for parts in [2, 3, 4, 7, 16] {
+                let bam_index = split(&url, parts, &options).await.unwrap();
+                assert_eq!(
+                    encode_bai(&bam_index.index),
+                    encode_bai(&one_pass.index),
+                    "{:?} in {} parts",
+                    format,
+                    parts
+                );
+                assert_eq!(
+                    encode_csi(&bam_index.index).unwrap(),
+                    encode_csi(&one_pass.index).unwrap(),
+                    "{:?} in {} parts",
+                    format,
+                    parts
+                );
+                assert_eq!(
+                    bam_index.flagstat.to_json().unwrap(),
+                    one_pass.flagstat.to_json().unwrap()
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn a_wrong_guess_falls_back_to_one_pass() {
+        let mut sam = sam_header(&[("chr1", 1_000_000)]);
+        let header: sam::Header = sam.parse().unwrap();
+        for i in 0..200 {
+            sam.push_str(&sam_record(&format!("a{}", i), 0, "chr1", 1 + 2 * i, "10M"));
+        }
+        // A record whose tag holds incompressible bytes and then what look
+        // like four more records. Halfway through the BAM is inside the tag,
+        // so the guess is the first of those, and the part before it reads
+        // on to the end of the real record.
+        let mut fake_sam = String::new();
+        for i in 0..4 {
+            fake_sam.push_str(&sam_record(&format!("fake{}", i), 0, "chr1", 1000, "10M"));
+        }
+        let mut fake = bam::Writer::from(Vec::new());
+        for result in sam::Reader::new(fake_sam.as_bytes()).records(&header) {
+            fake.write_record(&header, &result.unwrap()).unwrap();
+        }
+        let mut state = 1u32;
+        let tag: Vec<_> = std::iter::repeat_with(|| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 16) as u8
+        })
+        .take(200_000)
+        .chain(fake.into_inner())
+        .map(|b| b.to_string())
+        .collect();
+        sam.push_str(&format!(
+            "big\t0\tchr1\t1000\t60\t10M\t*\t0\t0\t*\t*\tXB:B:C,{}\n",
+            tag.join(",")
+        ));
+        for i in 0..200 {
+            sam.push_str(&sam_record(
+                &format!("b{}", i),
+                0,
+                "chr1",
+                1001 + 2 * i,
+                "10M",
+            ));
+        }
+        let bam = bam_from_sam(&sam);
+        let (_file, url) = write_temp(&bam);
+
+        let options = options(IndexFormat::Bai);
+        assert!(split(&url, 2, &options).await.is_none());
+    }
+}
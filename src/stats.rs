@@ -0,0 +1,163 @@
+//! `samtools idxstats` counts, read off an index.
+
+use noodles::{csi, sam};
+use serde::Serialize;
+
+use crate::Result;
+
+/// Which `idxstats` summaries to write next to the index.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatsFormat {
+    /// `samtools idxstats` columns: name, length, mapped, unmapped.
+    Tsv,
+    /// The same counts as a JSON object.
+    Json,
+    /// Write both.
+    Both,
+}
+
+impl StatsFormat {
+    /// The summaries to write, one file each.
+    pub fn parts(self) -> &'static [StatsKind] {
+        match self {
+            StatsFormat::Tsv => &[StatsKind::Tsv],
+            StatsFormat::Json => &[StatsKind::Json],
+            StatsFormat::Both => &[StatsKind::Tsv, StatsKind::Json],
+        }
+    }
+}
+
+/// The format of a single `idxstats` file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatsKind {
+    /// `.idxstats.tsv`
+    Tsv,
+    /// `.idxstats.json`
+    Json,
+}
+
+impl StatsKind {
+    /// The file extension, without the dot.
+    pub fn extension(self) -> &'static str {
+        match self {
+            StatsKind::Tsv => "idxstats.tsv",
+            StatsKind::Json => "idxstats.json",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ReferenceSequenceStats<'a> {
+    name: &'a str,
+    length: usize,
+    mapped: u64,
+    unmapped: u64,
+}
+
+/// Per-reference record counts, as `samtools idxstats` reports them.
+#[derive(Serialize)]
+pub struct IdxStats<'a> {
+    reference_sequences: Vec<ReferenceSequenceStats<'a>>,
+    /// Unmapped records with no reference sequence or position.
+    unplaced_unmapped: u64,
+}
+
+impl<'a> IdxStats<'a> {
+    /// Reads the counts off the index's per-reference metadata.
+    pub fn new(header: &'a sam::Header, index: &csi::Index) -> Self {
+        let reference_sequences = header
+            .reference_sequences()
+            .iter()
+            .zip(index.reference_sequences())
+            .map(|((name, map), reference_sequence)| {
+                let metadata = reference_sequence.metadata();
+                ReferenceSequenceStats {
+                    name: name.as_str(),
+                    length: usize::from(map.length()),
+                    mapped: metadata.map_or(0, |m| m.mapped_record_count()),
+                    unmapped: metadata.map_or(0, |m| m.unmapped_record_count()),
+                }
+            })
+            .collect();
+        Self {
+            reference_sequences,
+            unplaced_unmapped: index.unplaced_unmapped_record_count().unwrap_or(0),
+        }
+    }
+
+    /// Encodes the counts as `kind`.
+    pub fn encode(&self, kind: StatsKind) -> Result<Vec<u8>> {
+        match kind {
+            StatsKind::Tsv => Ok(self.to_tsv().into_bytes()),
+            StatsKind::Json => {
+                let mut buf = serde_json::to_vec_pretty(self)?;
+                buf.push(b'\n');
+                Ok(buf)
+            }
+        }
+    }
+
+    /// One line per reference sequence, then `*` for unplaced records.
+    fn to_tsv(&self) -> String {
+        let mut tsv = String::new();
+        for stats in &self.reference_sequences {
+            tsv.push_str(&format!(
+                "{}\t{}\t{}\t{}\n",
+                stats.name, stats.length, stats.mapped, stats.unmapped
+            ));
+        }
+        tsv.push_str(&format!("*\t0\t0\t{}\n", self.unplaced_unmapped));
+        tsv
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        build_bam_index,
+        testing::{bam_from_sam, sam_header, sam_record},
+    };
+
+    #[tokio::test]
+    async fn counts_match_samtools() {
+        let mut sam = sam_header(&[("chr1", 1000), ("chr2", 2000), ("chr3", 3000)]);
+        for record in [
+            sam_record("a", 0, "chr1", 10, "10M"),
+            sam_record("b", 16, "chr1", 20, "10M"),
+            // Secondary alignments count as mapped, as in samtools.
+            sam_record("b", 256, "chr1", 30, "10M"),
+            // Placed next to its mate, but unmapped.
+            sam_record("c", 4, "chr1", 40, "*"),
+            sam_record("d", 0, "chr3", 50, "10M"),
+            sam_record("e", 4, "*", 0, "*"),
+            sam_record("f", 4, "*", 0, "*"),
+        ] {
+            sam.push_str(&record);
+        }
+        let bam = bam_from_sam(&sam);
+        let bam_index = build_bam_index(&mut &bam[..], &Default::default())
+            .await
+            .unwrap();
+        let stats = bam_index.idxstats();
+
+        let tsv = stats.encode(StatsKind::Tsv).unwrap();
+        assert_eq!(
+            String::from_utf8(tsv).unwrap(),
+            "chr1\t1000\t3\t1\nchr2\t2000\t0\t0\nchr3\t3000\t1\t0\n*\t0\t0\t2\n"
+        );
+        let json = stats.encode(StatsKind::Json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "reference_sequences": [
+                    {"name": "chr1", "length": 1000, "mapped": 3, "unmapped": 1},
+                    {"name": "chr2", "length": 2000, "mapped": 0, "unmapped": 0},
+                    {"name": "chr3", "length": 3000, "mapped": 1, "unmapped": 0},
+                ],
+                "unplaced_unmapped": 2,
+            })
+        );
+    }
+}
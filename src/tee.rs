@@ -0,0 +1,150 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use object_store::{path::Path, MultipartId, ObjectStore};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// Copies everything read from `inner` to a multipart upload.
+///
+/// Bytes are written before the next read, so at most one read's worth is
+/// buffered and a slow destination slows the reads down.
+pub struct TeeReader<R> {
+    inner: R,
+    store: Arc<dyn ObjectStore>,
+    path: Path,
+    id: MultipartId,
+    writer: Box<dyn AsyncWrite + Unpin + Send>,
+    /// Read but not yet written.
+    pending: Vec<u8>,
+    written: u64,
+}
+
+impl<R: AsyncRead + Unpin> TeeReader<R> {
+    /// Starts the upload to `path`.
+    pub async fn new(
+        inner: R,
+        store: Arc<dyn ObjectStore>,
+        path: Path,
+    ) -> object_store::Result<Self> {
+        let (id, writer) = store.put_multipart(&path).await?;
+        Ok(Self {
+            inner,
+            store,
+            path,
+            id,
+            writer,
+            pending: Vec::new(),
+            written: 0,
+        })
+    }
+
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let n = ready!(Pin::new(&mut self.writer).poll_write(cx, &self.pending))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.drain(..n);
+            self.written += n as u64;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Copies whatever is left unread, completes the upload and returns how
+    /// many bytes were copied in all. The upload is aborted if that fails.
+    pub async fn finish(mut self) -> io::Result<u64> {
+        let result = async {
+            tokio::io::copy(&mut self, &mut tokio::io::sink()).await?;
+            std::future::poll_fn(|cx| self.poll_write_pending(cx)).await?;
+            self.writer.shutdown().await
+        }
+        .await;
+        match result {
+            Ok(()) => Ok(self.written),
+            Err(e) => {
+                self.abort().await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Abandons the upload, so no partial copy is left behind and the parts
+    /// uploaded so far aren't kept (and billed for) by the store.
+    pub async fn abort(self) {
+        if let Err(e) = self.store.abort_multipart(&self.path, &self.id).await {
+            log::warn!("Failed to abort the upload to {}: {}", self.path, e);
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for TeeReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.pending.extend_from_slice(&buf.filled()[start..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tokio::io::AsyncReadExt;
+    use tokio_util::io::StreamReader;
+
+    use super::*;
+    use crate::testing::TestStore;
+
+    #[tokio::test]
+    async fn finished_copies_are_complete() {
+        let store = Arc::new(TestStore::default());
+        let path = Path::from("copy.bam");
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        let mut reader = TeeReader::new(&data[..], store.clone(), path.clone())
+            .await
+            .unwrap();
+        let mut head = vec![0; 1000];
+        reader.read_exact(&mut head).await.unwrap();
+        assert_eq!(reader.finish().await.unwrap(), data.len() as u64);
+        let copy = store.get(&path).await.unwrap().bytes().await.unwrap();
+        assert_eq!(copy, data);
+        assert!(store.aborted.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_copies_are_aborted() {
+        let store = Arc::new(TestStore::default());
+        let path = Path::from("copy.bam");
+
+        // Abandoned by the caller.
+        let reader = TeeReader::new(&b"BAM\x01"[..], store.clone(), path.clone())
+            .await
+            .unwrap();
+        reader.abort().await;
+
+        // And failing to read the rest.
+        let chunks = [
+            Ok(Bytes::from_static(b"BAM\x01")),
+            Err(io::Error::from(io::ErrorKind::ConnectionReset)),
+        ];
+        let inner = StreamReader::new(futures::stream::iter(chunks));
+        let reader = TeeReader::new(inner, store.clone(), path.clone())
+            .await
+            .unwrap();
+        let e = reader.finish().await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+
+        assert_eq!(*store.aborted.lock().unwrap(), [path.clone(), path.clone()]);
+        assert!(store.head(&path).await.is_err());
+    }
+}
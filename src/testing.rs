@@ -0,0 +1,208 @@
+//! BAMs for tests, written from SAM text, and an object store to read them
+//! from that misbehaves on request.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use futures::{stream::BoxStream, StreamExt};
+use noodles::{bam, sam};
+use object_store::{
+    memory::InMemory, path::Path, GetOptions, GetResult, GetResultPayload, ListResult, MultipartId,
+    ObjectMeta, ObjectStore,
+};
+use tokio::io::AsyncWrite;
+
+/// A coordinate-sorted SAM header with these reference sequences.
+pub fn sam_header(reference_sequences: &[(&str, usize)]) -> String {
+    let mut s = String::from("@HD\tVN:1.6\tSO:coordinate\n");
+    for (name, length) in reference_sequences {
+        writeln!(s, "@SQ\tSN:{}\tLN:{}", name, length).unwrap();
+    }
+    s
+}
+
+/// A SAM record line with no sequence, qualities or mate.
+pub fn sam_record(name: &str, flags: u16, reference: &str, start: usize, cigar: &str) -> String {
+    format!(
+        "{}\t{}\t{}\t{}\t60\t{}\t*\t0\t0\t*\t*\n",
+        name, flags, reference, start, cigar
+    )
+}
+
+/// `records` records two bases apart across `chr1` and `chr2`, then a few
+/// unplaced unmapped ones, enough for a few dozen BGZF blocks.
+pub fn many_records(records: usize) -> String {
+    let mut sam = sam_header(&[("chr1", 1_000_000), ("chr2", 1_000_000)]);
+    let half = records / 2;
+    for i in 0..records {
+        let (reference, start) = match i < half {
+            true => ("chr1", 1 + 2 * i),
+            false => ("chr2", 1 + 2 * (i - half)),
+        };
+        // Sequences make the records big enough to span blocks.
+        writeln!(
+            sam,
+            "r{}\t0\t{}\t{}\t60\t100M\t*\t0\t0\t{}\t*",
+            i,
+            reference,
+            start,
+            "ACGT".repeat(25)
+        )
+        .unwrap();
+    }
+    for i in 0..3 {
+        sam.push_str(&sam_record(&format!("u{}", i), 4, "*", 0, "*"));
+    }
+    sam
+}
+
+/// Encodes a SAM file as BAM.
+pub fn bam_from_sam(sam: &str) -> Vec<u8> {
+    let mut reader = sam::Reader::new(sam.as_bytes());
+    let header = reader.read_header().unwrap();
+    let mut writer = bam::Writer::new(Vec::new());
+    writer.write_header(&header).unwrap();
+    for result in reader.records(&header) {
+        writer.write_record(&header, &result.unwrap()).unwrap();
+    }
+    writer.into_inner().finish().unwrap()
+}
+
+/// An in-memory object store with ETags, whose reads can be made to drop
+/// partway through, and which records aborted uploads.
+#[derive(Debug, Default)]
+pub struct TestStore {
+    inner: Arc<InMemory>,
+    /// Each object's ETag, bumped on every write.
+    e_tags: Mutex<HashMap<Path, usize>>,
+    /// Where the next read drops, and what the object is replaced with then.
+    drop: Mutex<Option<(usize, Option<Bytes>)>>,
+    /// Paths whose multipart uploads were aborted.
+    pub aborted: Mutex<Vec<Path>>,
+}
+
+impl TestStore {
+    /// Ends the next read with an error after `after` bytes, first replacing
+    /// the object with `replacement` if given.
+    pub fn drop_next_read(&self, after: usize, replacement: Option<Bytes>) {
+        *self.drop.lock().unwrap() = Some((after, replacement));
+    }
+
+    fn e_tag(&self, location: &Path) -> Option<String> {
+        let e_tags = self.e_tags.lock().unwrap();
+        Some(e_tags.get(location).copied().unwrap_or(0).to_string())
+    }
+
+    fn bump_e_tag(&self, location: &Path) {
+        *self
+            .e_tags
+            .lock()
+            .unwrap()
+            .entry(location.clone())
+            .or_default() += 1;
+    }
+}
+
+impl fmt::Display for TestStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TestStore")
+    }
+}
+
+#[async_trait::async_trait]
+impl ObjectStore for TestStore {
+    async fn put(&self, location: &Path, bytes: Bytes) -> object_store::Result<()> {
+        self.bump_e_tag(location);
+        self.inner.put(location, bytes).await
+    }
+
+    async fn put_multipart(
+        &self,
+        location: &Path,
+    ) -> object_store::Result<(MultipartId, Box<dyn AsyncWrite + Unpin + Send>)> {
+        self.bump_e_tag(location);
+        self.inner.put_multipart(location).await
+    }
+
+    async fn abort_multipart(
+        &self,
+        location: &Path,
+        multipart_id: &MultipartId,
+    ) -> object_store::Result<()> {
+        self.aborted.lock().unwrap().push(location.clone());
+        self.inner.abort_multipart(location, multipart_id).await
+    }
+
+    async fn get_opts(
+        &self,
+        location: &Path,
+        options: GetOptions,
+    ) -> object_store::Result<GetResult> {
+        let mut result = self.inner.get_opts(location, options).await?;
+        result.meta.e_tag = self.e_tag(location);
+        let Some((after, replacement)) = self.drop.lock().unwrap().take() else {
+            return Ok(result);
+        };
+        let (meta, range) = (result.meta.clone(), result.range.clone());
+        let bytes = result.bytes().await?;
+        let head = bytes.slice(..after.min(bytes.len()));
+        let (inner, location) = (self.inner.clone(), location.clone());
+        if replacement.is_some() {
+            self.bump_e_tag(&location);
+        }
+        let dropped = async move {
+            if let Some(replacement) = replacement {
+                inner.put(&location, replacement).await?;
+            }
+            Err(object_store::Error::Generic {
+                store: "TestStore",
+                source: "connection reset".into(),
+            })
+        };
+        let stream = futures::stream::once(async move { Ok(head) })
+            .chain(futures::stream::once(dropped))
+            .boxed();
+        Ok(GetResult {
+            payload: GetResultPayload::Stream(stream),
+            meta,
+            range,
+        })
+    }
+
+    async fn head(&self, location: &Path) -> object_store::Result<ObjectMeta> {
+        let mut meta = self.inner.head(location).await?;
+        meta.e_tag = self.e_tag(location);
+        Ok(meta)
+    }
+
+    async fn delete(&self, location: &Path) -> object_store::Result<()> {
+        self.inner.delete(location).await
+    }
+
+    // `async_trait` names the lifetime `'_` stands for.
+    #[allow(mismatched_lifetime_syntaxes)]
+    async fn list(
+        &self,
+        prefix: Option<&Path>,
+    ) -> object_store::Result<BoxStream<'_, object_store::Result<ObjectMeta>>> {
+        self.inner.list(prefix).await
+    }
+
+    async fn list_with_delimiter(&self, prefix: Option<&Path>) -> object_store::Result<ListResult> {
+        self.inner.list_with_delimiter(prefix).await
+    }
+
+    async fn copy(&self, from: &Path, to: &Path) -> object_store::Result<()> {
+        self.bump_e_tag(to);
+        self.inner.copy(from, to).await
+    }
+
+    async fn copy_if_not_exists(&self, from: &Path, to: &Path) -> object_store::Result<()> {
+        self.bump_e_tag(to);
+        self.inner.copy_if_not_exists(from, to).await
+    }
+}
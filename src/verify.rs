@@ -0,0 +1,437 @@
+use std::io::{self, Cursor, Read};
+
+use anyhow::{Context, Result};
+use noodles::{
+    bam,
+    bgzf::{self, VirtualPosition},
+    csi::{self, index::reference_sequence::bin::Chunk},
+    sam,
+};
+
+use stream_index::{
+    auth::redact_url, build_bam_index, check_csi_binning, get_async_stream_reader,
+    get_object_store, object_exists, IndexKind, IndexOptions, Source, StoreOptions, BAI_MIN_SHIFT,
+};
+
+use crate::cli;
+
+/// What checking an index against its BAM found.
+pub enum Verdict {
+    /// The index finds every record in the BAM.
+    Valid,
+    /// The index reads fine but doesn't match the BAM, e.g. because the BAM
+    /// was rewritten after it was indexed.
+    Stale(String),
+    /// The index can't be decoded, or holds values no index could.
+    Corrupt(String),
+}
+
+impl Verdict {
+    /// 0 for valid, 2 for stale and 3 for corrupt; 1 is any other error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Verdict::Valid => 0,
+            Verdict::Stale(_) => 2,
+            Verdict::Corrupt(_) => 3,
+        }
+    }
+}
+
+/// `<url>.bai`, or `<url>.csi` if there's no `.bai`, if either exists.
+pub async fn index_next_to(
+    url: &url::Url,
+    store_options: &StoreOptions,
+) -> Result<Option<url::Url>> {
+    for extension in ["bai", "csi"] {
+        let mut location = url.clone();
+        location.set_path(&format!("{}.{}", url.path(), extension));
+        if object_exists(&location, store_options).await? {
+            return Ok(Some(location));
+        }
+    }
+    Ok(None)
+}
+
+/// `<input>.bai`, or `<input>.csi` if there's no `.bai`.
+async fn find_index(input: &Source, store_options: &StoreOptions) -> Result<url::Url> {
+    let url = match input {
+        Source::Stdin => anyhow::bail!("--index is needed when reading the BAM from stdin"),
+        Source::Url(url) if url.scheme() == "drs" => {
+            anyhow::bail!("--index is needed when reading the BAM from a DRS URI")
+        }
+        Source::Url(url) => url,
+    };
+    index_next_to(url, store_options)
+        .await?
+        .with_context(|| format!("No .bai or .csi found next to {}; pass --index", url))
+}
+
+/// Reads the BAI or CSI at `location`.
+pub async fn read_index(location: &url::Url, store_options: &StoreOptions) -> Result<csi::Index> {
+    let (store, path) = get_object_store(location, store_options)?;
+    let (_, index) = async { anyhow::Ok(decode_index(&store.get(&path).await?.bytes().await?)?) }
+        .await
+        .with_context(|| format!("Failed to read {}", redact_url(location)))?;
+    Ok(index)
+}
+
+/// Decodes a BAI, or a CSI, which is BGZF-compressed.
+pub fn decode_index(buf: &[u8]) -> io::Result<(IndexKind, csi::Index)> {
+    if buf.starts_with(&[0x1f, 0x8b]) {
+        check_csi_header(buf)?;
+        let index = csi::Reader::new(buf).read_index()?;
+        return Ok((IndexKind::Csi, index));
+    }
+    let mut reader = bam::bai::Reader::new(Cursor::new(buf));
+    reader.read_header()?;
+    let index = reader.read_index()?;
+    Ok((IndexKind::Bai, index))
+}
+
+/// Checks a CSI's `min_shift` and depth before decoding it: noodles panics
+/// on depths whose bin IDs don't fit, and the bin sizes here would overflow.
+fn check_csi_header(buf: &[u8]) -> io::Result<()> {
+    let mut header = [0; 12];
+    bgzf::Reader::new(buf).read_exact(&mut header)?;
+    let field = |i: usize| i32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+    // Negative values are left for the decoder to reject.
+    if let (Ok(min_shift), Ok(depth)) = (u8::try_from(field(4)), u8::try_from(field(8))) {
+        check_csi_binning(min_shift, depth)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    }
+    Ok(())
+}
+
+/// Number of bins with `depth` levels below the root.
+fn bin_count(depth: u8) -> usize {
+    ((1 << (3 * (usize::from(depth) + 1))) - 1) / 7
+}
+
+/// `bin 4681 (chr1:1-16384)`: a bin of reference sequence `id` and the bases
+/// it covers.
+fn format_bin(header: &sam::Header, id: usize, index: &csi::Index, bin: usize) -> String {
+    let (mut level, mut first) = (0, 0);
+    while bin >= first + (1 << (3 * level)) {
+        first += 1 << (3 * level);
+        level += 1;
+    }
+    let size =
+        1usize << (usize::from(index.min_shift()) + 3 * (usize::from(index.depth()) - level));
+    let start = (bin - first) * size;
+    let name = header
+        .reference_sequences()
+        .get_index(id)
+        .map_or("?", |(name, _)| name.as_str());
+    format!("bin {} ({}:{}-{})", bin, name, start + 1, start + size)
+}
+
+fn format_virtual_position(position: VirtualPosition) -> String {
+    format!("{}:{}", position.compressed(), position.uncompressed())
+}
+
+fn reference_name(header: &sam::Header, id: usize) -> String {
+    header.reference_sequences().get_index(id).map_or_else(
+        || format!("reference sequence {}", id),
+        |(name, _)| name.to_string(),
+    )
+}
+
+/// Values a decoder accepts but no index could hold.
+fn check_structure(index: &csi::Index) -> Option<String> {
+    let bin_count = bin_count(index.depth());
+    for (id, reference_sequence) in index.reference_sequences().iter().enumerate() {
+        let mut bins: Vec<_> = reference_sequence.bins().iter().collect();
+        bins.sort_by_key(|(&bin, _)| bin);
+        for (&bin, contents) in bins {
+            if bin >= bin_count {
+                return Some(format!(
+                    "reference sequence {} has bin {}, but there are only {}",
+                    id, bin, bin_count
+                ));
+            }
+            if let Some(chunk) = contents.chunks().iter().find(|c| c.start() > c.end()) {
+                return Some(format!(
+                    "reference sequence {} bin {} has a chunk that ends at {} before it starts at {}",
+                    id,
+                    bin,
+                    format_virtual_position(chunk.end()),
+                    format_virtual_position(chunk.start())
+                ));
+            }
+        }
+    }
+    None
+}
+
+/// `chunks` sorted, with overlapping and touching ones joined.
+fn join_chunks(mut chunks: Vec<Chunk>) -> Vec<Chunk> {
+    chunks.sort_by_key(|chunk| chunk.start());
+    let mut joined: Vec<Chunk> = Vec::with_capacity(chunks.len());
+    for chunk in chunks {
+        match joined.last_mut() {
+            Some(last) if chunk.start() <= last.end() => {
+                *last = Chunk::new(last.start(), last.end().max(chunk.end()));
+            }
+            _ => joined.push(chunk),
+        }
+    }
+    joined
+}
+
+/// The first way `existing` fails to find the records `fresh` does.
+///
+/// Indexes aren't compared bin for bin: `samtools index` moves the chunks of
+/// bins smaller than a BGZF block into their parents, joins chunks in the
+/// same block, and fills empty linear index windows from the one before. So
+/// each of `fresh`'s chunks must lie within `existing`'s chunks for the same
+/// bin or an ancestor, and `existing`'s linear index and bin offsets must not
+/// start past the first record they cover.
+fn compare(
+    header: &sam::Header,
+    kind: IndexKind,
+    existing: &csi::Index,
+    fresh: &csi::Index,
+) -> Option<String> {
+    let (existing_len, fresh_len) = (
+        existing.reference_sequences().len(),
+        fresh.reference_sequences().len(),
+    );
+    if existing_len != fresh_len {
+        return Some(format!(
+            "the index has {} reference sequences, the BAM header {}",
+            existing_len, fresh_len
+        ));
+    }
+
+    let references = existing
+        .reference_sequences()
+        .iter()
+        .zip(fresh.reference_sequences());
+    for (id, (existing_reference, fresh_reference)) in references.enumerate() {
+        let name = reference_name(header, id);
+        if let (Some(e), Some(f)) = (existing_reference.metadata(), fresh_reference.metadata()) {
+            let counts = |m: &csi::index::reference_sequence::Metadata| {
+                (m.mapped_record_count(), m.unmapped_record_count())
+            };
+            if counts(e) != counts(f) {
+                return Some(format!(
+                    "{}: the index counts {} mapped and {} unmapped records, the BAM has {} and {}",
+                    name,
+                    e.mapped_record_count(),
+                    e.unmapped_record_count(),
+                    f.mapped_record_count(),
+                    f.unmapped_record_count()
+                ));
+            }
+            if (e.start_position(), e.end_position()) != (f.start_position(), f.end_position()) {
+                return Some(format!(
+                    "{}: the index has records from {} to {}, the BAM from {} to {}",
+                    name,
+                    format_virtual_position(e.start_position()),
+                    format_virtual_position(e.end_position()),
+                    format_virtual_position(f.start_position()),
+                    format_virtual_position(f.end_position())
+                ));
+            }
+        }
+        if fresh_reference.bins().is_empty() && !existing_reference.bins().is_empty() {
+            return Some(format!("{}: the index has records, the BAM has none", name));
+        }
+
+        let mut bins: Vec<_> = fresh_reference.bins().iter().collect();
+        bins.sort_by_key(|(&bin, _)| bin);
+        for (&bin, fresh_bin) in bins {
+            let mut chunks = Vec::new();
+            let mut ancestor = Some(bin);
+            while let Some(id) = ancestor {
+                if let Some(existing_bin) = existing_reference.bins().get(&id) {
+                    chunks.extend_from_slice(existing_bin.chunks());
+                }
+                ancestor = (id > 0).then(|| (id - 1) / 8);
+            }
+            let chunks = join_chunks(chunks);
+            for chunk in fresh_bin.chunks() {
+                let covered = chunks
+                    .iter()
+                    .any(|c| c.start() <= chunk.start() && chunk.end() <= c.end());
+                if !covered {
+                    return Some(format!(
+                        "{}: the index is missing the records from {} to {} in {}",
+                        name,
+                        format_virtual_position(chunk.start()),
+                        format_virtual_position(chunk.end()),
+                        format_bin(header, id, fresh, bin)
+                    ));
+                }
+            }
+            // Only CSI stores bin offsets; BAI has a linear index instead.
+            let existing_bin = existing_reference.bins().get(&bin);
+            if let Some(existing_bin) = existing_bin.filter(|_| kind == IndexKind::Csi) {
+                if existing_bin.loffset() > fresh_bin.loffset() {
+                    return Some(format!(
+                        "{}: the index starts {} at {}, past its first record at {}",
+                        name,
+                        format_bin(header, id, fresh, bin),
+                        format_virtual_position(existing_bin.loffset()),
+                        format_virtual_position(fresh_bin.loffset())
+                    ));
+                }
+            }
+        }
+
+        let windows = existing_reference
+            .linear_index()
+            .iter()
+            .zip(fresh_reference.linear_index());
+        for (window, (&e, &f)) in windows.enumerate() {
+            if f != VirtualPosition::default() && e > f {
+                let start = window << BAI_MIN_SHIFT;
+                return Some(format!(
+                    "{}: the index's linear index starts {}:{}-{} at {}, past its first record at {}",
+                    name,
+                    name,
+                    start + 1,
+                    start + (1 << BAI_MIN_SHIFT),
+                    format_virtual_position(e),
+                    format_virtual_position(f)
+                ));
+            }
+        }
+    }
+
+    match (
+        existing.unplaced_unmapped_record_count(),
+        fresh.unplaced_unmapped_record_count(),
+    ) {
+        (Some(e), Some(f)) if e != f => Some(format!(
+            "the index counts {} unplaced unmapped records, the BAM has {}",
+            e, f
+        )),
+        _ => None,
+    }
+}
+
+pub async fn run_verify(args: cli::VerifyArgs) -> Result<Verdict> {
+    let store_options = args.store.store_options()?;
+    let location = match args.index {
+        Some(url) => url,
+        None => find_index(&args.input, &store_options).await?,
+    };
+    let (store, path) = get_object_store(&location, &store_options)?;
+    let buf = async { anyhow::Ok(store.get(&path).await?.bytes().await?) }
+        .await
+        .with_context(|| format!("Failed to read {}", redact_url(&location)))?;
+    let location = redact_url(&location);
+
+    let verdict = match decode_index(&buf) {
+        Err(e) => Verdict::Corrupt(format!("{} can't be decoded: {}", location, e)),
+        Ok((kind, existing)) => match check_structure(&existing) {
+            Some(problem) => Verdict::Corrupt(problem),
+            None => {
+                let options = IndexOptions {
+                    format: kind.into(),
+                    min_shift: existing.min_shift(),
+                    depth: Some(existing.depth()),
+                    csi_fallback: false,
+                    ..args.reindex.index_options()
+                };
+                let mut reader = get_async_stream_reader(&args.input, &store_options).await?;
+                let fresh = build_bam_index(&mut reader, &options).await?;
+                match compare(&fresh.header, kind, &existing, &fresh.index) {
+                    Some(difference) => Verdict::Stale(difference),
+                    None => Verdict::Valid,
+                }
+            }
+        },
+    };
+    match &verdict {
+        Verdict::Valid => println!("valid: {} matches {}", location, args.input),
+        Verdict::Stale(difference) => {
+            println!(
+                "stale: {} doesn't match {}: {}",
+                location, args.input, difference
+            )
+        }
+        Verdict::Corrupt(problem) => println!("corrupt: {}", problem),
+    }
+    Ok(verdict)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::testing::{bam_from_sam, many_records};
+
+    /// A CSI with no reference sequences and this binning.
+    fn csi(min_shift: i32, depth: i32) -> Vec<u8> {
+        let mut writer = bgzf::Writer::new(Vec::new());
+        writer.write_all(b"CSI\x01").unwrap();
+        for n in [min_shift, depth, 0, 0] {
+            writer.write_all(&n.to_le_bytes()).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn out_of_range_binning_is_corrupt() {
+        let (kind, index) = decode_index(&csi(14, 5)).unwrap();
+        assert_eq!(kind, IndexKind::Csi);
+        assert_eq!((index.min_shift(), index.depth()), (14, 5));
+        assert!(check_structure(&index).is_none());
+
+        // Too deep for bin IDs, and too wide for positions.
+        for (min_shift, depth) in [(14, 21), (14, 255), (40, 10)] {
+            let e = decode_index(&csi(min_shift, depth)).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            assert!(e.to_string().contains("out of range"), "{}", e);
+        }
+    }
+
+    async fn bai(bam: &[u8]) -> Vec<u8> {
+        let bam_index = build_bam_index(&mut &bam[..], &Default::default())
+            .await
+            .unwrap();
+        let mut buf = Vec::new();
+        stream_index::write_bam_index(&mut buf, &bam_index.index)
+            .await
+            .unwrap();
+        buf
+    }
+
+    /// Verifies `bam` against `index` and returns the exit code.
+    async fn verify(bam: &[u8], index: &[u8]) -> i32 {
+        let dir = tempfile::tempdir().unwrap();
+        let (input, location) = (dir.path().join("a.bam"), dir.path().join("a.bam.bai"));
+        std::fs::write(&input, bam).unwrap();
+        std::fs::write(&location, index).unwrap();
+        let argv = ["stream-index", "verify", input.to_str().unwrap()];
+        let cli::Command::Verify(args) = cli::Cli::parse_from(argv).command else {
+            unreachable!()
+        };
+        run_verify(args).await.unwrap().exit_code()
+    }
+
+    #[tokio::test]
+    async fn indexes_are_valid_stale_or_corrupt() {
+        let bam = bam_from_sam(&many_records(2000));
+        let index = bai(&bam).await;
+        assert_eq!(verify(&bam, &index).await, 0);
+
+        // The index of a shorter BAM, or of one with the records moved.
+        for records in [1000, 2002] {
+            let other = bai(&bam_from_sam(&many_records(records))).await;
+            assert_eq!(verify(&bam, &other).await, 2, "{} records", records);
+        }
+
+        // Truncated, or with a byte of its magic changed.
+        assert_eq!(verify(&bam, &index[..index.len() / 2]).await, 3);
+        assert_eq!(verify(&bam, &[]).await, 3);
+        let mut garbled = index.clone();
+        garbled[0] = b'X';
+        assert_eq!(verify(&bam, &garbled).await, 3);
+    }
+}
@@ -0,0 +1,467 @@
+//! `view`: the records overlapping regions of a remote BAM, fetched with range
+//! requests for just the blocks its index points to.
+
+use std::{
+    io::{self, Write},
+    mem,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use noodles::{
+    bam,
+    bgzf::{self, VirtualPosition},
+    core::{region::Interval, Region},
+    csi,
+    sam::{self, alignment::Record},
+};
+use object_store::{path::Path, ObjectStore};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use stream_index::{
+    bgzf_block_size, build_bam_index, create_writer, drs, get_async_stream_reader,
+    get_object_store, merge_spans,
+    resume::{self, RetryOptions},
+    Source, StoreOptions,
+};
+
+use crate::{
+    cli,
+    verify::{index_next_to, read_index},
+};
+
+/// How much output to buffer before writing it out.
+const FLUSH_SIZE: usize = 1 << 20;
+
+/// What `view` writes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ViewFormat {
+    Sam,
+    Bam,
+}
+
+/// Records to print: those overlapping an interval of a reference sequence,
+/// or the unplaced unmapped ones at the end of the BAM.
+enum Target {
+    Mapped { id: usize, interval: Interval },
+    Unplaced,
+}
+
+impl Target {
+    /// Parses `chr1`, `chr1:100000`, `chr1:100000-200000` or `*`.
+    fn parse(header: &sam::Header, s: &str) -> Result<Self> {
+        if s == "*" {
+            return Ok(Target::Unplaced);
+        }
+        let reference_sequences = header.reference_sequences();
+        // A whole reference sequence name may itself have a `:` in it.
+        let region = match reference_sequences.contains_key(s) {
+            true => Region::new(s, ..),
+            false => s
+                .parse::<Region>()
+                .with_context(|| format!("Invalid region {:?}", s))?,
+        };
+        if let (Some(start), Some(end)) = (region.interval().start(), region.interval().end()) {
+            if start > end {
+                anyhow::bail!("Invalid region {:?}: it ends before it starts", s);
+            }
+        }
+        let id = reference_sequences
+            .get_index_of(region.name())
+            .with_context(|| format!("No reference sequence named {}", region.name()))?;
+        Ok(Target::Mapped {
+            id,
+            interval: region.interval(),
+        })
+    }
+
+    fn contains(&self, record: &Record) -> bool {
+        match self {
+            Target::Mapped { id, interval } => {
+                match (record.reference_sequence_id(), record.alignment_start()) {
+                    (Some(record_id), Some(start)) => {
+                        // As in samtools, a record with no aligned bases,
+                        // e.g. an unmapped read placed with its mate, covers
+                        // its start.
+                        let end = record.alignment_end().map_or(start, |end| end.max(start));
+                        record_id == *id && interval.intersects((start..=end).into())
+                    }
+                    _ => false,
+                }
+            }
+            Target::Unplaced => record.reference_sequence_id().is_none(),
+        }
+    }
+}
+
+/// A `Write` whose bytes can be taken out while a writer owns it.
+#[derive(Clone, Default)]
+struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Buffer {
+    fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    fn take(&self) -> Vec<u8> {
+        mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The writer for `--output-format`.
+enum Encoder {
+    Sam(sam::Writer<Buffer>),
+    Bam(bam::Writer<bgzf::Writer<Buffer>>),
+}
+
+/// Records encoded as SAM or BAM, and where they're written.
+struct Output {
+    encoder: Encoder,
+    buffer: Buffer,
+    sink: Box<dyn AsyncWrite + Unpin + Send>,
+}
+
+impl Output {
+    /// Starts the output with the header, which SAM only has if
+    /// `with_header` is set.
+    fn new(
+        format: ViewFormat,
+        with_header: bool,
+        header: &sam::Header,
+        sink: Box<dyn AsyncWrite + Unpin + Send>,
+    ) -> Result<Self> {
+        let buffer = Buffer::default();
+        let encoder = match format {
+            ViewFormat::Sam => {
+                let mut writer = sam::Writer::new(buffer.clone());
+                if with_header {
+                    writer.write_header(header)?;
+                }
+                Encoder::Sam(writer)
+            }
+            ViewFormat::Bam => {
+                let mut writer = bam::Writer::new(buffer.clone());
+                writer.write_header(header)?;
+                Encoder::Bam(writer)
+            }
+        };
+        Ok(Self {
+            encoder,
+            buffer,
+            sink,
+        })
+    }
+
+    async fn write(&mut self, header: &sam::Header, record: &Record) -> Result<()> {
+        match &mut self.encoder {
+            Encoder::Sam(writer) => writer.write_record(header, record)?,
+            Encoder::Bam(writer) => writer.write_record(header, record)?,
+        }
+        if self.buffer.len() >= FLUSH_SIZE {
+            self.sink.write_all(&self.buffer.take()).await?;
+        }
+        Ok(())
+    }
+
+    async fn finish(mut self) -> Result<()> {
+        if let Encoder::Bam(writer) = &mut self.encoder {
+            writer.try_finish()?;
+        }
+        self.sink.write_all(&self.buffer.take()).await?;
+        self.sink.flush().await?;
+        self.sink.shutdown().await?;
+        Ok(())
+    }
+}
+
+/// The BAM being queried.
+struct Bam {
+    url: url::Url,
+    store: Arc<dyn ObjectStore>,
+    path: Path,
+    size: u64,
+    retry: RetryOptions,
+}
+
+impl Bam {
+    /// Streams the whole blocks from byte `start` up to byte `end`.
+    async fn open(&self, start: u64, end: u64) -> Result<impl AsyncRead + Unpin + Send> {
+        let range = Some(start as usize..end as usize);
+        let reader = resume::open_resumable(
+            &self.url,
+            self.store.clone(),
+            self.path.clone(),
+            range,
+            &self.retry,
+        )
+        .await?;
+        Ok(reader)
+    }
+
+    /// The compressed size of the block at `offset`, from its header.
+    async fn block_size(&self, offset: u64) -> Result<u64> {
+        let start = offset as usize;
+        let buf = self.store.get_range(&self.path, start..start + 18).await?;
+        let size = bgzf_block_size(&buf)
+            .with_context(|| format!("No BGZF block starts at byte {}", offset))?;
+        Ok(size as u64)
+    }
+
+    /// Writes the records from `start` up to `end`, or the end of the file,
+    /// that `target` asks for.
+    async fn write_records(
+        &self,
+        header: &sam::Header,
+        start: VirtualPosition,
+        end: Option<VirtualPosition>,
+        target: &Target,
+        output: &mut Output,
+    ) -> Result<()> {
+        let base = start.compressed();
+        // Read-ahead must not run into a partial block, so the range ends
+        // with the block `end` is in.
+        let (end_offset, end) = match end {
+            Some(end) if end.uncompressed() == 0 => (end.compressed(), Some(end)),
+            Some(end) => (
+                end.compressed() + self.block_size(end.compressed()).await?,
+                Some(end),
+            ),
+            None => (self.size, None),
+        };
+        let end = end
+            .map(|end| VirtualPosition::try_from((end.compressed() - base, end.uncompressed())))
+            .transpose()?;
+
+        let mut reader = bgzf::AsyncReader::new(self.open(base, end_offset).await?);
+        let skip = u64::from(start.uncompressed());
+        tokio::io::copy(&mut (&mut reader).take(skip), &mut tokio::io::sink()).await?;
+        let mut reader = bam::AsyncReader::from(reader);
+        let mut record = Record::default();
+        while end.is_none_or(|end| reader.virtual_position() < end) {
+            if reader.read_record(header, &mut record).await? == 0 {
+                break;
+            }
+            if target.contains(&record) {
+                output.write(header, &record).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The index at `--index`, or next to the BAM, or else one built by
+/// streaming the BAM.
+async fn load_index(args: &cli::ViewArgs, store_options: &StoreOptions) -> Result<csi::Index> {
+    let input = &args.input;
+    let location = match &args.index {
+        Some(location) => Some(location.clone()),
+        None if input.scheme() == "drs" => None,
+        None => index_next_to(input, store_options).await?,
+    };
+    if let Some(location) = location {
+        return read_index(&location, store_options).await;
+    }
+    log::info!("No index found for {}; indexing it first", input);
+    let options = args.reindex.index_options();
+    let source = Source::Url(input.clone());
+    let mut reader = get_async_stream_reader(&source, store_options).await?;
+    Ok(build_bam_index(&mut reader, &options).await?.index)
+}
+
+pub async fn run_view(args: cli::ViewArgs) -> Result<()> {
+    let store_options = args.store.store_options()?;
+    let (read_url, read_options) = drs::resolve(&args.input, &store_options).await?;
+    let (store, path) = get_object_store(&read_url, &read_options)?;
+    let store: Arc<dyn ObjectStore> = Arc::from(store);
+    let bam = Bam {
+        url: args.input.clone(),
+        size: store.head(&path).await?.size as u64,
+        store,
+        path,
+        retry: read_options.retry.clone(),
+    };
+
+    let mut reader = bam::AsyncReader::new(bam.open(0, bam.size).await?);
+    let header: sam::Header = reader.read_header().await?.parse()?;
+    reader.read_reference_sequences().await?;
+    let header_end = reader.virtual_position();
+    drop(reader);
+
+    let targets = args
+        .regions
+        .iter()
+        .map(|region| Target::parse(&header, region))
+        .collect::<Result<Vec<_>>>()?;
+    let index = load_index(&args, &store_options).await?;
+
+    let sink = create_writer(args.output.as_ref(), &store_options).await?;
+    let mut output = Output::new(args.output_format, args.with_header, &header, sink)?;
+
+    for target in &targets {
+        match target {
+            Target::Mapped { id, interval } => {
+                for span in merge_spans(index.query(*id, *interval)?) {
+                    bam.write_records(&header, span.start(), Some(span.end()), target, &mut output)
+                        .await?;
+                }
+            }
+            Target::Unplaced => {
+                let start = index
+                    .first_record_in_last_linear_bin_start_position()
+                    .unwrap_or(header_end);
+                bam.write_records(&header, start, None, target, &mut output)
+                    .await?;
+            }
+        }
+    }
+    output.finish().await
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::testing::{bam_from_sam, many_records, sam_header, sam_record, TestStore};
+
+    /// What `view` writes for `bam` given `args`, e.g. regions and flags.
+    async fn run(bam: &[u8], args: &[&str]) -> Vec<u8> {
+        let dir = tempfile::tempdir().unwrap();
+        let (input, output) = (dir.path().join("in.bam"), dir.path().join("out"));
+        std::fs::write(&input, bam).unwrap();
+        let mut argv = vec!["stream-index", "view", input.to_str().unwrap()];
+        argv.extend(args);
+        argv.extend(["-o", output.to_str().unwrap()]);
+        let cli::Command::View(args) = cli::Cli::parse_from(argv).command else {
+            unreachable!()
+        };
+        run_view(args).await.unwrap();
+        std::fs::read(output).unwrap()
+    }
+
+    /// The names of the records `view` prints for `regions`.
+    async fn view(bam: &[u8], regions: &[&str]) -> Vec<String> {
+        let sam = String::from_utf8(run(bam, regions).await).unwrap();
+        sam.lines()
+            .map(|line| line.split('\t').next().unwrap().to_string())
+            .collect()
+    }
+
+    /// `bam`'s header, and each record's name and where it starts.
+    fn records(bam: &[u8]) -> (sam::Header, Vec<(String, VirtualPosition)>) {
+        let mut reader = bam::Reader::new(bam);
+        let header = reader.read_header().unwrap();
+        let mut records = Vec::new();
+        let mut record = Record::default();
+        loop {
+            let position = reader.virtual_position();
+            if reader.read_record(&header, &mut record).unwrap() == 0 {
+                break;
+            }
+            records.push((record.read_name().unwrap().to_string(), position));
+        }
+        (header, records)
+    }
+
+    #[tokio::test]
+    async fn records_overlapping_each_region_are_printed() {
+        let mut sam = sam_header(&[("chr1", 1000), ("chr2", 1000)]);
+        for record in [
+            sam_record("a1", 0, "chr1", 1, "10M"),
+            sam_record("a2", 0, "chr1", 5, "10M"),
+            sam_record("a3", 0, "chr1", 12, "5M"),
+            // Deletions count towards the span: 15-21.
+            sam_record("a4", 0, "chr1", 15, "2M3D2M"),
+            sam_record("a5", 0, "chr1", 21, "10M"),
+            // Unmapped, placed with its mate; covers just its start.
+            sam_record("a6", 4, "chr1", 25, "*"),
+            sam_record("a7", 0, "chr1", 100, "10M"),
+            sam_record("b1", 0, "chr2", 1, "10M"),
+            sam_record("u1", 4, "*", 0, "*"),
+        ] {
+            sam.push_str(&record);
+        }
+        let bam = bam_from_sam(&sam);
+
+        // Inclusive at both ends, and open-ended with just a start.
+        assert_eq!(view(&bam, &["chr1:10-20"]).await, ["a1", "a2", "a3", "a4"]);
+        assert_eq!(view(&bam, &["chr1:22"]).await, ["a5", "a6", "a7"]);
+        assert_eq!(view(&bam, &["chr1:26-99"]).await, ["a5"]);
+        // Each region in turn, repeating records in more than one.
+        assert_eq!(
+            view(&bam, &["chr2", "*", "chr1:14-15", "chr1:1-5"]).await,
+            ["b1", "u1", "a2", "a3", "a4", "a1", "a2"]
+        );
+    }
+
+    #[tokio::test]
+    async fn bam_output_reads_back_with_its_eof_block() {
+        let sam = many_records(2000);
+        let bam = bam_from_sam(&sam);
+        let regions = ["chr1:1001-1500", "*"];
+
+        let output = run(&bam, &[&regions[..], &["-O", "bam"]].concat()).await;
+        let eof = bgzf::Writer::new(Vec::new()).finish().unwrap();
+        assert!(output.ends_with(&eof));
+        let (header, records) = self::records(&output);
+        let (input_header, _) = self::records(&bam);
+        assert_eq!(header, input_header);
+        let names: Vec<_> = records.into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, view(&bam, &regions).await);
+        // r451 to r749, 100 bp each from 903 to 1499, then the 3 unplaced.
+        assert_eq!(names.len(), 299 + 3);
+    }
+
+    #[tokio::test]
+    async fn spans_ending_inside_a_block_stop_at_their_end() {
+        let bam_bytes = bam_from_sam(&many_records(2000));
+        let (header, records) = records(&bam_bytes);
+        let start = 100;
+        let end = (start..1000)
+            .find(|&i| {
+                let position = records[i].1;
+                position.uncompressed() != 0
+                    && position.compressed() > records[start].1.compressed()
+            })
+            .unwrap();
+
+        let store = Arc::new(TestStore::default());
+        let path = Path::from("a.bam");
+        store.put(&path, bam_bytes.clone().into()).await.unwrap();
+        let bam = Bam {
+            url: url::Url::parse("memory:///a.bam").unwrap(),
+            store,
+            path,
+            size: bam_bytes.len() as u64,
+            retry: Default::default(),
+        };
+        let (sink, mut written) = tokio::io::duplex(1 << 24);
+        let mut output = Output::new(ViewFormat::Sam, false, &header, Box::new(sink)).unwrap();
+        let target = Target::parse(&header, "chr1").unwrap();
+        let span = (records[start].1, Some(records[end].1));
+        bam.write_records(&header, span.0, span.1, &target, &mut output)
+            .await
+            .unwrap();
+        output.finish().await.unwrap();
+
+        let mut sam = String::new();
+        written.read_to_string(&mut sam).await.unwrap();
+        let names: Vec<_> = sam
+            .lines()
+            .map(|line| line.split('\t').next().unwrap())
+            .collect();
+        let expected: Vec<_> = records[start..end].iter().map(|(name, _)| name).collect();
+        assert_eq!(names, expected);
+    }
+}